@@ -0,0 +1,197 @@
+use crate::config;
+use crate::tracker::PresenceTracker;
+use chrono::{DateTime, Local};
+use crossbeam_channel::Receiver;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(Debug, Deserialize)]
+struct Mute {
+    /// How long to mute for, zero to unmute
+    #[serde(with = "humantime_serde")]
+    duration: std::time::Duration,
+}
+
+/// The status API, serving HTTP in a thread of its own and forwarding requests to be answered
+/// from the tracker's state, so that it needn't be shared between threads.
+pub struct Api {
+    token: Option<String>,
+    requests: Receiver<Request>,
+}
+
+impl Api {
+    pub fn start(config: &config::Api) -> crate::Result<Api> {
+        let server = Server::http(&config.listen).map_err(|e| crate::error::Error::ApiError {
+            address: config.listen.clone(),
+            message: e.to_string(),
+        })?;
+        let (requests_s, requests) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                if let Err(e) = requests_s.send(request) {
+                    println!("Failed to send API request, exiting: {}", e);
+                    return;
+                }
+            }
+        });
+        Ok(Api {
+            token: config.token.clone(),
+            requests,
+        })
+    }
+
+    pub fn requests(&self) -> &Receiver<Request> {
+        &self.requests
+    }
+
+    pub fn respond(
+        &self,
+        mut request: Request,
+        tracker: &mut PresenceTracker,
+        now: DateTime<Local>,
+    ) {
+        let authorized = self.token.as_ref().map_or(true, |token| {
+            let expected = format!("Bearer {}", token);
+            request.headers().iter().any(|header| {
+                header.field.equiv("Authorization") && header.value.as_str() == expected
+            })
+        });
+        let (status, body) = if authorized {
+            let mut content = String::new();
+            match request.as_reader().read_to_string(&mut content) {
+                Ok(_) => route(request.method(), request.url(), &content, tracker, now),
+                Err(e) => (400, json!({ "error": e.to_string() })),
+            }
+        } else {
+            (401, json!({"error": "unauthorized"}))
+        };
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(
+                "Content-Type: application/json"
+                    .parse::<Header>()
+                    .expect("Invalid header"),
+            );
+        if let Err(e) = request.respond(response) {
+            println!("Failed to respond to API request: {}", e);
+        }
+    }
+}
+
+fn route(
+    method: &Method,
+    url: &str,
+    body: &str,
+    tracker: &mut PresenceTracker,
+    now: DateTime<Local>,
+) -> (u16, Value) {
+    let path = url.split('?').next().unwrap_or(url).trim_end_matches('/');
+    match (method, path) {
+        (Method::Get, "/api/devices") => (200, json!(tracker.devices())),
+        (Method::Get, "/api/health") => {
+            let failure = tracker.last_failure();
+            (
+                200,
+                json!({
+                    "status": if failure.is_some() { "degraded" } else { "ok" },
+                    "last_failure": failure,
+                }),
+            )
+        }
+        (Method::Get, path) if path.starts_with("/api/users/") => {
+            let name =
+                url::percent_encoding::percent_decode(path["/api/users/".len()..].as_bytes())
+                    .decode_utf8_lossy()
+                    .into_owned();
+            let devices: Vec<_> = tracker
+                .devices()
+                .into_iter()
+                .filter(|device| device.user == name)
+                .collect();
+            if devices.is_empty() {
+                return (404, json!({ "error": format!("unknown user {}", name) }));
+            }
+            (
+                200,
+                json!({
+                    "name": name,
+                    "home": devices.iter().any(|device| device.online),
+                    "last_seen": devices.iter().filter_map(|device| device.last_seen).max(),
+                    "devices": devices,
+                }),
+            )
+        }
+        (Method::Get, path) if path.starts_with("/api/rtt/") => {
+            let mac = &path["/api/rtt/".len()..];
+            let history = mac
+                .parse()
+                .ok()
+                .and_then(|mac| tracker.rtt(mac).map(|history| (mac, history)));
+            match history {
+                Some((mac, history)) => (
+                    200,
+                    json!({
+                        "mac": mac,
+                        "summary": history.summary(),
+                        "samples": history.samples().collect::<Vec<_>>(),
+                    }),
+                ),
+                None => (404, json!({ "error": format!("no keepalives to {}", mac) })),
+            }
+        }
+        (Method::Post, path) if path.starts_with("/api/users/") => {
+            let (name, archived) = match path["/api/users/".len()..].rsplit_once('/') {
+                Some((name, "archive")) => (name, true),
+                Some((name, "unarchive")) => (name, false),
+                _ => return (404, json!({"error": "not found"})),
+            };
+            let name = url::percent_encoding::percent_decode(name.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned();
+            match tracker.set_archived(&name, archived) {
+                Ok(changed) => {
+                    if changed {
+                        println!(
+                            "{} {}",
+                            if archived { "Archived" } else { "Unarchived" },
+                            name
+                        );
+                    }
+                    (
+                        200,
+                        json!({ "name": name, "archived": archived, "changed": changed }),
+                    )
+                }
+                Err(e @ crate::error::Error::UnknownUser { .. }) => {
+                    (404, json!({ "error": e.to_string() }))
+                }
+                Err(e @ crate::error::Error::ArchivedInConfig { .. }) => {
+                    (409, json!({ "error": e.to_string() }))
+                }
+                Err(e) => (500, json!({ "error": e.to_string() })),
+            }
+        }
+        (Method::Post, "/api/mute") => {
+            let mute: Mute = match serde_json::from_str(body) {
+                Ok(mute) => mute,
+                Err(e) => return (400, json!({ "error": e.to_string() })),
+            };
+            let until = chrono::Duration::from_std(mute.duration)
+                .ok()
+                .and_then(|duration| now.checked_add_signed(duration));
+            let until = match until {
+                Some(until) if until > now => Some(until),
+                Some(_) => None,
+                None => return (400, json!({"error": "duration out of range"})),
+            };
+            match until {
+                Some(until) => println!("Muting notifications until {}", until),
+                None => println!("Unmuting notifications"),
+            }
+            tracker.mute(until);
+            (200, json!({ "muted_until": tracker.muted_until(now) }))
+        }
+        _ => (404, json!({"error": "not found"})),
+    }
+}
@@ -0,0 +1,158 @@
+use crate::history::Entry;
+use crate::state::State;
+use chrono::{DateTime, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::path::Path;
+
+const FORMAT: &str = "houserat-backup";
+const VERSION: u32 = 1;
+
+/// First line of a backup file, describing and checksumming the payload that follows it.
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    format: String,
+    version: u32,
+    sha256: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Backup {
+    pub created: DateTime<Local>,
+    pub config: String,
+    pub state: State,
+    pub history: Vec<Entry>,
+}
+
+fn invalid(path: &Path, reason: &str) -> crate::error::Error {
+    crate::error::Error::InvalidBackup {
+        path: path.to_path_buf(),
+        reason: reason.into(),
+    }
+}
+
+impl Backup {
+    /// Collects the config file content along with everything in the store.
+    pub fn create(config_path: &Path, store: &dyn crate::store::Store) -> crate::Result<Backup> {
+        let config =
+            std::fs::read_to_string(config_path).with_context(|| crate::error::ConfigNotFound {
+                path: config_path.to_path_buf(),
+            })?;
+        Ok(Backup {
+            created: Local::now(),
+            config,
+            state: store.load_state()?,
+            history: store.history(Local.timestamp_opt(0, 0).unwrap())?,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> crate::Result<()> {
+        let payload = serde_json::to_string(self).expect("Failed to serialize backup");
+        let header = Header {
+            format: FORMAT.into(),
+            version: VERSION,
+            sha256: format!("{:x}", Sha256::digest(payload.as_bytes())),
+        };
+        let content = format!(
+            "{}\n{}",
+            serde_json::to_string(&header).expect("Failed to serialize backup header"),
+            payload
+        );
+        std::fs::write(path, content).with_context(|| crate::error::BackupError {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn read(path: &Path) -> crate::Result<Backup> {
+        let content = std::fs::read_to_string(path).with_context(|| crate::error::BackupError {
+            path: path.to_path_buf(),
+        })?;
+        let mut parts = content.splitn(2, '\n');
+        let header: Header = parts
+            .next()
+            .and_then(|header| serde_json::from_str(header).ok())
+            .ok_or_else(|| invalid(path, "missing header"))?;
+        if header.format != FORMAT {
+            return Err(invalid(path, "not a houserat backup"));
+        }
+        if header.version != VERSION {
+            return Err(invalid(
+                path,
+                &format!("unsupported version {}", header.version),
+            ));
+        }
+        let payload = parts.next().unwrap_or("");
+        if format!("{:x}", Sha256::digest(payload.as_bytes())) != header.sha256 {
+            return Err(invalid(path, "checksum mismatch"));
+        }
+        serde_json::from_str(payload).map_err(|e| invalid(path, &e.to_string()))
+    }
+
+    /// Writes the config file if missing and restores state and history into its store, refusing to
+    /// overwrite a different config or existing history unless `force` is set.
+    pub fn restore(&self, config_path: &Path, force: bool) -> crate::Result<()> {
+        match std::fs::read_to_string(config_path) {
+            Ok(ref existing) if *existing == self.config => (),
+            Ok(_) if !force => {
+                return Err(crate::error::Error::RestoreConflict {
+                    reason: format!("config file '{}' differs", config_path.display()),
+                })
+            }
+            _ => {
+                std::fs::write(config_path, &self.config).with_context(|| {
+                    crate::error::ConfigWriteError {
+                        path: config_path.to_path_buf(),
+                    }
+                })?;
+                println!("Restored config file {}", config_path.display());
+            }
+        }
+
+        let config = crate::config::Config::from_file(config_path)?;
+        let mut store = crate::store::open(&config.storage)?;
+        if !force
+            && !store
+                .history(Local.timestamp_opt(0, 0).unwrap())?
+                .is_empty()
+        {
+            return Err(crate::error::Error::RestoreConflict {
+                reason: "store already has history".into(),
+            });
+        }
+        store.save_state(&self.state)?;
+        for entry in &self.history {
+            store.append_history(entry)?;
+        }
+        println!(
+            "Restored state and {} history entries from backup created {}",
+            self.history.len(),
+            self.created.to_rfc2822()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integrity() {
+        let path = std::env::temp_dir().join(format!("houserat-backup-{}", std::process::id()));
+        let backup = Backup {
+            created: Local::now(),
+            config: "interface = \"eth0\"\n".to_string(),
+            state: State::default(),
+            history: Vec::new(),
+        };
+        backup.write(&path).unwrap();
+        assert_eq!(Backup::read(&path).unwrap().config, backup.config);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replace("eth0", "eth1")).unwrap();
+        assert!(Backup::read(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use crate::config;
+use crate::network::Event;
+use aes::cipher::{BlockEncrypt, NewBlockCipher};
+use crossbeam_channel::Sender;
+use pnet::util::MacAddr;
+
+const AF_BLUETOOTH: libc::c_int = 31;
+const BTPROTO_HCI: libc::c_int = 1;
+const SOL_HCI: libc::c_int = 0;
+const HCI_FILTER: libc::c_int = 2;
+const HCI_CHANNEL_RAW: u16 = 0;
+
+const HCI_COMMAND_PKT: u8 = 0x01;
+const HCI_EVENT_PKT: u8 = 0x04;
+const EVT_LE_META_EVENT: u8 = 0x3e;
+const EVT_LE_ADVERTISING_REPORT: u8 = 0x02;
+const LE_SET_SCAN_PARAMETERS: u16 = 0x08 << 10 | 0x000b;
+const LE_SET_SCAN_ENABLE: u16 = 0x08 << 10 | 0x000c;
+
+#[repr(C)]
+struct SockAddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+#[repr(C)]
+struct HciFilter {
+    type_mask: u32,
+    event_mask: [u32; 2],
+    opcode: u16,
+}
+
+/// Whether a resolvable private address was generated from an identity resolving key, using the
+/// `ah` function from the Bluetooth Core spec (Vol 3, Part H, 2.2.2).
+pub fn resolves(irk: &[u8; 16], address: MacAddr) -> bool {
+    if address.0 >> 6 != 0b01 {
+        return false;
+    }
+    let mut block = [0u8; 16];
+    block[13..].copy_from_slice(&[address.0, address.1, address.2]);
+    let mut block: aes::Block = block.into();
+    aes::Aes128::new_from_slice(irk)
+        .expect("IRK is 16 bytes")
+        .encrypt_block(&mut block);
+    block[13..] == [address.3, address.4, address.5]
+}
+
+fn identify(beacons: &[config::Beacon], address: MacAddr) -> Option<MacAddr> {
+    beacons
+        .iter()
+        .find(|beacon| {
+            beacon.mac == address || beacon.irk.map_or(false, |irk| resolves(&irk, address))
+        })
+        .map(|beacon| beacon.mac)
+}
+
+/// Parses the addresses in an LE advertising report event, which stores them little endian.
+fn parse_advertising_report(packet: &[u8]) -> Vec<MacAddr> {
+    let mut addresses = Vec::new();
+    if packet.len() < 5
+        || packet[0] != HCI_EVENT_PKT
+        || packet[1] != EVT_LE_META_EVENT
+        || packet[3] != EVT_LE_ADVERTISING_REPORT
+    {
+        return addresses;
+    }
+    let mut offset = 5;
+    for _ in 0..packet[4] {
+        // event type, address type, address, data length, data, rssi
+        let report = match packet.get(offset..offset + 9) {
+            Some(report) => report,
+            None => break,
+        };
+        let a = &report[2..8];
+        addresses.push(MacAddr(a[5], a[4], a[3], a[2], a[1], a[0]));
+        offset += 9 + report[8] as usize + 1;
+    }
+    addresses
+}
+
+fn io_error() -> crate::error::Error {
+    crate::error::Error::BleError {
+        source: std::io::Error::last_os_error(),
+    }
+}
+
+fn send_command(fd: libc::c_int, opcode: u16, params: &[u8]) -> crate::Result<()> {
+    let mut packet = vec![HCI_COMMAND_PKT, opcode as u8, (opcode >> 8) as u8];
+    packet.push(params.len() as u8);
+    packet.extend_from_slice(params);
+    if unsafe { libc::write(fd, packet.as_ptr() as *const _, packet.len()) } < 0 {
+        return Err(io_error());
+    }
+    Ok(())
+}
+
+/// Passively scans for advertisements on an HCI adapter in a thread of its own, sending
+/// `Event::Beacon` with the identity address of every configured beacon seen.
+pub fn start(adapter: u16, beacons: &[config::Beacon], events: Sender<Event>) -> crate::Result<()> {
+    let fd = unsafe {
+        libc::socket(
+            AF_BLUETOOTH,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            BTPROTO_HCI,
+        )
+    };
+    if fd < 0 {
+        return Err(io_error());
+    }
+    let address = SockAddrHci {
+        hci_family: AF_BLUETOOTH as libc::sa_family_t,
+        hci_dev: adapter,
+        hci_channel: HCI_CHANNEL_RAW,
+    };
+    let filter = HciFilter {
+        type_mask: 1 << HCI_EVENT_PKT,
+        event_mask: [0, 1 << (EVT_LE_META_EVENT - 32)],
+        opcode: 0,
+    };
+    unsafe {
+        if libc::bind(
+            fd,
+            &address as *const SockAddrHci as *const libc::sockaddr,
+            std::mem::size_of::<SockAddrHci>() as libc::socklen_t,
+        ) < 0
+            || libc::setsockopt(
+                fd,
+                SOL_HCI,
+                HCI_FILTER,
+                &filter as *const HciFilter as *const libc::c_void,
+                std::mem::size_of::<HciFilter>() as libc::socklen_t,
+            ) < 0
+        {
+            let error = io_error();
+            libc::close(fd);
+            return Err(error);
+        }
+    }
+
+    // passive scan with 10ms interval and window, public own address and no filter policy
+    send_command(
+        fd,
+        LE_SET_SCAN_PARAMETERS,
+        &[0x00, 0x10, 0x00, 0x10, 0x00, 0x00, 0x00],
+    )?;
+    // enable without filtering duplicates, since repeated advertisements are what keeps devices present
+    send_command(fd, LE_SET_SCAN_ENABLE, &[0x01, 0x00])?;
+
+    let beacons = beacons.to_vec();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 260];
+        loop {
+            let len = unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut _, buffer.len()) };
+            if len < 0 {
+                println!("Failed to read BLE advertisement, exiting: {}", io_error());
+                return;
+            }
+            for address in parse_advertising_report(&buffer[..len as usize]) {
+                if let Some(mac) = identify(&beacons, address) {
+                    if let Err(e) = events.send(Event::Beacon { mac }) {
+                        println!("Failed to send event, exiting: {}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolves() {
+        // sample data for the ah function from the Bluetooth Core spec (Vol 3, Part H, D.7)
+        let irk = [
+            0xec, 0x02, 0x34, 0xa3, 0x57, 0xc8, 0xad, 0x05, 0x34, 0x10, 0x10, 0xa6, 0x0a, 0x39,
+            0x7d, 0x9b,
+        ];
+        assert!(resolves(&irk, MacAddr(0x70, 0x81, 0x94, 0x0d, 0xfb, 0xaa)));
+        assert!(!resolves(&irk, MacAddr(0x70, 0x81, 0x94, 0x0d, 0xfb, 0xab)));
+    }
+
+    #[test]
+    fn test_parse_advertising_report() {
+        let packet = [
+            0x04, 0x3e, 0x0f, 0x02, 0x01, 0x00, 0x01, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x02,
+            0x01, 0x06, 0xc0,
+        ];
+        assert_eq!(
+            parse_advertising_report(&packet),
+            vec![MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55)]
+        );
+    }
+}
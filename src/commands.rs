@@ -0,0 +1,535 @@
+use crate::config::Config;
+use chrono::{DateTime, Local};
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+/// How many changes the audit log keeps in memory, for `/undo` and `/audit`
+const AUDIT_SIZE: usize = 50;
+/// How many changes `/audit` lists
+const AUDIT_SHOWN: usize = 10;
+
+/// A command sent to the bot, e.g. `/wake desktop`.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Wake a device by hostname or MAC
+    Wake(String),
+    /// Turn vacation mode `on` or `off`, or tell whether it's on
+    Vacation(String),
+    /// Apply the change previewed to the chat
+    Confirm,
+    /// Drop the change previewed to the chat
+    Cancel,
+    /// Revert the last change still in effect
+    Undo,
+    /// List the last changes
+    Audit,
+    Unknown(String),
+}
+
+impl Command {
+    /// Parses a message starting with `/`, ignoring the `@botname` suffix Telegram adds in groups.
+    pub fn parse(text: &str) -> Option<Command> {
+        let mut words = text.trim().splitn(2, char::is_whitespace);
+        let command = words.next()?.strip_prefix('/')?;
+        let command = command.split('@').next().unwrap_or(command);
+        let argument = words.next().unwrap_or("").trim().to_string();
+        Some(match command {
+            "wake" => Command::Wake(argument),
+            "vacation" => Command::Vacation(argument),
+            "confirm" => Command::Confirm,
+            "cancel" => Command::Cancel,
+            "undo" => Command::Undo,
+            "audit" => Command::Audit,
+            _ => Command::Unknown(command.to_string()),
+        })
+    }
+}
+
+/// A change commands make to the running configuration, previewed before it's confirmed and
+/// recorded in the audit log so it can be undone.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "setting", rename_all = "snake_case")]
+pub enum Change {
+    Vacation { from: bool, to: bool },
+}
+
+impl Change {
+    fn apply(&self, actions: &mut dyn Actions) {
+        match self {
+            Change::Vacation { to, .. } => {
+                actions.vacation(Some(*to));
+            }
+        }
+    }
+
+    /// Whether the change is still in effect, i.e. nothing changed the same setting since.
+    fn in_effect(&self, actions: &mut dyn Actions) -> bool {
+        match self {
+            Change::Vacation { to, .. } => actions.vacation(None) == *to,
+        }
+    }
+
+    fn reversed(&self) -> Change {
+        match *self {
+            Change::Vacation { from, to } => Change::Vacation { from: to, to: from },
+        }
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        match self {
+            Change::Vacation { from, to } => {
+                write!(f, "vacation mode: {} -> {}", on_off(*from), on_off(*to))
+            }
+        }
+    }
+}
+
+/// A change in the audit log.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Audited {
+    pub timestamp: DateTime<Local>,
+    pub chat_id: i64,
+    pub change: Change,
+    /// Whether this change reverted an earlier one by `/undo`
+    pub undo: bool,
+    /// Set once the change was reverted by `/undo`, not stored as it follows from the changes after
+    #[serde(skip)]
+    pub undone: bool,
+}
+
+/// What commands act on.
+pub trait Actions {
+    /// Sends a Wake-on-LAN packet to a device.
+    fn wake(&mut self, mac: MacAddr) -> crate::Result<()>;
+    /// Turns vacation mode on or off, or leaves it as is with `None`, returning whether it's on.
+    fn vacation(&mut self, on: Option<bool>) -> bool;
+    /// Persists a change in the audit log, so it can be listed and undone after a restart.
+    fn audited(&mut self, audited: &Audited) -> crate::Result<()>;
+}
+
+/// Answers commands, letting chats act only on devices of their own user or users they're
+/// subscribed to. Vacation mode may be toggled by any configured chat.
+///
+/// Commands changing the configuration, e.g. `/vacation on`, are recorded in an audit log that's
+/// persisted by `Actions`, and undone by `/undo`. With `confirm` they're only previewed, and applied by a `/confirm` from the
+/// same chat.
+pub struct Commands {
+    hostnames: HashMap<String, MacAddr>,
+    allowed: HashMap<MacAddr, BTreeSet<i64>>,
+    chats: BTreeSet<i64>,
+    confirm: bool,
+    /// Changes previewed to each chat, awaiting `/confirm`
+    pending: HashMap<i64, Change>,
+    audit: VecDeque<Audited>,
+}
+
+impl Commands {
+    pub fn new(config: &Config) -> Self {
+        let hostnames = config
+            .devices
+            .iter()
+            .map(|device| (device.hostname.to_lowercase(), device.mac))
+            .collect();
+        let allowed = config
+            .rules
+            .iter()
+            .map(|(mac, metadata)| {
+                let chats: BTreeSet<i64> = config
+                    .identities
+                    .get(&metadata.name)
+                    .and_then(|identity| identity.telegram)
+                    .into_iter()
+                    .chain(metadata.identity.telegram)
+                    .collect();
+                (*mac, chats)
+            })
+            .collect();
+        let chats = config
+            .rules
+            .values()
+            .filter_map(|metadata| metadata.identity.telegram)
+            .chain(
+                config
+                    .identities
+                    .values()
+                    .filter_map(|identity| identity.telegram),
+            )
+            .chain(config.admin_chat_id)
+            .collect();
+        Commands {
+            hostnames,
+            allowed,
+            chats,
+            confirm: config.confirm_commands,
+            pending: HashMap::new(),
+            audit: VecDeque::new(),
+        }
+    }
+
+    /// Continues the audit log persisted before, oldest change first.
+    pub fn with_audit(mut self, audit: Vec<Audited>) -> Self {
+        for audited in audit {
+            self.push(audited);
+        }
+        self
+    }
+
+    /// The audit log, oldest change first.
+    pub fn audit(&self) -> impl Iterator<Item = &Audited> {
+        self.audit.iter()
+    }
+
+    fn push(&mut self, audited: Audited) {
+        if audited.undo {
+            // undoing again goes further back rather than undoing the undo
+            if let Some(last) = self.last_undoable() {
+                self.audit[last].undone = true;
+            }
+        }
+        if self.audit.len() == AUDIT_SIZE {
+            self.audit.pop_front();
+        }
+        self.audit.push_back(audited);
+    }
+
+    fn last_undoable(&self) -> Option<usize> {
+        self.audit
+            .iter()
+            .rposition(|audited| !audited.undo && !audited.undone)
+    }
+
+    fn record(
+        &mut self,
+        chat_id: i64,
+        change: Change,
+        undo: bool,
+        actions: &mut dyn Actions,
+        now: DateTime<Local>,
+    ) {
+        println!(
+            "Audit: chat {} {} {}",
+            chat_id,
+            if undo { "undid, changing" } else { "changed" },
+            change
+        );
+        let audited = Audited {
+            timestamp: now,
+            chat_id,
+            change,
+            undo,
+            undone: false,
+        };
+        if let Err(e) = actions.audited(&audited) {
+            println!("Failed to record audit log: {}", e);
+        }
+        self.push(audited);
+    }
+
+    /// Applies a change and records it, or previews it if changes need confirming.
+    fn change(
+        &mut self,
+        chat_id: i64,
+        change: Change,
+        actions: &mut dyn Actions,
+        now: DateTime<Local>,
+    ) -> Option<String> {
+        if self.confirm {
+            self.pending.insert(chat_id, change);
+            return Some(format!(
+                "Would change {}, send /confirm to apply or /cancel",
+                change
+            ));
+        }
+        change.apply(actions);
+        self.record(chat_id, change, false, actions, now);
+        None
+    }
+
+    /// The reply once vacation mode was changed or asked about.
+    fn vacation(actions: &mut dyn Actions) -> String {
+        if actions.vacation(None) {
+            "Vacation mode is on, arrivals are not announced and unknown devices are alerted about"
+                .into()
+        } else {
+            "Vacation mode is off".into()
+        }
+    }
+
+    /// Handles a command from a chat and returns the reply.
+    pub fn handle(
+        &mut self,
+        chat_id: i64,
+        command: Command,
+        actions: &mut dyn Actions,
+        now: DateTime<Local>,
+    ) -> String {
+        match command {
+            Command::Wake(device) => {
+                if device.is_empty() {
+                    return "Usage: /wake <hostname or MAC>".into();
+                }
+                let mac = match self.hostnames.get(&device.to_lowercase()) {
+                    Some(mac) => *mac,
+                    None => match device.parse() {
+                        Ok(mac) => mac,
+                        Err(_) => return format!("Unknown device {}", device),
+                    },
+                };
+                if !self
+                    .allowed
+                    .get(&mac)
+                    .map_or(false, |chats| chats.contains(&chat_id))
+                {
+                    println!("Chat {} is not allowed to wake {}", chat_id, mac);
+                    return format!("Not allowed to wake {}", device);
+                }
+                println!("Waking {} ({}) for chat {}", device, mac, chat_id);
+                match actions.wake(mac) {
+                    Ok(()) => format!("Sent Wake-on-LAN to {}", device),
+                    Err(e) => format!("Failed to wake {}: {}", device, e),
+                }
+            }
+            Command::Vacation(argument) => {
+                let on = match argument.to_lowercase().as_str() {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    "" => None,
+                    _ => return "Usage: /vacation [on|off]".into(),
+                };
+                if !self.chats.contains(&chat_id) {
+                    println!("Chat {} is not allowed to toggle vacation mode", chat_id);
+                    return "Not allowed to toggle vacation mode".into();
+                }
+                let from = actions.vacation(None);
+                if let Some(to) = on.filter(|to| *to != from) {
+                    let change = Change::Vacation { from, to };
+                    if let Some(preview) = self.change(chat_id, change, actions, now) {
+                        return preview;
+                    }
+                }
+                Self::vacation(actions)
+            }
+            Command::Confirm => {
+                let change = match self.pending.remove(&chat_id) {
+                    Some(change) => change,
+                    None => return "Nothing to confirm".into(),
+                };
+                if !change.reversed().in_effect(actions) {
+                    return format!("Not applying {}, it was changed since", change);
+                }
+                change.apply(actions);
+                self.record(chat_id, change, false, actions, now);
+                format!("Changed {}", change)
+            }
+            Command::Cancel => match self.pending.remove(&chat_id) {
+                Some(change) => format!("Dropped {}", change),
+                None => "Nothing to cancel".into(),
+            },
+            Command::Undo => {
+                if !self.chats.contains(&chat_id) {
+                    println!("Chat {} is not allowed to undo changes", chat_id);
+                    return "Not allowed to undo changes".into();
+                }
+                let change = match self.last_undoable() {
+                    Some(last) => self.audit[last].change,
+                    None => return "Nothing to undo".into(),
+                };
+                if !change.in_effect(actions) {
+                    return format!("Can't undo {}, it was changed since", change);
+                }
+                let reverted = change.reversed();
+                reverted.apply(actions);
+                self.record(chat_id, reverted, true, actions, now);
+                format!("Undid {}, now {}", change, reverted)
+            }
+            Command::Audit => {
+                if !self.chats.contains(&chat_id) {
+                    return "Not allowed to view the audit log".into();
+                }
+                if self.audit.is_empty() {
+                    return "No changes yet".into();
+                }
+                let skipped = self.audit.len().saturating_sub(AUDIT_SHOWN);
+                self.audit
+                    .iter()
+                    .skip(skipped)
+                    .map(|audited| {
+                        format!(
+                            "{} chat {}: {}{}{}",
+                            audited.timestamp.format("%Y-%m-%d %H:%M"),
+                            audited.chat_id,
+                            if audited.undo { "undo, " } else { "" },
+                            audited.change,
+                            if audited.undone { " (undone)" } else { "" }
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            Command::Unknown(command) => format!("Unknown command /{}", command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::{Identity, Metadata};
+
+    const MAC: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+    #[derive(Default)]
+    struct Recorder {
+        woken: Option<MacAddr>,
+        vacation: bool,
+        audit: Vec<Audited>,
+    }
+
+    impl Actions for Recorder {
+        fn wake(&mut self, mac: MacAddr) -> crate::Result<()> {
+            self.woken = Some(mac);
+            Ok(())
+        }
+
+        fn vacation(&mut self, on: Option<bool>) -> bool {
+            self.vacation = on.unwrap_or(self.vacation);
+            self.vacation
+        }
+
+        fn audited(&mut self, audited: &Audited) -> crate::Result<()> {
+            self.audit.push(audited.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Command::parse("/wake@houserat_bot  desktop "),
+            Some(Command::Wake("desktop".into()))
+        );
+        assert_eq!(Command::parse("/wake"), Some(Command::Wake("".into())));
+        assert_eq!(
+            Command::parse("/start"),
+            Some(Command::Unknown("start".into()))
+        );
+        assert_eq!(Command::parse("hello"), None);
+    }
+
+    fn telegram(chat_id: i64) -> Identity {
+        Identity {
+            telegram: Some(chat_id),
+            ..Default::default()
+        }
+    }
+
+    fn config(confirm_commands: bool) -> config::Config {
+        let mut rules = HashMap::new();
+        rules.insert(
+            MAC,
+            Metadata::new("Owner".into(), None, None, "Sub".into(), telegram(2)),
+        );
+        let mut identities = HashMap::new();
+        identities.insert("Owner".to_string(), telegram(1));
+        identities.insert("Sub".to_string(), telegram(2));
+        config::Config {
+            commands: true,
+            confirm_commands,
+            rules,
+            devices: vec![config::Device {
+                hostname: "Desktop".into(),
+                mac: MAC,
+            }],
+            identities,
+            ..config::Config::for_tests()
+        }
+    }
+
+    #[test]
+    fn test_authorization() {
+        let mut commands = Commands::new(&config(false));
+        let now = Local::now();
+        let mut wake = |device: &str, chat_id| {
+            let mut recorder = Recorder::default();
+            commands.handle(chat_id, Command::Wake(device.into()), &mut recorder, now);
+            recorder.woken
+        };
+
+        assert_eq!(wake("desktop", 1), Some(MAC));
+        assert_eq!(wake("00:11:22:33:44:55", 2), Some(MAC));
+        assert_eq!(wake("desktop", 3), None);
+        assert_eq!(wake("laptop", 1), None);
+
+        let mut recorder = Recorder::default();
+        commands.handle(3, Command::Vacation("on".into()), &mut recorder, now);
+        assert!(!recorder.vacation);
+        commands.handle(2, Command::Vacation("on".into()), &mut recorder, now);
+        assert!(recorder.vacation);
+
+        // undone after a restart, from the persisted audit log
+        let mut commands = Commands::new(&config(false)).with_audit(recorder.audit.clone());
+        commands.handle(3, Command::Undo, &mut recorder, now);
+        assert!(recorder.vacation);
+        commands.handle(2, Command::Undo, &mut recorder, now);
+        assert!(!recorder.vacation);
+    }
+
+    #[test]
+    fn test_confirm_and_undo() {
+        let mut commands = Commands::new(&config(true));
+        let mut recorder = Recorder::default();
+        let now = Local::now();
+        let mut handle = |chat_id, command| commands.handle(chat_id, command, &mut recorder, now);
+
+        assert_eq!(
+            handle(1, Command::Vacation("on".into())),
+            "Would change vacation mode: off -> on, send /confirm to apply or /cancel"
+        );
+        assert_eq!(handle(2, Command::Confirm), "Nothing to confirm");
+        assert_eq!(
+            handle(1, Command::Confirm),
+            "Changed vacation mode: off -> on"
+        );
+        assert_eq!(handle(1, Command::Confirm), "Nothing to confirm");
+
+        handle(2, Command::Vacation("off".into()));
+        assert_eq!(
+            handle(2, Command::Cancel),
+            "Dropped vacation mode: on -> off"
+        );
+
+        // a preview gone stale isn't applied
+        handle(2, Command::Vacation("off".into()));
+        handle(1, Command::Vacation("off".into()));
+        handle(1, Command::Confirm);
+        assert_eq!(
+            handle(2, Command::Confirm),
+            "Not applying vacation mode: on -> off, it was changed since"
+        );
+
+        assert_eq!(
+            handle(1, Command::Undo),
+            "Undid vacation mode: on -> off, now vacation mode: off -> on"
+        );
+        assert_eq!(
+            handle(1, Command::Undo),
+            "Undid vacation mode: off -> on, now vacation mode: on -> off"
+        );
+        assert_eq!(handle(1, Command::Undo), "Nothing to undo");
+        let audit: Vec<(bool, bool)> = commands
+            .audit()
+            .map(|audited| (audited.undo, audited.undone))
+            .collect();
+        assert_eq!(
+            audit,
+            vec![(false, true), (false, true), (true, false), (true, false)]
+        );
+        let restarted = Commands::new(&config(true)).with_audit(recorder.audit.clone());
+        assert!(restarted.audit().eq(commands.audit()));
+    }
+}
@@ -1,10 +1,12 @@
+use crate::telegram::ParseMode;
 use chrono::NaiveTime;
 use pnet::util::MacAddr;
+use schemars::JsonSchema;
 use serde::Deserialize;
 use snafu::ResultExt;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::net::Ipv4Addr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 pub fn deserialize_naivetime<'de, D>(d: D) -> Result<NaiveTime, D::Error>
@@ -32,38 +34,359 @@ where
     d.deserialize_str(V)
 }
 
-#[derive(Debug, Deserialize)]
+/// Like `deserialize_naivetime`, for optional fields that also have `#[serde(default)]`.
+fn deserialize_optional_naivetime<'de, D>(d: D) -> Result<Option<NaiveTime>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    deserialize_naivetime(d).map(Some)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Period {
     #[serde(deserialize_with = "deserialize_naivetime")]
+    #[schemars(with = "String")]
     start: NaiveTime,
     #[serde(deserialize_with = "deserialize_naivetime")]
+    #[schemars(with = "String")]
     end: NaiveTime,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Json,
+    Sqlite,
+    Sled,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Storage {
+    #[serde(default = "default_backend")]
+    pub backend: Backend,
+    /// Directory to store files in, by default the `StateDirectory=` systemd gives the service or
+    /// else `/var/lib/houserat`
+    #[serde(default = "default_storage_path")]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Sent with every delivery, e.g. `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Signs deliveries with HMAC-SHA256 in the `X-Houserat-Signature` header
+    pub secret: Option<String>,
+    /// Events to deliver, all if empty
+    #[serde(default)]
+    pub events: Vec<crate::Status>,
+    /// Encrypts the body end to end, so relays on the way can't read it
+    pub encrypt: Option<Encryption>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionKind {
+    Age,
+    Pgp,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Encryption {
+    pub kind: EncryptionKind,
+    /// An age public key, e.g. `age1...`, or for PGP a file holding the public key
+    pub recipient: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Leader {
+    #[serde(default = "default_leader_id")]
+    pub id: String,
+    #[serde(with = "humantime_serde", default = "default_lease")]
+    #[schemars(with = "String")]
+    pub lease: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+pub enum PollerKind {
+    #[serde(rename = "unifi")]
+    UniFi,
+    #[serde(rename = "openwrt")]
+    OpenWrt,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Poller {
+    pub kind: PollerKind,
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    /// UniFi site name
+    pub site: Option<String>,
+    /// OpenWrt wireless interfaces, all hostapd interfaces if empty
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+    #[serde(with = "humantime_serde", default = "default_poll_interval")]
+    #[schemars(with = "String")]
+    pub interval: Duration,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// Ask the poller whether a device is still associated before announcing its departure
+    #[serde(default)]
+    pub cross_check: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseFormat {
+    Dnsmasq,
+    Kea,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Leases {
+    pub path: PathBuf,
+    #[serde(default = "default_lease_format")]
+    pub format: LeaseFormat,
+    /// Check for an active lease before announcing a device's departure
+    #[serde(default)]
+    pub cross_check: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Ble {
+    /// Index of the HCI adapter, e.g. 0 for hci0
+    #[serde(default)]
+    pub adapter: u16,
+    #[serde(with = "humantime_serde", default = "default_ble_absence")]
+    #[schemars(with = "String")]
+    pub absence: Duration,
+}
+
+/// How packets are captured, for tuning to the network houserat sits on.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Capture {
+    /// BPF expression for extra traffic to capture, or'ed with houserat's own filter
+    pub filter: Option<String>,
+    /// Put the interface in promiscuous mode, unnecessary on a mirrored port
+    #[serde(default = "default_promisc")]
+    pub promisc: bool,
+    /// Bytes captured of each packet, at least 46, the libpcap default being enough for any
+    pub snaplen: Option<i32>,
+    /// Size of the kernel capture buffer in bytes
+    pub buffer_size: Option<i32>,
+    /// How long libpcap may buffer packets before handing them over, lower reduces latency
+    #[serde(with = "humantime_serde", default = "default_capture_timeout")]
+    #[schemars(with = "String")]
+    pub timeout: Duration,
+    pub sampling: Option<Sampling>,
+}
+
+/// Thinning out ARP on very busy networks, so low-power hosts keep up. DHCP is never sampled.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Sampling {
+    /// Process only 1 in this many ARP packets of each device
+    pub rate: u32,
+    /// Packets per second above which sampling starts
+    #[serde(default = "default_sampling_threshold")]
+    pub threshold: u32,
+    /// How long the packet rate is measured over, and how often it's measured again
+    #[serde(with = "humantime_serde", default = "default_sampling_interval")]
+    #[schemars(with = "String")]
+    pub interval: Duration,
+}
+
+/// Pacing of keepalives, so they don't go out in a burst that a managed switch may flag as a storm.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Keepalive {
+    /// How much of each tick keepalives are spread over, zero to send them all at once
+    #[serde(with = "humantime_serde", default = "default_keepalive_spread")]
+    #[schemars(with = "String")]
+    pub spread: Duration,
+    /// Most keepalives sent per second
+    #[serde(default = "default_keepalive_rate")]
+    pub rate: u32,
+}
+
+/// Operational alerts for the admin chat, besides startup, shutdown and capture problems.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Alerts {
+    /// Notifications failing to deliver in a row before alerting, 0 to never alert
+    #[serde(default = "default_delivery_failures")]
+    pub delivery_failures: u32,
+    /// When to send a daily summary of devices first seen in the last day that belong to no user
+    #[serde(default, deserialize_with = "deserialize_optional_naivetime")]
+    #[schemars(with = "Option<String>")]
+    pub unknown_summary: Option<NaiveTime>,
+}
+
+/// Presence events as JSON lines, for other tools to consume.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Output {
+    /// Unix domain socket to write events to every connected client on
+    pub socket_path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Api {
+    /// Address to serve the status API on
+    #[serde(default = "default_api_listen")]
+    pub listen: String,
+    /// Bearer token required in the `Authorization` header, if set
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    #[default]
+    System,
+    Session,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Dbus {
+    /// Bus to export the `org.houserat.Presence` service on
+    #[serde(default)]
+    pub bus: Bus,
+}
+
+/// Never transmitting anything, for captures from a switch SPAN port where that's impossible.
+/// Devices are considered present for a while after each sighting, depending on its kind.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Passive {
+    /// How long an ARP packet or other sighting counts as presence
+    #[serde(with = "humantime_serde", default = "default_passive_arp")]
+    #[schemars(with = "String")]
+    pub arp: Duration,
+    /// How long a DHCP request counts as presence
+    #[serde(with = "humantime_serde", default = "default_passive_dhcp")]
+    #[schemars(with = "String")]
+    pub dhcp: Duration,
+    /// How long an mDNS or SSDP announcement counts as presence
+    #[serde(with = "humantime_serde", default = "default_passive_mdns")]
+    #[schemars(with = "String")]
+    pub mdns: Duration,
+}
+
+/// Periodic "still away"/"still home" reminders about a user, e.g. for caregivers.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Reminder {
+    /// How often to remind while the user is away
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "Option<String>")]
+    pub away: Option<Duration>,
+    /// How often to remind while the user is home
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "Option<String>")]
+    pub home: Option<Duration>,
+    /// Most reminders to send per absence or stay
+    #[serde(default = "default_max_reminders")]
+    pub max: u32,
+}
+
+/// A Bluetooth LE device, recognized by its identity address or, when it uses resolvable private
+/// addresses, its identity resolving key.
+#[derive(Debug, Clone)]
+pub struct Beacon {
+    pub mac: MacAddr,
+    pub irk: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct ConfigDevice<'a> {
     hostname: Option<&'a str>,
+    #[schemars(with = "String")]
+    mac: MacAddr,
+    #[serde(default)]
+    ping: bool,
+    /// Alert the admin chat when the device's DHCP hostname changes
+    #[serde(default)]
+    hostname_alert: bool,
+    /// Alert the admin chat when the device goes offline, for infrastructure such as a NAS
+    #[serde(default)]
+    always_online: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ConfigBeacon<'a> {
+    #[schemars(with = "String")]
     mac: MacAddr,
+    irk: Option<&'a str>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct User<'a> {
     name: &'a str,
     icon: Option<&'a str>,
     username: Option<&'a str>,
     chat_id: Option<i64>,
+    /// Matrix user ID, e.g. `@alice:example.org`
+    matrix: Option<&'a str>,
+    email: Option<&'a str>,
+    /// Phone number in E.164 format, e.g. `+15551234567`
+    phone: Option<&'a str>,
+    /// Token of the bot notifying this user as a subscriber, instead of the global one
+    bot_token: Option<&'a str>,
     subscriber: Option<&'a str>,
+    #[serde(default)]
+    archived: bool,
     #[serde(default, rename = "device")]
     devices: Vec<ConfigDevice<'a>>,
+    #[serde(default, rename = "beacon")]
+    beacons: Vec<ConfigBeacon<'a>>,
+    reminder: Option<Reminder>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct ConfigData<'a> {
     interface: &'a str,
-    bot_token: &'a str,
+    bot_token: Option<&'a str>,
+    bot_token_env: Option<&'a str>,
+    bot_token_file: Option<PathBuf>,
+    admin_chat_id: Option<i64>,
     #[serde(with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
     cooldown: Option<Duration>,
     quiet_period: Option<Period>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    commands: bool,
+    #[serde(default)]
+    confirm_commands: bool,
+    #[serde(default)]
+    mdns: bool,
+    #[serde(default)]
+    vacation: bool,
+    daily_limit: Option<u32>,
+    #[serde(default)]
+    parse_mode: ParseMode,
+    #[serde(default)]
+    storage: Storage,
+    #[serde(default, rename = "webhook")]
+    webhooks: Vec<Webhook>,
+    leader: Option<Leader>,
+    #[serde(default, rename = "poller")]
+    pollers: Vec<Poller>,
+    leases: Option<Leases>,
+    #[serde(default)]
+    ble: Ble,
+    #[serde(default)]
+    capture: Capture,
+    #[serde(default)]
+    keepalive: Keepalive,
+    #[serde(default)]
+    alerts: Alerts,
+    passive: Option<Passive>,
+    #[serde(default)]
+    ignore: crate::filter::Filter,
+    api: Option<Api>,
+    dbus: Option<Dbus>,
+    output: Option<Output>,
     #[serde(borrow, rename = "user")]
     users: Vec<User<'a>>,
 }
@@ -75,7 +398,7 @@ pub struct Interface {
     pub addresses: NetworkAddresses,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NetworkAddresses {
     pub mac: MacAddr,
     pub ip: Ipv4Addr,
@@ -91,10 +414,47 @@ pub struct Device {
 pub struct Config {
     pub interface: Interface,
     pub bot_token: String,
+    /// Bot tokens of users notified by a bot of their own, by name
+    pub bot_tokens: HashMap<String, String>,
+    /// Chat to alert about problems with houserat itself
+    pub admin_chat_id: Option<i64>,
     pub cooldown: Option<chrono::Duration>,
     pub quiet_period: Option<Period>,
+    pub dry_run: bool,
+    /// Answer commands such as `/wake` sent to the bot
+    pub commands: bool,
+    /// Preview changes made by commands, applying them only once confirmed with `/confirm`
+    pub confirm_commands: bool,
+    /// Describe arriving devices from their mDNS services
+    pub mdns: bool,
+    /// Start in vacation mode, not announcing arrivals but alerting about unknown devices
+    pub vacation: bool,
+    /// Most notifications per user per day, the rest are collapsed into a digest
+    pub daily_limit: Option<u32>,
+    /// How notifications are formatted by Telegram
+    pub parse_mode: ParseMode,
+    pub storage: Storage,
+    pub webhooks: Vec<Webhook>,
+    pub leader: Option<Leader>,
+    pub pollers: Vec<Poller>,
+    pub leases: Option<Leases>,
+    pub ble: Ble,
+    pub beacons: Vec<Beacon>,
+    pub capture: Capture,
+    pub keepalive: Keepalive,
+    pub alerts: Alerts,
+    pub passive: Option<Passive>,
+    pub ignore: crate::filter::Filter,
+    pub api: Option<Api>,
+    pub dbus: Option<Dbus>,
+    pub output: Option<Output>,
     pub rules: HashMap<MacAddr, crate::Metadata>,
     pub devices: Vec<Device>,
+    /// Where each user is reached when subscribed to others' devices
+    pub identities: HashMap<String, crate::Identity>,
+    pub archived: BTreeSet<String>,
+    /// Rules of the users archived in the state, to be restored if they're unarchived while running
+    pub parked: HashMap<MacAddr, crate::Metadata>,
 }
 
 impl Period {
@@ -107,6 +467,189 @@ impl Period {
     }
 }
 
+impl std::fmt::Display for Period {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl std::fmt::Display for Bus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::System => write!(f, "system"),
+            Self::Session => write!(f, "session"),
+        }
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Sqlite => write!(f, "sqlite"),
+            Self::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            backend: default_backend(),
+            path: default_storage_path(),
+        }
+    }
+}
+
+fn default_backend() -> Backend {
+    Backend::Json
+}
+
+fn default_storage_path() -> PathBuf {
+    std::env::var_os("STATE_DIRECTORY")
+        .and_then(|dirs| std::env::split_paths(&dirs).next())
+        .unwrap_or_else(|| PathBuf::from("/var/lib/houserat"))
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+fn default_leader_id() -> String {
+    let mut hostname = [0u8; 256];
+    unsafe { libc::gethostname(hostname.as_mut_ptr() as *mut libc::c_char, hostname.len()) };
+    let len = hostname
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(hostname.len());
+    format!(
+        "{}-{}",
+        String::from_utf8_lossy(&hostname[..len]),
+        std::process::id()
+    )
+}
+
+fn default_lease() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_poll_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_lease_format() -> LeaseFormat {
+    LeaseFormat::Dnsmasq
+}
+
+fn default_passive_arp() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+fn default_passive_dhcp() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+fn default_passive_mdns() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+fn default_api_listen() -> String {
+    "127.0.0.1:8080".into()
+}
+
+fn default_max_reminders() -> u32 {
+    3
+}
+
+fn default_ble_absence() -> Duration {
+    Duration::from_secs(300)
+}
+
+impl Default for Ble {
+    fn default() -> Self {
+        Ble {
+            adapter: 0,
+            absence: default_ble_absence(),
+        }
+    }
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Capture {
+            filter: None,
+            promisc: default_promisc(),
+            snaplen: None,
+            buffer_size: None,
+            timeout: default_capture_timeout(),
+            sampling: None,
+        }
+    }
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Alerts {
+            delivery_failures: default_delivery_failures(),
+            unknown_summary: None,
+        }
+    }
+}
+
+fn default_delivery_failures() -> u32 {
+    3
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            spread: default_keepalive_spread(),
+            rate: default_keepalive_rate(),
+        }
+    }
+}
+
+fn default_keepalive_spread() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_keepalive_rate() -> u32 {
+    20
+}
+
+fn default_promisc() -> bool {
+    true
+}
+
+fn default_capture_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_sampling_threshold() -> u32 {
+    1000
+}
+
+fn default_sampling_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn parse_irk(irk: &str) -> Option<[u8; 16]> {
+    let irk = irk.replace(':', "");
+    if irk.len() != 32 || !irk.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&irk[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
 impl NetworkAddresses {
     pub fn new(mac: MacAddr, ip: Ipv4Addr) -> NetworkAddresses {
         NetworkAddresses { mac, ip }
@@ -123,6 +666,11 @@ impl Config {
         let config_data: ConfigData = toml::from_str(&config_content)?;
 
         let interface = Interface::from_name(config_data.interface)?;
+        let bot_token = resolve_bot_token(
+            config_data.bot_token,
+            config_data.bot_token_env,
+            config_data.bot_token_file.as_deref(),
+        )?;
 
         let cooldown = if let Some(cooldown) = config_data.cooldown {
             Some(
@@ -133,13 +681,56 @@ impl Config {
             None
         };
 
+        if let Some(snaplen) = config_data.capture.snaplen {
+            if snaplen < crate::network::MIN_SNAPLEN {
+                return Err(crate::error::Error::InvalidSnaplen { snaplen });
+            }
+        }
+
+        for webhook in &config_data.webhooks {
+            reqwest::Url::parse(&webhook.url).with_context(|| crate::error::InvalidWebhookUrl {
+                url: webhook.url.clone(),
+            })?;
+        }
+
+        let archived: BTreeSet<String> = config_data
+            .users
+            .iter()
+            .filter(|u| u.archived)
+            .map(|u| u.name.into())
+            .collect();
+
         let users: HashMap<&str, &User> = config_data.users.iter().map(|u| (u.name, u)).collect();
         let mut rules: HashMap<MacAddr, crate::Metadata> = HashMap::new();
         let mut devices = Vec::new();
+        let mut beacons = Vec::new();
+        let mut identities: HashMap<String, crate::Identity> = HashMap::new();
+        for user in &config_data.users {
+            let identity = crate::Identity {
+                telegram: user.chat_id,
+                matrix: user.matrix.map(String::from),
+                email: user.email.map(String::from),
+                phone: user.phone.map(String::from),
+            };
+            identity.validate(user.name)?;
+            identities.insert(user.name.into(), identity);
+        }
+        let mut bot_tokens = HashMap::new();
+        for user in &config_data.users {
+            if let Some(token) = user.bot_token {
+                let token = token.trim();
+                if token.is_empty() {
+                    return Err(crate::error::Error::EmptyBotToken {
+                        from: format!("user {}", user.name),
+                    });
+                }
+                bot_tokens.insert(user.name.into(), token.into());
+            }
+        }
         for user in &config_data.users {
             let subscriber = match &user.subscriber {
                 Some(subscriber) => {
-                    if user.devices.is_empty() {
+                    if user.devices.is_empty() && user.beacons.is_empty() {
                         return Err(crate::error::Error::NoDevices {
                             user: user.name.into(),
                         });
@@ -149,7 +740,7 @@ impl Config {
                         .ok_or_else(|| unknown_user(&subscriber))?
                 }
                 None => {
-                    if !user.devices.is_empty() {
+                    if !user.devices.is_empty() || !user.beacons.is_empty() {
                         return Err(crate::error::Error::NoSubscriber {
                             user: user.name.into(),
                         });
@@ -157,11 +748,15 @@ impl Config {
                     continue;
                 }
             };
-            let chat_id = subscriber
-                .chat_id
-                .ok_or_else(|| crate::error::Error::MissingChatId {
+            if archived.contains(user.name) || archived.contains(subscriber.name) {
+                continue;
+            }
+            let identity = &identities[subscriber.name];
+            if identity.is_empty() {
+                return Err(crate::error::Error::NoIdentity {
                     user: subscriber.name.into(),
-                })?;
+                });
+            }
             for device in &user.devices {
                 if let Some(hostname) = device.hostname {
                     devices.push(Device {
@@ -169,51 +764,188 @@ impl Config {
                         mac: device.mac,
                     });
                 }
-                rules
-                    .insert(
-                        device.mac,
-                        crate::Metadata::new(
-                            user.name.into(),
-                            user.icon.map(|s| s.into()),
-                            user.username.map(|s| s.into()),
-                            subscriber.name.into(),
-                            chat_id,
-                        ),
-                    )
-                    .map_or(Ok(()), |v| {
-                        Err(crate::error::Error::DuplicateDevice {
-                            device: device.mac,
-                            user: user.name.into(),
-                            orig_user: v.name,
+            }
+            for beacon in &user.beacons {
+                beacons.push(Beacon {
+                    mac: beacon.mac,
+                    irk: beacon
+                        .irk
+                        .map(|irk| {
+                            parse_irk(irk).ok_or_else(|| crate::error::Error::InvalidIrk {
+                                user: user.name.into(),
+                            })
                         })
-                    })?;
+                        .transpose()?,
+                });
+            }
+            let macs = user
+                .devices
+                .iter()
+                .map(|d| (d.mac, d.ping, d.hostname_alert, d.always_online));
+            let beacons = user.beacons.iter().map(|b| (b.mac, false, false, false));
+            for (mac, ping, hostname_alert, always_online) in macs.chain(beacons) {
+                let mut metadata = crate::Metadata::new(
+                    user.name.into(),
+                    user.icon.map(|s| s.into()),
+                    user.username.map(|s| s.into()),
+                    subscriber.name.into(),
+                    identity.clone(),
+                );
+                metadata.ping = ping;
+                metadata.hostname_alert = hostname_alert;
+                metadata.always_online = always_online;
+                metadata.reminder = user.reminder.clone();
+                rules.insert(mac, metadata).map_or(Ok(()), |v| {
+                    Err(crate::error::Error::DuplicateDevice {
+                        device: mac,
+                        user: user.name.into(),
+                        orig_user: v.name,
+                    })
+                })?;
             }
         }
 
         Ok(Config {
             interface,
-            bot_token: config_data.bot_token.into(),
+            bot_token,
+            bot_tokens,
+            admin_chat_id: config_data.admin_chat_id,
             cooldown,
             quiet_period: config_data.quiet_period,
+            dry_run: config_data.dry_run,
+            commands: config_data.commands,
+            confirm_commands: config_data.confirm_commands,
+            mdns: config_data.mdns,
+            vacation: config_data.vacation,
+            daily_limit: config_data.daily_limit,
+            parse_mode: config_data.parse_mode,
+            storage: config_data.storage,
+            webhooks: config_data.webhooks,
+            leader: config_data.leader,
+            pollers: config_data.pollers,
+            leases: config_data.leases,
+            ble: config_data.ble,
+            beacons,
+            capture: config_data.capture,
+            keepalive: config_data.keepalive,
+            alerts: config_data.alerts,
+            passive: config_data.passive,
+            ignore: config_data.ignore,
+            api: config_data.api,
+            dbus: config_data.dbus,
+            output: config_data.output,
             rules,
             devices,
+            identities,
+            archived,
+            parked: HashMap::new(),
         })
     }
+
+    /// Stops tracking the users archived in the state too, e.g. by `houserat archive`, and the
+    /// devices of users subscribed to them. The state isn't read while parsing, so that checking a
+    /// config never opens the store.
+    pub fn archive(&mut self, archived: &BTreeSet<String>) {
+        let identities = &self.identities;
+        self.archived.extend(
+            archived
+                .iter()
+                .filter(|u| identities.contains_key(*u))
+                .cloned(),
+        );
+        let archived = &self.archived;
+        let (parked, rules) =
+            std::mem::take(&mut self.rules)
+                .into_iter()
+                .partition(|(_, metadata)| {
+                    archived.contains(&metadata.name)
+                        || archived.contains(&metadata.subscriber_name)
+                });
+        self.rules = rules;
+        self.parked.extend(parked);
+        let rules = &self.rules;
+        self.devices
+            .retain(|device| rules.contains_key(&device.mac));
+        self.beacons
+            .retain(|beacon| rules.contains_key(&beacon.mac));
+    }
+
+    /// A dry run config without users on a made-up `eth0`, for tests to fill in what they need.
+    #[doc(hidden)]
+    pub fn for_tests() -> Config {
+        Config {
+            interface: Interface {
+                name: "eth0".to_string(),
+                index: 0,
+                addresses: NetworkAddresses::new(MacAddr::zero(), Ipv4Addr::new(192, 168, 1, 1)),
+            },
+            bot_token: String::new(),
+            bot_tokens: HashMap::new(),
+            admin_chat_id: None,
+            cooldown: None,
+            quiet_period: None,
+            dry_run: true,
+            commands: false,
+            confirm_commands: false,
+            mdns: false,
+            vacation: false,
+            daily_limit: None,
+            parse_mode: Default::default(),
+            storage: Storage::default(),
+            webhooks: Vec::new(),
+            leader: None,
+            pollers: Vec::new(),
+            leases: None,
+            ble: Ble::default(),
+            beacons: Vec::new(),
+            capture: Capture::default(),
+            keepalive: Keepalive::default(),
+            alerts: Alerts::default(),
+            passive: None,
+            ignore: Default::default(),
+            api: None,
+            dbus: None,
+            output: None,
+            rules: HashMap::new(),
+            devices: Vec::new(),
+            identities: HashMap::new(),
+            archived: Default::default(),
+            parked: HashMap::new(),
+        }
+    }
+
+    /// Token of the bot notifying a subscriber.
+    pub fn bot_token_for(&self, subscriber: &str) -> &str {
+        self.bot_tokens
+            .get(subscriber)
+            .map_or(&self.bot_token, String::as_str)
+    }
 }
 
 impl Interface {
+    pub fn all() -> Vec<Interface> {
+        pnet::datalink::interfaces()
+            .into_iter()
+            .filter(|iface| !iface.is_loopback())
+            .filter_map(|iface| Interface::from_network_interface(iface).ok())
+            .collect()
+    }
+
     fn from_name(name: &str) -> crate::Result<Interface> {
-        let interface = match pnet::datalink::interfaces()
+        match pnet::datalink::interfaces()
             .into_iter()
             .find(|iface| iface.name == name)
         {
-            Some(interface) => interface,
-            None => {
-                return Err(crate::error::Error::UnknownInterface {
-                    interface: name.into(),
-                })
-            }
-        };
+            Some(interface) => Interface::from_network_interface(interface),
+            None => Err(crate::error::Error::UnknownInterface {
+                interface: name.into(),
+            }),
+        }
+    }
+
+    fn from_network_interface(
+        interface: pnet::datalink::NetworkInterface,
+    ) -> crate::Result<Interface> {
         let mac = match interface.mac {
             Some(mac) => mac,
             None => {
@@ -243,6 +975,40 @@ impl Interface {
     }
 }
 
+/// Takes the bot token from the config itself, an environment variable or a file such as a
+/// systemd credential, so that it needn't be in a config that's readable by everyone.
+fn resolve_bot_token(
+    token: Option<&str>,
+    env: Option<&str>,
+    file: Option<&Path>,
+) -> crate::Result<String> {
+    let (token, from) = match (token, env, file) {
+        (Some(token), None, None) => (token.to_string(), "bot_token".to_string()),
+        (None, Some(var), None) => (
+            std::env::var(var).map_err(|_| crate::error::Error::BotTokenEnv { var: var.into() })?,
+            format!("environment variable {}", var),
+        ),
+        (None, None, Some(path)) => (
+            std::fs::read_to_string(path).with_context(|| crate::error::BotTokenFile {
+                path: path.to_path_buf(),
+            })?,
+            format!("'{}'", path.display()),
+        ),
+        (None, None, None) => return Err(crate::error::Error::MissingBotToken),
+        _ => return Err(crate::error::Error::ConflictingBotToken),
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(crate::error::Error::EmptyBotToken { from });
+    }
+    Ok(token.into())
+}
+
+/// A JSON Schema of the config file, for editors to complete and validate it.
+pub fn schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(ConfigData)
+}
+
 fn unknown_user(user: &str) -> crate::error::Error {
     crate::error::Error::UnknownUser { user: user.into() }
 }
@@ -269,4 +1035,42 @@ mod tests {
         assert_eq!(period1.is_between(now), true);
         assert_eq!(period2.is_between(now), false);
     }
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let properties = &schema["properties"];
+        assert_eq!(properties["interface"]["type"], "string");
+        assert!(properties["user"].is_object());
+        assert!(properties["passive"].is_object());
+        assert!(schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&"interface".into()));
+    }
+
+    #[test]
+    fn test_resolve_bot_token() {
+        std::env::set_var("HOUSERAT_TEST_BOT_TOKEN", "123:abc\n");
+        assert_eq!(
+            resolve_bot_token(None, Some("HOUSERAT_TEST_BOT_TOKEN"), None).unwrap(),
+            "123:abc"
+        );
+        assert_eq!(
+            resolve_bot_token(Some("123:abc"), None, None).unwrap(),
+            "123:abc"
+        );
+        assert!(matches!(
+            resolve_bot_token(None, Some("HOUSERAT_TEST_MISSING"), None),
+            Err(crate::error::Error::BotTokenEnv { .. })
+        ));
+        assert!(matches!(
+            resolve_bot_token(Some("123:abc"), Some("HOUSERAT_TEST_BOT_TOKEN"), None),
+            Err(crate::error::Error::ConflictingBotToken)
+        ));
+        assert!(matches!(
+            resolve_bot_token(None, None, None),
+            Err(crate::error::Error::MissingBotToken)
+        ));
+    }
 }
@@ -0,0 +1,753 @@
+use crate::config::{self, Bus};
+use crate::tracker::DeviceStatus;
+use crate::Status;
+use crossbeam_channel::Sender;
+use pnet::util::MacAddr;
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::os::unix::net::UnixStream;
+
+const NAME: &str = "org.houserat.Presence";
+const PATH: &str = "/org/houserat/Presence";
+const INTERFACE: &str = "org.houserat.Presence";
+const SYSTEM_BUS_ADDRESS: &str = "unix:path=/var/run/dbus/system_bus_socket";
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Largest message accepted, as in the reference implementation
+const MAX_MESSAGE: usize = 128 << 20;
+const NAME_FLAG_DO_NOT_QUEUE: u32 = 0x4;
+const NAME_PRIMARY_OWNER: u32 = 1;
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.houserat.Presence">
+    <method name="GetDevices">
+      <arg name="devices" type="a(sssb)" direction="out"/>
+    </method>
+    <signal name="DeviceArrived">
+      <arg name="mac" type="s"/>
+      <arg name="user" type="s"/>
+    </signal>
+    <signal name="DeviceLeft">
+      <arg name="mac" type="s"/>
+      <arg name="user" type="s"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+/// Marshals values little endian, aligned relative to the start of the header or the body, which
+/// both start 8-aligned in the message.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn align(&mut self, alignment: usize) {
+        while self.0.len() % alignment != 0 {
+            self.0.push(0);
+        }
+    }
+
+    fn byte(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.byte(value.len() as u8);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+
+    /// An array of elements aligned to `alignment`, written by `elements`. Its length excludes the
+    /// padding before the first element.
+    fn array(&mut self, alignment: usize, elements: impl FnOnce(&mut Self)) {
+        self.u32(0);
+        let at = self.0.len() - 4;
+        self.align(alignment);
+        let start = self.0.len();
+        elements(self);
+        let len = (self.0.len() - start) as u32;
+        self.0[at..at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, alignment: usize) {
+        self.pos = (self.pos + alignment - 1) / alignment * alignment;
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let value = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(value)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.data.get(self.pos..self.pos + 4)?);
+        self.pos += 4;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<String> {
+        let value = self.data.get(self.pos..self.pos + len)?;
+        // and the nul terminator
+        self.pos += len + 1;
+        String::from_utf8(value.to_vec()).ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn signature(&mut self) -> Option<String> {
+        let len = self.byte()? as usize;
+        self.bytes(len)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Message {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    destination: Option<String>,
+    sender: Option<String>,
+    signature: String,
+    body: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Message {
+    fn call(destination: &str, path: &str, interface: &str, member: &str) -> Self {
+        Message {
+            kind: METHOD_CALL,
+            destination: Some(destination.into()),
+            path: Some(path.into()),
+            interface: Some(interface.into()),
+            member: Some(member.into()),
+            ..Default::default()
+        }
+    }
+
+    fn reply(call: &Message, kind: u8, signature: &str, body: Vec<u8>) -> Self {
+        Message {
+            kind,
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            signature: signature.into(),
+            body,
+            ..Default::default()
+        }
+    }
+
+    fn with_body(mut self, signature: &str, body: Writer) -> Self {
+        self.signature = signature.into();
+        self.body = body.0;
+        self
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.byte(b'l');
+        writer.byte(self.kind);
+        writer.byte(self.flags);
+        // protocol version
+        writer.byte(1);
+        writer.u32(self.body.len() as u32);
+        writer.u32(self.serial);
+        writer.array(8, |writer| {
+            let strings = [
+                (FIELD_PATH, "o", &self.path),
+                (FIELD_INTERFACE, "s", &self.interface),
+                (FIELD_MEMBER, "s", &self.member),
+                (FIELD_ERROR_NAME, "s", &self.error_name),
+                (FIELD_DESTINATION, "s", &self.destination),
+                (FIELD_SENDER, "s", &self.sender),
+            ];
+            for (code, signature, value) in strings.iter() {
+                if let Some(value) = value {
+                    writer.align(8);
+                    writer.byte(*code);
+                    writer.signature(signature);
+                    writer.string(value);
+                }
+            }
+            if let Some(serial) = self.reply_serial {
+                writer.align(8);
+                writer.byte(FIELD_REPLY_SERIAL);
+                writer.signature("u");
+                writer.u32(serial);
+            }
+            if !self.signature.is_empty() {
+                writer.align(8);
+                writer.byte(FIELD_SIGNATURE);
+                writer.signature("g");
+                writer.signature(&self.signature);
+            }
+        });
+        writer.align(8);
+        writer.0.extend_from_slice(&self.body);
+        writer.0
+    }
+
+    fn decode(data: &[u8]) -> Option<Message> {
+        let big_endian = match data.first()? {
+            b'l' => false,
+            b'B' => true,
+            _ => return None,
+        };
+        let mut reader = Reader {
+            data,
+            pos: 1,
+            big_endian,
+        };
+        let mut message = Message {
+            kind: reader.byte()?,
+            flags: reader.byte()?,
+            big_endian,
+            ..Default::default()
+        };
+        let _version = reader.byte()?;
+        let body_len = reader.u32()? as usize;
+        message.serial = reader.u32()?;
+        let end = reader.u32()? as usize + reader.pos;
+        while reader.pos < end {
+            reader.align(8);
+            let code = reader.byte()?;
+            match (code, reader.signature()?.as_str()) {
+                (FIELD_REPLY_SERIAL, "u") => message.reply_serial = Some(reader.u32()?),
+                (FIELD_SIGNATURE, "g") => message.signature = reader.signature()?,
+                (_, "s") | (_, "o") => {
+                    let value = Some(reader.string()?);
+                    match code {
+                        FIELD_PATH => message.path = value,
+                        FIELD_INTERFACE => message.interface = value,
+                        FIELD_MEMBER => message.member = value,
+                        FIELD_ERROR_NAME => message.error_name = value,
+                        FIELD_DESTINATION => message.destination = value,
+                        FIELD_SENDER => message.sender = value,
+                        _ => (),
+                    }
+                }
+                (_, "u") => {
+                    reader.u32()?;
+                }
+                (_, "g") => {
+                    reader.signature()?;
+                }
+                _ => return None,
+            }
+        }
+        reader.align(8);
+        message.body = data.get(reader.pos..reader.pos + body_len)?.to_vec();
+        Some(message)
+    }
+
+    fn body(&self) -> Reader {
+        Reader {
+            data: &self.body,
+            pos: 0,
+            big_endian: self.big_endian,
+        }
+    }
+}
+
+/// The path of the Unix socket in a bus address, e.g. `unix:path=/run/user/1000/bus`.
+fn socket_path(address: &str) -> Option<&str> {
+    address.split(';').find_map(|address| {
+        address
+            .strip_prefix("unix:")?
+            .split(',')
+            .find_map(|part| part.strip_prefix("path="))
+    })
+}
+
+fn read_message(stream: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut fixed = [0u8; 16];
+    stream.read_exact(&mut fixed)?;
+    let u32_at = |at: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&fixed[at..at + 4]);
+        if fixed[0] == b'B' {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let header_len = (16 + u32_at(12) as usize + 7) / 8 * 8;
+    let len = header_len + u32_at(4) as usize;
+    if len > MAX_MESSAGE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("message of {} bytes is too large", len),
+        ));
+    }
+    let mut data = fixed.to_vec();
+    data.resize(len, 0);
+    stream.read_exact(&mut data[16..])?;
+    Ok(data)
+}
+
+/// A connection to the bus, which is only written to by one thread at a time.
+struct Connection {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl Connection {
+    fn open(path: &str) -> std::io::Result<Connection> {
+        let mut stream = UnixStream::connect(path)?;
+        let uid = users::get_effective_uid();
+        // hex encoded as ASCII, in which the digits are 0x30 to 0x39
+        let uid: String = uid.to_string().chars().flat_map(|c| vec!['3', c]).collect();
+        stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", uid).as_bytes())?;
+        // byte by byte, so nothing the bus sends after the line is consumed
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        if !line.starts_with(b"OK ") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "authentication rejected: {}",
+                    String::from_utf8_lossy(&line).trim()
+                ),
+            ));
+        }
+        stream.write_all(b"BEGIN\r\n")?;
+        Ok(Connection { stream, serial: 0 })
+    }
+
+    fn send(&mut self, mut message: Message) -> std::io::Result<u32> {
+        self.serial += 1;
+        message.serial = self.serial;
+        self.stream.write_all(&message.encode())?;
+        Ok(self.serial)
+    }
+
+    /// Calls a method of the bus itself and waits for the reply, before anything else is read.
+    fn call_bus(
+        &mut self,
+        member: &str,
+        signature: &str,
+        body: Writer,
+    ) -> std::io::Result<Message> {
+        let call = Message::call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            member,
+        )
+        .with_body(signature, body);
+        let serial = self.send(call)?;
+        loop {
+            let reply = match Message::decode(&read_message(&mut self.stream)?) {
+                Some(reply) if reply.reply_serial == Some(serial) => reply,
+                // e.g. the NameAcquired signal
+                _ => continue,
+            };
+            if reply.kind == ERROR {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "{} failed with {}: {}",
+                        member,
+                        reply.error_name.as_deref().unwrap_or_default(),
+                        reply.body().string().unwrap_or_default()
+                    ),
+                ));
+            }
+            return Ok(reply);
+        }
+    }
+}
+
+struct Device {
+    mac: MacAddr,
+    user: String,
+    ip: Option<Ipv4Addr>,
+    online: bool,
+}
+
+enum Input {
+    Call(Message),
+    Presence {
+        mac: MacAddr,
+        ip: Option<Ipv4Addr>,
+        status: Status,
+    },
+}
+
+fn devices_body(devices: &[Device]) -> Writer {
+    let mut body = Writer::default();
+    body.array(8, |body| {
+        for device in devices {
+            body.align(8);
+            body.string(&device.mac.to_string());
+            body.string(&device.user);
+            body.string(&device.ip.map_or(String::new(), |ip| ip.to_string()));
+            body.u32(device.online.into());
+        }
+    });
+    body
+}
+
+fn answer(call: &Message, devices: &[Device]) -> Message {
+    let method = (call.interface.as_deref(), call.member.as_deref());
+    match method {
+        (Some(INTERFACE), Some("GetDevices")) | (None, Some("GetDevices"))
+            if call.path.as_deref() == Some(PATH) =>
+        {
+            Message::reply(call, METHOD_RETURN, "a(sssb)", devices_body(devices).0)
+        }
+        (Some("org.freedesktop.DBus.Introspectable"), Some("Introspect")) => {
+            let mut body = Writer::default();
+            body.string(INTROSPECTION);
+            Message::reply(call, METHOD_RETURN, "s", body.0)
+        }
+        (Some("org.freedesktop.DBus.Peer"), Some("Ping")) => {
+            Message::reply(call, METHOD_RETURN, "", Vec::new())
+        }
+        _ => {
+            let mut body = Writer::default();
+            body.string(&format!(
+                "No method {} of interface {} at {}",
+                call.member.as_deref().unwrap_or_default(),
+                call.interface.as_deref().unwrap_or_default(),
+                call.path.as_deref().unwrap_or_default()
+            ));
+            let mut reply = Message::reply(call, ERROR, "s", body.0);
+            reply.error_name = Some("org.freedesktop.DBus.Error.UnknownMethod".into());
+            reply
+        }
+    }
+}
+
+/// Exports presence as the `org.houserat.Presence` service, whose `GetDevices` lists configured
+/// devices and which signals `DeviceArrived` and `DeviceLeft`. It talks to the bus from threads of
+/// its own, answering calls from the presence it's told about.
+#[derive(Clone)]
+pub struct Presence {
+    inputs: Sender<Input>,
+}
+
+impl Presence {
+    pub fn start(config: &config::Dbus, devices: Vec<DeviceStatus>) -> crate::Result<Presence> {
+        let address = match config.bus {
+            Bus::System => std::env::var("DBUS_SYSTEM_BUS_ADDRESS")
+                .unwrap_or_else(|_| SYSTEM_BUS_ADDRESS.into()),
+            Bus::Session => std::env::var("DBUS_SESSION_BUS_ADDRESS").unwrap_or_default(),
+        };
+        let error = |message: String| crate::error::Error::DbusError {
+            address: address.clone(),
+            message,
+        };
+        let path = socket_path(&address).ok_or_else(|| error("no unix:path= address".into()))?;
+        let mut connection = Connection::open(path).map_err(|e| error(e.to_string()))?;
+        connection
+            .call_bus("Hello", "", Writer::default())
+            .map_err(|e| error(e.to_string()))?;
+        let mut body = Writer::default();
+        body.string(NAME);
+        body.u32(NAME_FLAG_DO_NOT_QUEUE);
+        let reply = connection
+            .call_bus("RequestName", "su", body)
+            .map_err(|e| error(e.to_string()))?;
+        if reply.body().u32() != Some(NAME_PRIMARY_OWNER) {
+            return Err(error(format!("{} is already owned", NAME)));
+        }
+
+        let (inputs, inputs_r) = crossbeam_channel::unbounded();
+        let mut stream = connection
+            .stream
+            .try_clone()
+            .map_err(|e| error(e.to_string()))?;
+        let calls = inputs.clone();
+        std::thread::spawn(move || loop {
+            let data = match read_message(&mut stream) {
+                Ok(data) => data,
+                Err(e) => {
+                    println!("Failed to read from D-Bus, exiting: {}", e);
+                    return;
+                }
+            };
+            match Message::decode(&data) {
+                Some(message) if message.kind == METHOD_CALL => {
+                    if calls.send(Input::Call(message)).is_err() {
+                        return;
+                    }
+                }
+                Some(_) => (),
+                None => println!("Ignoring malformed D-Bus message"),
+            }
+        });
+        let mut devices: Vec<Device> = devices
+            .into_iter()
+            .map(|device| Device {
+                mac: device.mac,
+                user: device.user,
+                ip: device.ip,
+                online: device.online,
+            })
+            .collect();
+        std::thread::spawn(move || {
+            for input in inputs_r {
+                let message = match input {
+                    Input::Call(call) => {
+                        if call.flags & NO_REPLY_EXPECTED != 0 {
+                            continue;
+                        }
+                        answer(&call, &devices)
+                    }
+                    Input::Presence { mac, ip, status } => {
+                        let device = match devices.iter_mut().find(|device| device.mac == mac) {
+                            Some(device) => device,
+                            None => continue,
+                        };
+                        device.online = status == Status::Arrived;
+                        device.ip = ip.or(device.ip);
+                        let mut body = Writer::default();
+                        body.string(&mac.to_string());
+                        body.string(&device.user);
+                        Message {
+                            kind: SIGNAL,
+                            path: Some(PATH.into()),
+                            interface: Some(INTERFACE.into()),
+                            member: Some(match status {
+                                Status::Arrived => "DeviceArrived".into(),
+                                Status::Left => "DeviceLeft".into(),
+                            }),
+                            ..Default::default()
+                        }
+                        .with_body("ss", body)
+                    }
+                };
+                if let Err(e) = connection.send(message) {
+                    println!("Failed to write to D-Bus, exiting: {}", e);
+                    return;
+                }
+            }
+        });
+        Ok(Presence { inputs })
+    }
+
+    /// Signals an arrival or departure.
+    pub fn presence(&self, mac: MacAddr, ip: Option<Ipv4Addr>, status: Status) {
+        if let Err(e) = self.inputs.send(Input::Presence { mac, ip, status }) {
+            println!("Failed to signal presence on D-Bus: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let mut call = Message::call(NAME, PATH, INTERFACE, "GetDevices");
+        call.serial = 7;
+        call.sender = Some(":1.42".into());
+        let encoded = call.encode();
+        assert_eq!(encoded.len() % 8, 0);
+        assert_eq!(Message::decode(&encoded), Some(call));
+
+        let call = Message::decode(&encoded).unwrap();
+        let devices = vec![Device {
+            mac: MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55),
+            user: "Alice".into(),
+            ip: None,
+            online: true,
+        }];
+        let reply = answer(&call, &devices);
+        assert_eq!(
+            (reply.kind, reply.reply_serial, reply.destination.as_deref()),
+            (METHOD_RETURN, Some(7), Some(":1.42"))
+        );
+        let mut body = reply.body();
+        // the array's length, then padding to its first struct
+        assert_eq!(body.u32(), Some(reply.body.len() as u32 - 8));
+        body.align(8);
+        assert_eq!(body.string().as_deref(), Some("00:11:22:33:44:55"));
+        assert_eq!(body.string().as_deref(), Some("Alice"));
+        assert_eq!(body.string().as_deref(), Some(""));
+        assert_eq!(body.u32(), Some(1));
+
+        let mut call = call;
+        call.member = Some("Shutdown".into());
+        assert_eq!(answer(&call, &devices).kind, ERROR);
+
+        assert_eq!(
+            socket_path("unix:abstract=/tmp/x;unix:path=/run/user/1000/bus,guid=1"),
+            Some("/run/user/1000/bus")
+        );
+        assert_eq!(socket_path("tcp:host=localhost"), None);
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// What dbus-daemon 1.16 sent a client that called Hello and then RequestName for `NAME`:
+    /// the reply to Hello, the NameAcquired signals of its unique and requested names and the
+    /// reply to RequestName, as read off the socket.
+    const BUS_MESSAGES: &str = "\
+        6c02010109000000010000003d00000006017300040000003a312e3000000000\
+        0501750001000000080167000173000007017300140000006f72672e66726565\
+        6465736b746f702e4442757300000000040000003a312e30006c040101090000\
+        00020000008d00000001016f00150000002f6f72672f667265656465736b746f\
+        702f4442757300000002017300140000006f72672e667265656465736b746f70\
+        2e4442757300000000030173000c0000004e616d654163717569726564000000\
+        0006017300040000003a312e3000000000080167000173000007017300140000\
+        006f72672e667265656465736b746f702e4442757300000000040000003a312e\
+        30006c0401011a000000030000008d00000001016f00150000002f6f72672f66\
+        7265656465736b746f702f4442757300000002017300140000006f72672e6672\
+        65656465736b746f702e4442757300000000030173000c0000004e616d654163\
+        7175697265640000000006017300040000003a312e3000000000080167000173\
+        000007017300140000006f72672e667265656465736b746f702e444275730000\
+        0000150000006f72672e686f7573657261742e50726573656e6365006c020101\
+        04000000040000003d00000006017300040000003a312e300000000005017500\
+        02000000080167000175000007017300140000006f72672e667265656465736b\
+        746f702e444275730000000001000000";
+
+    /// `dbus-send --print-reply --dest=org.houserat.Presence /org/houserat/Presence
+    /// org.houserat.Presence.GetDevices`, as dbus-daemon delivered it.
+    const DBUS_SEND_GET_DEVICES: &str = "\
+        6c01000100000000020000008500000001016f00160000002f6f72672f686f75\
+        73657261742f50726573656e6365000002017300150000006f72672e686f7573\
+        657261742e50726573656e6365000000030173000a0000004765744465766963\
+        657300000000000006017300150000006f72672e686f7573657261742e507265\
+        73656e636500000007017300040000003a312e3100000000";
+
+    /// `busctl call org.houserat.Presence /org/houserat/Presence
+    /// org.freedesktop.DBus.Introspectable Introspect` from systemd's sd-bus, which orders header
+    /// fields differently and allows interactive authorization.
+    const BUSCTL_INTROSPECT: &str = "\
+        6c01040100000000020000009500000001016f00160000002f6f72672f686f75\
+        73657261742f50726573656e63650000030173000a000000496e74726f737065\
+        637400000000000002017300230000006f72672e667265656465736b746f702e\
+        444275732e496e74726f737065637461626c6500000000000601730015000000\
+        6f72672e686f7573657261742e50726573656e63650000000701730004000000\
+        3a312e3200000000";
+
+    #[test]
+    fn test_bus_messages() {
+        let data = unhex(BUS_MESSAGES);
+        let mut stream = &data[..];
+        let mut messages = Vec::new();
+        while !stream.is_empty() {
+            messages.push(Message::decode(&read_message(&mut stream).unwrap()).unwrap());
+        }
+        assert_eq!(messages.len(), 4);
+
+        let hello = &messages[0];
+        assert_eq!(
+            (hello.kind, hello.reply_serial, hello.sender.as_deref()),
+            (METHOD_RETURN, Some(1), Some("org.freedesktop.DBus"))
+        );
+        assert_eq!(hello.body().string().as_deref(), Some(":1.0"));
+
+        let acquired = &messages[2];
+        assert_eq!(
+            (acquired.kind, acquired.member.as_deref()),
+            (SIGNAL, Some("NameAcquired"))
+        );
+        assert_eq!(acquired.body().string().as_deref(), Some(NAME));
+
+        let requested = &messages[3];
+        assert_eq!(
+            (requested.reply_serial, requested.signature.as_str()),
+            (Some(2), "u")
+        );
+        assert_eq!(requested.body().u32(), Some(NAME_PRIMARY_OWNER));
+    }
+
+    #[test]
+    fn test_captured_calls() {
+        let data = unhex(DBUS_SEND_GET_DEVICES);
+        let call = Message::decode(&data).unwrap();
+        assert_eq!(
+            (call.kind, call.flags, call.serial, call.sender.as_deref()),
+            (METHOD_CALL, 0, 2, Some(":1.1"))
+        );
+        // fields in the same order as dbus-send, so encoding it again is byte for byte the same
+        assert_eq!(call.encode(), data);
+        let reply = answer(&call, &[]);
+        assert_eq!(
+            (reply.kind, reply.reply_serial, reply.destination.as_deref()),
+            (METHOD_RETURN, Some(2), Some(":1.1"))
+        );
+        assert_eq!(reply.body().u32(), Some(0));
+
+        let call = Message::decode(&unhex(BUSCTL_INTROSPECT)).unwrap();
+        assert_eq!(
+            (call.path.as_deref(), call.interface.as_deref()),
+            (Some(PATH), Some("org.freedesktop.DBus.Introspectable"))
+        );
+        assert_eq!(call.flags & NO_REPLY_EXPECTED, 0);
+        let reply = answer(&call, &[]);
+        assert_eq!(
+            (reply.kind, reply.destination.as_deref()),
+            (METHOD_RETURN, Some(":1.2"))
+        );
+        assert_eq!(reply.body().string().as_deref(), Some(INTROSPECTION));
+    }
+}
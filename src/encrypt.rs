@@ -0,0 +1,80 @@
+use crate::config::{Encryption, EncryptionKind};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The program and its arguments encrypting stdin to ASCII armor on stdout.
+fn command(encryption: &Encryption) -> (&'static str, Vec<&str>) {
+    match encryption.kind {
+        EncryptionKind::Age => ("age", vec!["--armor", "--recipient", &encryption.recipient]),
+        EncryptionKind::Pgp => (
+            "gpg",
+            vec![
+                "--batch",
+                "--armor",
+                "--trust-model",
+                "always",
+                "--recipient-file",
+                &encryption.recipient,
+                "--encrypt",
+            ],
+        ),
+    }
+}
+
+/// Encrypts data to a recipient with the `age` or `gpg` command, returning it ASCII armored. These
+/// are used rather than libraries, so keys and formats are exactly those of the familiar tools.
+pub fn encrypt(encryption: &Encryption, data: &[u8]) -> crate::Result<Vec<u8>> {
+    let (program, args) = command(encryption);
+    let error = |message: String| crate::error::Error::EncryptError {
+        program: program.to_string(),
+        message,
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| error(e.to_string()))?;
+    // written from a thread of its own, in case the output fills the pipe before the input is read
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let data = data.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&data));
+    let output = child.wait_with_output().map_err(|e| error(e.to_string()))?;
+    writer
+        .join()
+        .expect("Failed to join writer")
+        .map_err(|e| error(e.to_string()))?;
+    if !output.status.success() {
+        return Err(error(format!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let encryption = Encryption {
+            kind: EncryptionKind::Age,
+            recipient: "age1abc".into(),
+        };
+        assert_eq!(
+            command(&encryption),
+            ("age", vec!["--armor", "--recipient", "age1abc"])
+        );
+        let encryption = Encryption {
+            kind: EncryptionKind::Pgp,
+            recipient: "/etc/houserat/key.asc".into(),
+        };
+        let (program, args) = command(&encryption);
+        assert_eq!(program, "gpg");
+        assert!(args.ends_with(&["--recipient-file", "/etc/houserat/key.asc", "--encrypt"]));
+    }
+}
@@ -11,8 +11,18 @@ pub enum Error {
     BadInterface { interface: String },
     #[snafu(display("Unknown user {}", user))]
     UnknownUser { user: String },
+    #[snafu(display("{} is archived in config file", user))]
+    ArchivedInConfig { user: String },
     #[snafu(display("Missing chat_id for '{}'", user))]
     MissingChatId { user: String },
+    #[snafu(display("Subscriber '{}' has no chat_id, matrix, email or phone", user))]
+    NoIdentity { user: String },
+    #[snafu(display("User '{}' has an invalid {} '{}'", user, provider, value))]
+    InvalidIdentity {
+        user: String,
+        provider: String,
+        value: String,
+    },
     #[snafu(display("User '{}' has same device {} as '{}'", user, device, orig_user))]
     DuplicateDevice {
         device: MacAddr,
@@ -23,8 +33,32 @@ pub enum Error {
     NoDevices { user: String },
     #[snafu(display("User '{}' has devices but no subscriber", user))]
     NoSubscriber { user: String },
+    #[snafu(display(
+        "User '{}' has a beacon with an invalid IRK, expected 32 hex digits",
+        user
+    ))]
+    InvalidIrk { user: String },
+    #[snafu(display("Missing bot token, set one of bot_token, bot_token_env or bot_token_file"))]
+    MissingBotToken,
+    #[snafu(display("Only one of bot_token, bot_token_env or bot_token_file may be set"))]
+    ConflictingBotToken,
+    #[snafu(display("Environment variable {} from bot_token_env is not set", var))]
+    BotTokenEnv { var: String },
+    #[snafu(display("Failed to read bot_token_file '{}': {}", path.display(), source))]
+    BotTokenFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Bot token from {} is empty", from))]
+    EmptyBotToken { from: String },
     #[snafu(display("Duration {:?} is out of range", value))]
     InvalidDuration { value: std::time::Duration },
+    #[snafu(display(
+        "Capture snaplen {} is too small, at least {} bytes are needed",
+        snaplen,
+        crate::network::MIN_SNAPLEN
+    ))]
+    InvalidSnaplen { snaplen: i32 },
     #[snafu(display("Config file '{}' not found: {}", path.display(), source))]
     ConfigNotFound {
         path: PathBuf,
@@ -32,16 +66,148 @@ pub enum Error {
     },
     #[snafu(display("Invalid config: {}", source))]
     ConfigError { source: toml::de::Error },
+    #[snafu(display("Failed to read state file '{}': {}", path.display(), source))]
+    StateReadError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to write state file '{}': {}", path.display(), source))]
+    StateWriteError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Invalid state file '{}': {}", path.display(), source))]
+    InvalidState {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[snafu(display("Failed to access history file '{}': {}", path.display(), source))]
+    HistoryError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Invalid history file '{}': {}", path.display(), source))]
+    InvalidHistory {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[snafu(display("Failed to write config file '{}': {}", path.display(), source))]
+    ConfigWriteError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to access backup file '{}': {}", path.display(), source))]
+    BackupError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Invalid backup file '{}': {}", path.display(), reason))]
+    InvalidBackup { path: PathBuf, reason: String },
+    #[snafu(display("Refusing to restore, {} (use --force to override)", reason))]
+    RestoreConflict { reason: String },
+    #[snafu(display("Storage backend {} is not supported by this build", backend))]
+    UnsupportedStorage { backend: crate::config::Backend },
+    #[snafu(display("Storage error ({}): {}", backend, message))]
+    StorageError {
+        backend: crate::config::Backend,
+        message: String,
+    },
+    #[snafu(display("Invalid webhook URL '{}': {}", url, source))]
+    InvalidWebhookUrl {
+        url: String,
+        source: reqwest::UrlError,
+    },
+    #[snafu(display("Invalid header '{}' for webhook {}", name, url))]
+    InvalidWebhookHeader { name: String, url: String },
+    #[snafu(display("Failed to encrypt with {}: {}", program, message))]
+    EncryptError { program: String, message: String },
+    #[snafu(display("Failed to access webhook outbox '{}': {}", path.display(), source))]
+    OutboxError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to access leader lease '{}': {}", path.display(), source))]
+    LeaseError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to poll {}: {}", url, message))]
+    PollerError { url: String, message: String },
+    #[snafu(display("Failed to read lease file '{}': {}", path.display(), source))]
+    LeaseFileError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Bluetooth LE error: {}", source))]
+    BleError { source: std::io::Error },
+    #[snafu(display(
+        "Beacons are configured but this build lacks the ble feature, which needs Linux"
+    ))]
+    UnsupportedBle,
+    #[snafu(display("Failed to start API server on {}: {}", address, message))]
+    ApiError { address: String, message: String },
+    #[snafu(display("Failed to connect to D-Bus at '{}': {}", address, message))]
+    DbusError { address: String, message: String },
+    #[snafu(display("D-Bus is configured but this build lacks the dbus feature"))]
+    UnsupportedDbus,
+    #[snafu(display("Failed to listen for signals: {}", source))]
+    SignalError { source: std::io::Error },
+    #[snafu(display("Failed to output events to {}: {}", target, source))]
+    OutputError {
+        target: String,
+        source: std::io::Error,
+    },
     #[snafu(display("PCAP error: {}", source))]
     PcapError { source: pcap::Error },
+    #[snafu(display("Not transmitting in passive mode"))]
+    Passive,
+    #[snafu(display("Capture failed {} times in a row", failures))]
+    CaptureFailed { failures: u32 },
     #[snafu(display("PCAP thread exited: {}", source))]
     RecvError {
         source: crossbeam_channel::RecvError,
     },
-    #[snafu(display("Failed to send ARP packet: {}", source))]
+    #[snafu(display("Failed to send packet: {}", source))]
     SendError { source: std::io::Error },
     #[snafu(display("Failed communicating with Telegram: {}", source))]
     TelegramError { source: reqwest::Error },
+    #[snafu(display("Telegram rejected the bot token"))]
+    InvalidBotToken,
+    #[snafu(display("Telegram chat {} not found", chat_id))]
+    ChatNotFound { chat_id: i64 },
+    #[snafu(display("Chat {} blocked the bot", chat_id))]
+    BotBlockedByUser { chat_id: i64 },
+    #[snafu(display("Telegram refused request: {}", description))]
+    TelegramRefused { description: String },
+    #[snafu(display("Webhook {} timed out", url))]
+    WebhookTimeout { url: String },
+}
+
+impl Error {
+    /// What the user can do about the error, for errors caused by configuration or by people.
+    pub fn remediation(&self) -> Option<&'static str> {
+        match self {
+            Error::InvalidBotToken => Some("check bot_token against the token @BotFather gave you"),
+            Error::ChatNotFound { .. } => {
+                Some("check the chat_id and make sure the user started a conversation with the bot")
+            }
+            Error::BotBlockedByUser { .. } => {
+                Some("ask the user to unblock the bot, or remove their chat_id")
+            }
+            Error::WebhookTimeout { .. } => {
+                Some("check that the webhook URL is reachable and responds within 30 seconds")
+            }
+            _ => None,
+        }
+    }
+
+    /// The error followed by its remediation, if any, for logs.
+    pub fn report(&self) -> String {
+        match self.remediation() {
+            Some(remediation) => format!("{} ({})", self, remediation),
+            None => self.to_string(),
+        }
+    }
 }
 
 impl From<pcap::Error> for Error {
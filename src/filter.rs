@@ -0,0 +1,159 @@
+use crate::network::{self, Event};
+use pnet::util::MacAddr;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::net::Ipv4Addr;
+
+/// A MAC address prefix such as an OUI, e.g. `00:11:22`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacPrefix(Vec<u8>);
+
+/// An IPv4 network in CIDR notation, e.g. `192.168.50.0/24`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    address: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl std::str::FromStr for MacPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = s
+            .split(|c| c == ':' || c == '-')
+            .map(|b| u8::from_str_radix(b, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("invalid MAC prefix '{}'", s))?;
+        if bytes.is_empty() || bytes.len() > 6 {
+            return Err(format!("invalid MAC prefix '{}'", s));
+        }
+        Ok(MacPrefix(bytes))
+    }
+}
+
+impl MacPrefix {
+    pub fn matches(&self, mac: MacAddr) -> bool {
+        let MacAddr(a, b, c, d, e, f) = mac;
+        [a, b, c, d, e, f].starts_with(&self.0)
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network '{}', expected e.g. 10.0.0.0/8", s);
+        let mut parts = s.splitn(2, '/');
+        let address = parts
+            .next()
+            .and_then(|a| a.parse().ok())
+            .ok_or_else(invalid)?;
+        let prefix_len = match parts.next() {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= 32)
+                .ok_or_else(invalid)?,
+            None => 32,
+        };
+        Ok(Network {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl Network {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len))
+            .unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.address) & mask
+    }
+}
+
+fn deserialize_parsed<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+    T: std::str::FromStr<Err = String>,
+{
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Traffic to ignore before it reaches the tracker, e.g. a guest network or lab subnet visible on
+/// the same capture, so it doesn't show up in the inventory or trigger notifications.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct Filter {
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    #[schemars(with = "Vec<String>")]
+    pub macs: Vec<MacPrefix>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    #[schemars(with = "Vec<String>")]
+    pub networks: Vec<Network>,
+    #[serde(default)]
+    pub vlans: BTreeSet<u16>,
+}
+
+impl Filter {
+    pub fn ignores(&self, event: &Event) -> bool {
+        let (mac, ip) = match event {
+            Event::Connected { mac, ip, .. } => (*mac, *ip),
+            Event::Alive { mac, ip } | Event::Announced { mac, ip } => (*mac, Some(*ip)),
+            Event::Beacon { mac } | Event::Described { mac, .. } | Event::RoundTrip { mac, .. } => {
+                (*mac, None)
+            }
+            Event::Ignored => return false,
+        };
+        self.macs.iter().any(|prefix| prefix.matches(mac))
+            || ip.map_or(false, |ip| {
+                self.networks.iter().any(|network| network.contains(ip))
+            })
+    }
+
+    pub fn ignores_vlan(&self, vlan: u16) -> bool {
+        self.vlans.contains(&vlan)
+    }
+
+    /// Whether a captured frame is tagged with an ignored VLAN.
+    pub fn ignores_frame(&self, data: &[u8]) -> bool {
+        !self.vlans.is_empty()
+            && network::vlan_id(data).map_or(false, |vlan| self.ignores_vlan(vlan))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_ignores() {
+        let filter = Filter {
+            macs: vec!["00:11:22".parse().unwrap()],
+            networks: vec!["192.168.50.0/24".parse().unwrap()],
+            vlans: BTreeSet::new(),
+        };
+        let alive = |mac, ip| Event::Alive { mac, ip };
+
+        assert!(filter.ignores(&alive(
+            MacAddr(0, 0x11, 0x22, 1, 2, 3),
+            Ipv4Addr::new(192, 168, 1, 10)
+        )));
+        assert!(filter.ignores(&alive(
+            MacAddr(0, 0x11, 0x23, 1, 2, 3),
+            Ipv4Addr::new(192, 168, 50, 10)
+        )));
+        assert!(!filter.ignores(&alive(
+            MacAddr(0, 0x11, 0x23, 1, 2, 3),
+            Ipv4Addr::new(192, 168, 51, 10)
+        )));
+        assert!(Network::from_str("0.0.0.0/0")
+            .unwrap()
+            .contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(MacPrefix::from_str("00:11:22:33:44:55:66").is_err());
+    }
+}
@@ -0,0 +1,25 @@
+use chrono::{DateTime, Local};
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Entry {
+    pub timestamp: DateTime<Local>,
+    pub mac: MacAddr,
+    pub name: String,
+    pub status: crate::Status,
+    /// How long the device was home, on departures
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "humantime_serde"
+    )]
+    pub stay: Option<std::time::Duration>,
+    /// Round-trip times of the device's keepalives leading up to a departure, to tell poor Wi-Fi
+    /// from actually leaving
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt: Option<crate::rtt::Summary>,
+    /// The notification this entry was recorded for, to find it in the logs and backends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
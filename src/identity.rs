@@ -0,0 +1,137 @@
+use serde::Serialize;
+
+/// Where a subscriber is reached, by each backend they've enabled. Notifiers pick the address they
+/// deliver to, skipping subscribers without one, so a single `subscriber = "Alice"` reaches Alice
+/// on every backend configured for that user.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Identity {
+    /// Telegram chat ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub telegram: Option<i64>,
+    /// Matrix user ID, e.g. `@alice:example.org`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Phone number in E.164 format, e.g. `+15551234567`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+}
+
+fn valid_matrix_id(id: &str) -> bool {
+    let mut parts = id.splitn(2, ':');
+    match (parts.next().and_then(|l| l.strip_prefix('@')), parts.next()) {
+        (Some(localpart), Some(server)) => {
+            !localpart.is_empty() && !server.is_empty() && !id.contains(char::is_whitespace)
+        }
+        _ => false,
+    }
+}
+
+fn valid_email(email: &str) -> bool {
+    let mut parts = email.splitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|label| !label.is_empty())
+                && !email.contains(char::is_whitespace)
+        }
+        _ => false,
+    }
+}
+
+fn valid_phone(phone: &str) -> bool {
+    match phone.strip_prefix('+') {
+        Some(digits) => {
+            (7..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+impl Identity {
+    pub fn is_empty(&self) -> bool {
+        self.providers().is_empty()
+    }
+
+    /// Names of the backends this identity can be reached by.
+    pub fn providers(&self) -> Vec<&'static str> {
+        let mut providers = Vec::new();
+        if self.telegram.is_some() {
+            providers.push("telegram");
+        }
+        if self.matrix.is_some() {
+            providers.push("matrix");
+        }
+        if self.email.is_some() {
+            providers.push("email");
+        }
+        if self.phone.is_some() {
+            providers.push("phone");
+        }
+        providers
+    }
+
+    /// Checks that every address is well formed, so typos are caught on load rather than when
+    /// someone arrives.
+    pub fn validate(&self, user: &str) -> crate::Result<()> {
+        let invalid = |provider: &str, value: &str| crate::error::Error::InvalidIdentity {
+            user: user.into(),
+            provider: provider.into(),
+            value: value.into(),
+        };
+        match (&self.matrix, &self.email, &self.phone) {
+            (Some(matrix), _, _) if !valid_matrix_id(matrix) => Err(invalid("matrix", matrix)),
+            (_, Some(email), _) if !valid_email(email) => Err(invalid("email", email)),
+            (_, _, Some(phone)) if !valid_phone(phone) => Err(invalid("phone", phone)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut addresses = Vec::new();
+        if let Some(chat_id) = self.telegram {
+            addresses.push(format!("chat {}", chat_id));
+        }
+        addresses.extend(self.matrix.clone());
+        addresses.extend(self.email.clone());
+        addresses.extend(self.phone.clone());
+        if addresses.is_empty() {
+            write!(f, "nowhere")
+        } else {
+            write!(f, "{}", addresses.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let identity = Identity {
+            telegram: Some(42),
+            matrix: Some("@alice:example.org".into()),
+            email: Some("alice@example.org".into()),
+            phone: Some("+15551234567".into()),
+        };
+        assert!(identity.validate("Alice").is_ok());
+        assert_eq!(
+            identity.providers(),
+            vec!["telegram", "matrix", "email", "phone"]
+        );
+        assert!(Identity::default().is_empty());
+
+        assert!(!valid_matrix_id("alice:example.org"));
+        assert!(!valid_matrix_id("@alice"));
+        assert!(!valid_email("alice@localhost"));
+        assert!(!valid_email("alice@@example.org"));
+        assert!(!valid_phone("5551234567"));
+        assert!(!valid_phone("+1 555 123 4567"));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::network::Event;
+use chrono::{DateTime, Local};
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+/// What is known about a device seen on the network, whether it belongs to a user or not.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sighting {
+    pub ip: Option<Ipv4Addr>,
+    pub hostname: Option<String>,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>,
+    /// A friendly description of the device, e.g. from its mDNS services
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Inventory(BTreeMap<MacAddr, Sighting>);
+
+impl Inventory {
+    pub fn record(&mut self, event: &Event, now: DateTime<Local>) {
+        if let Event::Described { mac, description } = event {
+            if let Some(sighting) = self.0.get_mut(mac) {
+                sighting.description = Some(description.clone());
+            }
+            return;
+        }
+        let (mac, ip, hostname) = match event {
+            Event::Connected { mac, ip, hostname } => (*mac, *ip, hostname.as_ref()),
+            Event::Alive { mac, ip } | Event::Announced { mac, ip } => (*mac, Some(*ip), None),
+            Event::Beacon { mac } => (*mac, None, None),
+            Event::Ignored | Event::Described { .. } | Event::RoundTrip { .. } => return,
+        };
+        let sighting = self.0.entry(mac).or_insert_with(|| Sighting {
+            ip: None,
+            hostname: None,
+            first_seen: now,
+            last_seen: now,
+            description: None,
+        });
+        sighting.last_seen = now;
+        if ip.is_some() {
+            sighting.ip = ip;
+        }
+        if hostname.is_some() {
+            sighting.hostname = hostname.cloned();
+        }
+    }
+
+    /// Adds newer sightings from another inventory, e.g. one recorded by a previous run.
+    pub fn merge(&mut self, other: Inventory) {
+        for (mac, sighting) in other.0 {
+            match self.0.get_mut(&mac) {
+                Some(existing) if existing.last_seen >= sighting.last_seen => {
+                    existing.first_seen = existing.first_seen.min(sighting.first_seen);
+                    if existing.description.is_none() {
+                        existing.description = sighting.description;
+                    }
+                }
+                Some(existing) => {
+                    let first_seen = existing.first_seen.min(sighting.first_seen);
+                    let description = existing.description.take();
+                    *existing = sighting;
+                    existing.first_seen = first_seen;
+                    existing.description = existing.description.take().or(description);
+                }
+                None => {
+                    self.0.insert(mac, sighting);
+                }
+            }
+        }
+    }
+
+    pub fn description(&self, mac: &MacAddr) -> Option<&str> {
+        self.0.get(mac)?.description.as_deref()
+    }
+
+    pub fn get(&self, mac: &MacAddr) -> Option<&Sighting> {
+        self.0.get(mac)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MacAddr, &Sighting)> {
+        self.0.iter()
+    }
+}
+
+/// Vendor names by OUI, the first 3 bytes of a MAC address.
+#[derive(Debug, Default)]
+pub struct Vendors(HashMap<[u8; 3], String>);
+
+/// Where distributions usually install the IEEE OUI registry or Wireshark's copy of it.
+pub const VENDOR_FILES: &[&str] = &[
+    "/usr/share/ieee-data/oui.txt",
+    "/usr/share/misc/oui.txt",
+    "/usr/share/wireshark/manuf",
+];
+
+impl Vendors {
+    /// Parses either the IEEE `oui.txt` (`00-11-22   (hex)\t\tVendor`) or Wireshark's `manuf`
+    /// (`00:11:22\tShort\tVendor`).
+    pub fn parse(content: &str) -> Vendors {
+        let mut vendors = HashMap::new();
+        for line in content.lines() {
+            let (prefix, vendor) = if let Some(index) = line.find("(hex)") {
+                (&line[..index], line[index + 5..].trim())
+            } else if !line.starts_with('#') {
+                let mut fields = line.split('\t');
+                let prefix = fields.next().unwrap_or("");
+                let short = fields.next().unwrap_or("");
+                (prefix, fields.next().unwrap_or(short).trim())
+            } else {
+                continue;
+            };
+            let bytes: Vec<u8> = prefix
+                .trim()
+                .split(|c| c == '-' || c == ':')
+                .filter_map(|b| u8::from_str_radix(b, 16).ok())
+                .collect();
+            if bytes.len() == 3 && !vendor.is_empty() {
+                vendors.insert([bytes[0], bytes[1], bytes[2]], vendor.to_string());
+            }
+        }
+        Vendors(vendors)
+    }
+
+    pub fn load(path: Option<&Path>) -> Vendors {
+        let paths: Vec<&Path> = match path {
+            Some(path) => vec![path],
+            None => VENDOR_FILES.iter().map(Path::new).collect(),
+        };
+        for path in paths {
+            if let Ok(content) = std::fs::read_to_string(path) {
+                return Vendors::parse(&content);
+            }
+        }
+        Vendors::default()
+    }
+
+    pub fn lookup(&self, mac: MacAddr) -> Option<&str> {
+        if mac.0 & 0x02 != 0 {
+            return Some("(random)");
+        }
+        self.0.get(&[mac.0, mac.1, mac.2]).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Dot,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(Format::Dot),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format '{}', expected dot or json", s)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Node<'a> {
+    mac: MacAddr,
+    ip: Option<Ipv4Addr>,
+    hostname: Option<&'a str>,
+    vendor: Option<&'a str>,
+    description: Option<&'a str>,
+    user: Option<&'a str>,
+    first_seen: DateTime<Local>,
+    last_seen: DateTime<Local>,
+}
+
+/// Renders the inventory as a star around the interface houserat listens on, labelling devices
+/// with the user they belong to if any.
+pub fn map(
+    inventory: &Inventory,
+    config: &crate::config::Config,
+    vendors: &Vendors,
+    format: Format,
+) -> String {
+    let nodes: Vec<Node> = inventory
+        .iter()
+        .map(|(mac, sighting)| Node {
+            mac: *mac,
+            ip: sighting.ip,
+            hostname: sighting.hostname.as_deref(),
+            vendor: vendors.lookup(*mac),
+            description: sighting.description.as_deref(),
+            user: config.rules.get(mac).map(|metadata| metadata.name.as_str()),
+            first_seen: sighting.first_seen,
+            last_seen: sighting.last_seen,
+        })
+        .collect();
+
+    match format {
+        Format::Json => serde_json::to_string_pretty(&nodes).expect("Failed to serialize map"),
+        Format::Dot => {
+            let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+            let mut dot = String::from("graph houserat {\n");
+            dot += &format!(
+                "  \"{}\" [shape=box, label=\"{}\\n{}\"];\n",
+                config.interface.addresses.mac,
+                escape(&config.interface.name),
+                config.interface.addresses.ip
+            );
+            for node in &nodes {
+                let mut label = vec![node
+                    .user
+                    .or(node.hostname)
+                    .map_or_else(|| node.mac.to_string(), escape)];
+                if let Some(ip) = node.ip {
+                    label.push(ip.to_string());
+                }
+                if let Some(vendor) = node.vendor {
+                    label.push(escape(vendor));
+                }
+                label.push(format!("last seen {}", node.last_seen.format("%F %R")));
+                dot += &format!(
+                    "  \"{}\" [label=\"{}\"{}];\n  \"{}\" -- \"{}\";\n",
+                    node.mac,
+                    label.join("\\n"),
+                    if node.user.is_some() {
+                        ", style=bold"
+                    } else {
+                        ""
+                    },
+                    config.interface.addresses.mac,
+                    node.mac
+                );
+            }
+            dot += "}\n";
+            dot
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vendors() {
+        let vendors = Vendors::parse(
+            "00-11-22   (hex)\t\tExample Corp\n\
+             001122     (base 16)\t\tExample Corp\n\
+             # comment\n\
+             A8:BB:CC\tShort\tAnother Vendor Inc\n\
+             DC:EE:F0\tOnly\n",
+        );
+        assert_eq!(
+            vendors.lookup(MacAddr(0, 0x11, 0x22, 1, 2, 3)),
+            Some("Example Corp")
+        );
+        assert_eq!(
+            vendors.lookup(MacAddr(0xa8, 0xbb, 0xcc, 1, 2, 3)),
+            Some("Another Vendor Inc")
+        );
+        assert_eq!(
+            vendors.lookup(MacAddr(0xdc, 0xee, 0xf0, 1, 2, 3)),
+            Some("Only")
+        );
+        assert_eq!(
+            vendors.lookup(MacAddr(0xaa, 0xbb, 0xcc, 1, 2, 3)),
+            Some("(random)")
+        );
+        assert_eq!(vendors.lookup(MacAddr(0, 0, 0, 1, 2, 3)), None);
+    }
+}
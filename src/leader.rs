@@ -0,0 +1,179 @@
+use crate::notifier::{Notification, Notifier};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Holder {
+    id: String,
+    expires: DateTime<Local>,
+}
+
+/// A lease in shared storage, held by at most one of several replicas tracking the same network.
+pub struct Lease {
+    id: String,
+    path: PathBuf,
+    lock_path: PathBuf,
+    snapshot_path: PathBuf,
+    duration: chrono::Duration,
+    leader: AtomicBool,
+}
+
+fn lease_error(path: &Path) -> crate::error::LeaseError<PathBuf> {
+    crate::error::LeaseError {
+        path: path.to_path_buf(),
+    }
+}
+
+impl Lease {
+    pub fn new(config: &crate::config::Leader, storage_path: &Path) -> crate::Result<Lease> {
+        Ok(Lease {
+            id: config.id.clone(),
+            path: storage_path.join("leader.json"),
+            lock_path: storage_path.join("leader.lock"),
+            snapshot_path: storage_path.join("presence.json"),
+            duration: chrono::Duration::from_std(config.lease).map_err(|_e| {
+                crate::error::Error::InvalidDuration {
+                    value: config.lease,
+                }
+            })?,
+            leader: AtomicBool::new(false),
+        })
+    }
+
+    /// How often to renew the lease so it doesn't expire while held.
+    pub fn renew_interval(&self) -> std::time::Duration {
+        (self.duration / 3).to_std().unwrap()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::SeqCst)
+    }
+
+    /// Publishes the leader's presence state for standby replicas.
+    pub fn publish(&self, snapshot: &crate::tracker::Snapshot) -> crate::Result<()> {
+        let content = serde_json::to_string(snapshot).expect("Failed to serialize snapshot");
+        let tmp_path = self.snapshot_path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)
+            .and_then(|()| std::fs::rename(&tmp_path, &self.snapshot_path))
+            .with_context(|| lease_error(&self.snapshot_path))
+    }
+
+    /// Reads the presence state last published by the leader, if any.
+    pub fn fetch(&self) -> crate::Result<Option<crate::tracker::Snapshot>> {
+        match std::fs::read_to_string(&self.snapshot_path) {
+            Ok(content) => Ok(serde_json::from_str(&content).ok()),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| lease_error(&self.snapshot_path)),
+        }
+    }
+
+    /// Takes the lease if it is free or expired, or renews it if already held, returning whether
+    /// this replica is the leader.
+    pub fn acquire(&self, now: DateTime<Local>) -> crate::Result<bool> {
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&self.lock_path)
+            .with_context(|| lease_error(&self.lock_path))?;
+        // serializes replicas between reading the holder and replacing it, released on close
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| lease_error(&self.lock_path));
+        }
+
+        let holder: Option<Holder> = match std::fs::read_to_string(&self.path) {
+            Ok(content) => serde_json::from_str(&content).ok(),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| lease_error(&self.path)),
+        };
+        let leader = match holder {
+            Some(ref holder) if holder.id != self.id && holder.expires > now => false,
+            _ => {
+                let holder = Holder {
+                    id: self.id.clone(),
+                    expires: now + self.duration,
+                };
+                let content = serde_json::to_string(&holder).expect("Failed to serialize lease");
+                let tmp_path = self.path.with_extension("tmp");
+                std::fs::write(&tmp_path, content)
+                    .and_then(|()| std::fs::rename(&tmp_path, &self.path))
+                    .with_context(|| lease_error(&self.path))?;
+                true
+            }
+        };
+
+        if self.leader.swap(leader, Ordering::SeqCst) != leader {
+            if leader {
+                println!("Acquired leader lease as {}", self.id);
+            } else {
+                println!(
+                    "Leader lease held by {}, not sending notifications",
+                    holder.map_or_else(String::new, |h| h.id)
+                );
+            }
+        }
+        Ok(leader)
+    }
+}
+
+/// Only passes notifications on while holding the lease, so replicas don't send duplicates.
+pub struct LeaderOnly {
+    lease: Arc<Lease>,
+    notifier: Box<dyn Notifier>,
+}
+
+impl LeaderOnly {
+    pub fn new(lease: Arc<Lease>, notifier: Box<dyn Notifier>) -> Self {
+        Self { lease, notifier }
+    }
+}
+
+impl Notifier for LeaderOnly {
+    fn notify(&self, notification: &Notification) -> crate::Result<()> {
+        if self.lease.acquire(notification.timestamp)? {
+            self.notifier.notify(notification)
+        } else {
+            println!(
+                "Not the leader, skipping notification to {}",
+                notification.subscriber_name
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(dir: &Path, id: &str) -> Lease {
+        let config = crate::config::Leader {
+            id: id.to_string(),
+            lease: std::time::Duration::from_secs(30),
+        };
+        Lease::new(&config, dir).unwrap()
+    }
+
+    #[test]
+    fn test_single_leader() {
+        let dir = std::env::temp_dir().join(format!("houserat-leader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let a = lease(&dir, "a");
+        let b = lease(&dir, "b");
+        let now = Local::now();
+
+        assert!(a.acquire(now).unwrap());
+        assert!(!b.acquire(now).unwrap());
+        assert!(a.acquire(now + chrono::Duration::seconds(20)).unwrap());
+        assert!(!b.acquire(now + chrono::Duration::seconds(40)).unwrap());
+        assert!(b.acquire(now + chrono::Duration::seconds(60)).unwrap());
+        assert!(!a.acquire(now + chrono::Duration::seconds(60)).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
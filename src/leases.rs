@@ -0,0 +1,352 @@
+use crate::config::{self, LeaseFormat};
+use crate::network::Event;
+use chrono::{DateTime, Local, TimeZone};
+use crossbeam_channel::Sender;
+use pnet::util::MacAddr;
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub mac: MacAddr,
+    pub ip: Ipv4Addr,
+    pub hostname: Option<String>,
+    /// `None` for infinite leases
+    pub expires: Option<DateTime<Local>>,
+}
+
+impl Lease {
+    fn is_active(&self, now: DateTime<Local>) -> bool {
+        self.expires.map_or(true, |expires| expires > now)
+    }
+}
+
+fn timestamp(value: &str) -> Option<DateTime<Local>> {
+    Local.timestamp_opt(value.parse().ok()?, 0).single()
+}
+
+/// Parses dnsmasq's `<expiry> <mac> <ip> <hostname> <client-id>` lines, `*` meaning no hostname.
+fn parse_dnsmasq(content: &str) -> Vec<Lease> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let expires = fields.next()?;
+            let mac = fields.next()?.parse().ok()?;
+            let ip = fields.next()?.parse().ok()?;
+            let hostname = fields.next().filter(|h| *h != "*").map(String::from);
+            Some(Lease {
+                mac,
+                ip,
+                hostname,
+                expires: if expires == "0" {
+                    None
+                } else {
+                    Some(timestamp(expires)?)
+                },
+            })
+        })
+        .collect()
+}
+
+/// Parses a Kea memfile CSV, where later lines for the same address replace earlier ones and
+/// released or reclaimed leases are dropped.
+fn parse_kea(content: &str) -> Vec<Lease> {
+    let mut lines = content.lines();
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header.split(',').collect(),
+        None => return Vec::new(),
+    };
+    let column = |name| header.iter().position(|c| *c == name);
+    let (address, hwaddr, lifetime, expire, hostname, state) = match (
+        column("address"),
+        column("hwaddr"),
+        column("valid_lifetime"),
+        column("expire"),
+        column("hostname"),
+        column("state"),
+    ) {
+        (Some(a), Some(h), Some(l), Some(e), Some(n), Some(s)) => (a, h, l, e, n, s),
+        _ => return Vec::new(),
+    };
+
+    let mut leases: HashMap<Ipv4Addr, Lease> = HashMap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or("");
+        let ip: Ipv4Addr = match field(address).parse() {
+            Ok(ip) => ip,
+            Err(_) => continue,
+        };
+        if field(lifetime) == "0" || field(state) != "0" {
+            leases.remove(&ip);
+            continue;
+        }
+        let (mac, expires) = match (field(hwaddr).parse(), timestamp(field(expire))) {
+            (Ok(mac), Some(expires)) => (mac, expires),
+            _ => continue,
+        };
+        let hostname = Some(field(hostname))
+            .filter(|h| !h.is_empty())
+            .map(|h| h.trim_end_matches('.').to_string());
+        leases.insert(
+            ip,
+            Lease {
+                mac,
+                ip,
+                hostname,
+                expires: Some(expires),
+            },
+        );
+    }
+    leases.into_values().collect()
+}
+
+fn lease_file_error(path: &Path) -> crate::error::LeaseFileError<PathBuf> {
+    crate::error::LeaseFileError {
+        path: path.to_path_buf(),
+    }
+}
+
+/// Reads the active leases in a lease file, keyed by MAC.
+pub fn read(
+    path: &Path,
+    format: LeaseFormat,
+    now: DateTime<Local>,
+) -> crate::Result<HashMap<MacAddr, Lease>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| lease_file_error(path)),
+    };
+    let leases = match format {
+        LeaseFormat::Dnsmasq => parse_dnsmasq(&content),
+        LeaseFormat::Kea => parse_kea(&content),
+    };
+    Ok(leases
+        .into_iter()
+        .filter(|lease| lease.is_active(now))
+        .map(|lease| (lease.mac, lease))
+        .collect())
+}
+
+/// Checks for an active lease of a device, for `cross_check`.
+pub struct CrossCheck {
+    path: PathBuf,
+    format: LeaseFormat,
+}
+
+impl CrossCheck {
+    pub fn new(config: &config::Leases) -> Self {
+        CrossCheck {
+            path: config.path.clone(),
+            format: config.format,
+        }
+    }
+}
+
+impl crate::tracker::CrossCheck for CrossCheck {
+    fn is_present(&mut self, mac: MacAddr) -> crate::Result<bool> {
+        Ok(read(&self.path, self.format, Local::now())?.contains_key(&mac))
+    }
+}
+
+/// Waits for a lease file to change, with inotify on its directory since lease files are often
+/// replaced rather than written in place.
+#[cfg(target_os = "linux")]
+struct Watch {
+    fd: libc::c_int,
+    file_name: Option<std::ffi::OsString>,
+    buffer: [u8; 4096],
+}
+
+#[cfg(target_os = "linux")]
+impl Watch {
+    fn new(path: &Path) -> crate::Result<Watch> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        let dir_name = std::ffi::CString::new(dir.as_os_str().as_bytes()).unwrap();
+        if fd < 0
+            || unsafe {
+                libc::inotify_add_watch(
+                    fd,
+                    dir_name.as_ptr(),
+                    libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE,
+                )
+            } < 0
+        {
+            return Err(std::io::Error::last_os_error()).with_context(|| lease_file_error(&dir));
+        }
+        Ok(Watch {
+            fd,
+            file_name: path.file_name().map(|name| name.to_os_string()),
+            buffer: [0; 4096],
+        })
+    }
+
+    fn wait(&mut self) -> std::io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        loop {
+            let buffer = &mut self.buffer;
+            let len = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut _, buffer.len()) };
+            if len < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            let mut changed = false;
+            let mut offset = 0;
+            while offset < len as usize {
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buffer.as_ptr().add(offset) as *const _) };
+                let name_start = offset + std::mem::size_of::<libc::inotify_event>();
+                let name = &buffer[name_start..name_start + event.len as usize];
+                let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(name.len())];
+                changed |= self
+                    .file_name
+                    .as_ref()
+                    .map_or(false, |file_name| file_name.as_bytes() == name);
+                offset = name_start + event.len as usize;
+            }
+            if changed {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Waits for a lease file to change by polling its modification time and size, where there's no
+/// inotify.
+#[cfg(not(target_os = "linux"))]
+struct Watch {
+    path: PathBuf,
+    last: Option<(std::time::SystemTime, u64)>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watch {
+    const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+    fn new(path: &Path) -> crate::Result<Watch> {
+        let mut watch = Watch {
+            path: path.to_path_buf(),
+            last: None,
+        };
+        watch.last = watch.stat();
+        Ok(watch)
+    }
+
+    fn stat(&self) -> Option<(std::time::SystemTime, u64)> {
+        let metadata = std::fs::metadata(&self.path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    fn wait(&mut self) -> std::io::Result<()> {
+        loop {
+            std::thread::sleep(Self::POLL_INTERVAL);
+            let current = self.stat();
+            if current != self.last {
+                self.last = current;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Watches a lease file in a thread of its own, sending `Event::Connected` and `Event::Alive` for
+/// every new or renewed lease, and returns the leases active at startup.
+pub fn start(config: &config::Leases, events: Sender<Event>) -> crate::Result<Vec<Lease>> {
+    let path = config.path.clone();
+    let format = config.format;
+    let mut watch = Watch::new(&path)?;
+
+    let mut leases = read(&path, format, Local::now())?;
+    let initial = leases.values().cloned().collect();
+    std::thread::spawn(move || loop {
+        if let Err(e) = watch.wait() {
+            println!("Failed to watch lease file, exiting: {}", e);
+            return;
+        }
+
+        let current = match read(&path, format, Local::now()) {
+            Ok(current) => current,
+            Err(e) => {
+                println!("Failed to read lease file: {}", e);
+                continue;
+            }
+        };
+        for lease in current.values() {
+            if leases.get(&lease.mac) == Some(lease) {
+                continue;
+            }
+            let sent = events
+                .send(Event::Connected {
+                    mac: lease.mac,
+                    ip: Some(lease.ip),
+                    hostname: lease.hostname.clone(),
+                })
+                .and_then(|()| {
+                    events.send(Event::Alive {
+                        mac: lease.mac,
+                        ip: lease.ip,
+                    })
+                });
+            if let Err(e) = sent {
+                println!("Failed to send event, exiting: {}", e);
+                return;
+            }
+        }
+        leases = current;
+    });
+    Ok(initial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+    #[test]
+    fn test_parse_dnsmasq() {
+        let leases = parse_dnsmasq(
+            "1700000000 00:11:22:33:44:55 192.168.1.10 myphone 01:00:11:22:33:44:55\n\
+             0 66:77:88:99:aa:bb 192.168.1.11 * *\n\
+             garbage\n",
+        );
+        assert_eq!(leases.len(), 2);
+        assert_eq!(leases[0].mac, MAC);
+        assert_eq!(leases[0].ip, Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(leases[0].hostname.as_deref(), Some("myphone"));
+        assert_eq!(leases[0].expires, timestamp("1700000000"));
+        assert_eq!(leases[1].hostname, None);
+        assert_eq!(leases[1].expires, None);
+    }
+
+    #[test]
+    fn test_parse_kea() {
+        let leases = parse_kea(
+            "address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context\n\
+             192.168.1.10,00:11:22:33:44:55,,3600,1700000000,1,0,0,myphone.,0,\n\
+             192.168.1.10,00:11:22:33:44:55,,3600,1700003600,1,0,0,myphone.,0,\n\
+             192.168.1.11,66:77:88:99:aa:bb,,3600,1700000000,1,0,0,,0,\n\
+             192.168.1.11,66:77:88:99:aa:bb,,0,1700000000,1,0,0,,0,\n",
+        );
+        assert_eq!(
+            leases,
+            vec![Lease {
+                mac: MAC,
+                ip: Ipv4Addr::new(192, 168, 1, 10),
+                hostname: Some("myphone".to_string()),
+                expires: timestamp("1700003600"),
+            }]
+        );
+    }
+}
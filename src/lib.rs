@@ -0,0 +1,48 @@
+//! Presence detection of known devices on the local network.
+//!
+//! The `houserat` binary is a thin wrapper around [`PresenceTracker`], which can also be embedded
+//! and driven by any source of [`Event`]s.
+
+pub mod api;
+pub mod backup;
+#[cfg(all(feature = "ble", target_os = "linux"))]
+pub mod ble;
+pub mod commands;
+pub mod config;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod encrypt;
+pub mod error;
+pub mod filter;
+pub mod history;
+pub mod identity;
+pub mod inventory;
+pub mod leader;
+pub mod leases;
+pub mod mdns;
+pub mod metadata;
+pub mod network;
+pub mod notifier;
+pub mod output;
+pub mod pacing;
+pub mod poller;
+pub mod replay;
+pub mod rtt;
+pub mod sampling;
+pub mod signals;
+pub mod simulate;
+pub mod state;
+pub mod stats;
+pub mod store;
+pub mod systemd;
+pub mod telegram;
+pub mod tracker;
+pub mod webhook;
+
+pub use identity::Identity;
+pub use metadata::Metadata;
+pub use network::Event;
+pub use notifier::{Notification, Notifier};
+pub use tracker::{PresenceTracker, Status};
+
+pub type Result<T, E = error::Error> = std::result::Result<T, E>;
@@ -1,126 +1,422 @@
 use c_ares_resolver::Resolver;
-use config::NetworkAddresses;
 use crossbeam_channel::{never, select};
-use metadata::Metadata;
-use network::Event;
-use pnet::util::MacAddr;
-use std::collections::{hash_map, HashMap};
+#[cfg(all(feature = "ble", target_os = "linux"))]
+use houserat::ble;
+#[cfg(feature = "dbus")]
+use houserat::dbus;
+use houserat::network::{self, Event};
+use houserat::notifier::{self, Notifier};
+use houserat::tracker::{
+    Clock, CrossCheck, PresenceTracker, SystemClock, ALLOWED_PACKETS_LOST, TICK_SECS,
+};
+use houserat::{
+    api, backup, commands, config, error, inventory, leader, leases, mdns, output, pacing, poller,
+    replay, signals, simulate, stats, store, systemd, telegram, webhook, Result,
+};
 use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
 
-mod config;
-mod error;
-mod metadata;
-mod network;
-mod telegram;
-
-const TICK_SECS: u32 = 20;
-const ALLOWED_PACKETS_LOST: u32 = 3;
+const INVENTORY_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const REMINDER_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const DIGEST_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// How often to resolve configured hostnames again, in case a device's address changed
+const RESOLVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+const CAPTURE_RESTART_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+/// Consecutive capture failures, without a packet in between, after which houserat gives up
+const MAX_CAPTURE_FAILURES: u32 = 6;
 
 #[derive(Debug, structopt::StructOpt)]
 #[structopt(about)]
 struct Opt {
     #[structopt(long, default_value = "config.toml")]
     config_file: PathBuf,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, structopt::StructOpt)]
+enum Command {
+    /// Monitor the network and notify subscribers
+    Run {
+        /// Log notifications instead of sending them
+        #[structopt(long)]
+        dry_run: bool,
+        /// Print presence events as JSON lines on stdout, text or json, moving logs to stderr
+        #[structopt(long, default_value = "text")]
+        output: output::Format,
+    },
+    /// Validate the config file and print the resolved rules
+    CheckConfig,
+    /// List interfaces that can be used for capturing
+    ListInterfaces,
+    /// Print a JSON Schema of the config file, for editor completion and validation
+    Schema,
+    /// Send a test message to a user to verify bot_token and chat_id
+    SendTest {
+        /// Name of user to send message to
+        user: String,
+    },
+    /// Feed packets from a capture file instead of the live interface, implies --dry-run
+    Replay {
+        /// Path to pcap file
+        file: PathBuf,
+        /// Replay speed relative to capture timestamps, e.g. 10x, or max for no delays
+        #[structopt(long, default_value = "1x")]
+        speed: replay::Speed,
+    },
+    /// Compare notifications from recorded history against a candidate config
+    Simulate {
+        /// Path to candidate config file
+        #[structopt(long)]
+        config: PathBuf,
+        /// How far back in history to simulate
+        #[structopt(long, default_value = "30d", parse(try_from_str = humantime::parse_duration))]
+        history: std::time::Duration,
+    },
+    /// Save config, state and history to a single file
+    Backup {
+        /// Path to backup file
+        file: PathBuf,
+    },
+    /// Restore config, state and history from a backup file
+    Restore {
+        /// Path to backup file
+        file: PathBuf,
+        /// Overwrite a different config file and merge into existing history
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Stop tracking and notifying a user, keeping their configuration
+    Archive {
+        /// Name of user to archive
+        user: String,
+    },
+    /// Resume tracking and notifying an archived user
+    Unarchive {
+        /// Name of user to unarchive
+        user: String,
+    },
+    /// Print every device seen on the network with its IP, vendor and last seen time
+    Map {
+        /// Output format, dot (Graphviz) or json
+        #[structopt(long, default_value = "dot")]
+        format: inventory::Format,
+        /// IEEE oui.txt or Wireshark manuf file to look up vendors in
+        #[structopt(long)]
+        vendors: Option<PathBuf>,
+    },
 }
 
-type Result<T, E = error::Error> = std::result::Result<T, E>;
+struct HouseRat {
+    interface_name: String,
+    devices: Vec<config::Device>,
+    lease: Option<Arc<leader::Lease>>,
+    pollers: Vec<config::Poller>,
+    leases: Option<config::Leases>,
+    #[cfg(all(feature = "ble", target_os = "linux"))]
+    ble_adapter: u16,
+    beacons: Vec<config::Beacon>,
+    commands: Option<commands::Commands>,
+    api: Option<config::Api>,
+    dbus: Option<config::Dbus>,
+    mdns: bool,
+    /// Sends admin alerts and answers bot commands
+    telegram: telegram::Client,
+    admin_chat_id: Option<i64>,
+    dry_run: bool,
+    /// Shared with the keepalive pacer, `None` in passive mode
+    socket: Option<Arc<network::Socket>>,
+    addresses: config::NetworkAddresses,
+    capture: config::Capture,
+    /// The BPF filter of the capture, rebuilt as the devices it depends on change
+    capture_filter: String,
+    /// How many times the capture filter was rebuilt
+    filter_generation: u64,
+    /// Applies a rebuilt filter to the running capture
+    filter_updates: Option<crossbeam_channel::Sender<String>>,
+    /// When ARP keepalives were sent, for the capture to time their replies, `None` in passive mode
+    keepalives: Option<crossbeam_channel::Receiver<(pnet::util::MacAddr, std::time::SystemTime)>>,
+    /// Receives SIGUSR1, which toggles vacation mode
+    vacation_toggles: crossbeam_channel::Receiver<libc::c_int>,
+    /// Receives SIGTERM and SIGINT, which stop houserat after alerting the admin chat
+    stops: crossbeam_channel::Receiver<libc::c_int>,
+    alerts: config::Alerts,
+    tracker: PresenceTracker,
+    /// What the tracker is told the time is
+    clock: Box<dyn Clock>,
+}
 
-#[derive(Debug)]
-enum Status {
-    Arrived,
-    Left,
+/// What bot commands act on in the running daemon.
+struct Actions<'a> {
+    socket: &'a Option<Arc<network::Socket>>,
+    addresses: &'a config::NetworkAddresses,
+    tracker: &'a mut PresenceTracker,
 }
 
-impl std::fmt::Display for Status {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::Arrived => write!(f, "arrived"),
-            Self::Left => write!(f, "left"),
+impl commands::Actions for Actions<'_> {
+    fn wake(&mut self, mac: pnet::util::MacAddr) -> Result<()> {
+        match self.socket {
+            Some(socket) => socket.send_wol(self.addresses, mac),
+            None => Err(error::Error::Passive),
         }
     }
-}
 
-#[derive(Debug)]
-struct Tracking {
-    ip: std::net::Ipv4Addr,
-    outstanding: u32,
-}
+    fn vacation(&mut self, on: Option<bool>) -> bool {
+        if let Some(on) = on {
+            self.tracker.set_vacation(on);
+        }
+        self.tracker.vacation()
+    }
 
-struct HouseRat {
-    interface_name: String,
-    network_addresses: NetworkAddresses,
-    socket: network::Socket,
-    client: telegram::Client,
-    cooldown: Option<chrono::Duration>,
-    quiet_period: Option<config::Period>,
-    devices: Option<Vec<config::Device>>,
-    rules: HashMap<MacAddr, Metadata>,
-    online: HashMap<MacAddr, Tracking>,
+    fn audited(&mut self, audited: &commands::Audited) -> Result<()> {
+        self.tracker.append_audit(audited)
+    }
 }
 
 impl HouseRat {
-    fn new(config: config::Config) -> Result<Self> {
-        Ok(Self {
-            interface_name: config.interface.name,
-            network_addresses: config.interface.addresses,
-            socket: network::Socket::new(config.interface.index)?,
-            client: telegram::Client::new(&config.bot_token),
-            cooldown: config.cooldown,
-            quiet_period: config.quiet_period,
-            devices: Some(config.devices),
-            rules: config.rules,
-            online: HashMap::new(),
-        })
-    }
-
+    /// Starts capturing in a thread of its own, whose channel disconnects if capturing fails.
     fn start_pcap(&mut self) -> Result<crossbeam_channel::Receiver<Event>> {
-        let mut capture = pcap::Capture::from_device(self.interface_name.as_str())?
-            .promisc(true)
-            .open()?;
-        capture.direction(pcap::Direction::In)?;
-        capture.filter("arp or (udp and port bootpc)")?;
-
+        let (filter_s, filter_r) = crossbeam_channel::unbounded();
+        let live = replay::Live::open(
+            &self.interface_name,
+            &self.capture,
+            &self.capture_filter,
+            filter_r,
+            self.keepalives.clone(),
+            self.tracker.filter().clone(),
+        )?;
+        let ignore = self.tracker.filter().clone();
         let (s, r) = crossbeam_channel::unbounded();
-        std::thread::spawn(move || loop {
-            match capture.next() {
-                Ok(packet) => {
-                    if let Err(e) = s.send(network::parse_packet(packet.data)) {
-                        println!("Failed to send event, exiting: {}", e);
-                        return;
-                    }
-                }
-                Err(e) => {
-                    println!("Failed to read packet, exiting: {}", e);
-                    return;
-                }
-            };
+        std::thread::spawn(move || {
+            if let Err(e) = replay::forward(live, &ignore, &s) {
+                println!("Failed to capture, exiting: {}", e);
+            }
         });
+        self.filter_updates = Some(filter_s);
 
         Ok(r)
     }
 
+    /// Rebuilds the capture filter when the devices it depends on change, applying it to the
+    /// running capture without restarting it, or to the next one if it's restarting.
+    fn update_filter(&mut self) {
+        let filter =
+            network::capture_filter(&self.tracker.pinged(), self.capture.filter.as_deref());
+        if filter == self.capture_filter {
+            return;
+        }
+        self.filter_generation += 1;
+        println!(
+            "Applying capture filter generation {}: {}",
+            self.filter_generation, filter
+        );
+        if let Some(updates) = &self.filter_updates {
+            // fails only if the capture stopped, in which case it restarts with the new filter
+            let _ = updates.send(filter.clone());
+        }
+        self.capture_filter = filter;
+    }
+
+    /// Sends an alert to the admin chat, or just logs it if there's none.
+    fn alert(&self, text: &str) {
+        println!("Alert: {}", text);
+        self.tell_admin(&format!("⚠️ {}", text));
+    }
+
+    /// Sends an operational message that's no cause for concern to the admin chat, if any.
+    fn inform(&self, text: &str) {
+        println!("{}", text);
+        self.tell_admin(&format!("ℹ️ {}", text));
+    }
+
+    fn tell_admin(&self, text: &str) {
+        let chat_id = match self.admin_chat_id {
+            Some(chat_id) if !self.dry_run => chat_id,
+            _ => return,
+        };
+        let message = telegram::Message::new(chat_id, text.to_string(), false);
+        if let Err(e) = message.send(&self.telegram) {
+            println!("Failed to send alert: {}", e.report());
+        }
+    }
+
+    /// Tracks an event, sending the alerts it raises unless a replica, like notifications.
+    fn handle_event(&mut self, event: Event) {
+        let now = self.clock.now();
+        let id = self.tracker.correlation_id(now);
+        self.tracker.handle_event(event, now, &id);
+        self.send_alerts();
+    }
+
+    /// Sends the alerts raised by the tracker, unless a replica.
+    fn send_alerts(&mut self) {
+        let alerts = self.tracker.take_alerts();
+        if self
+            .lease
+            .as_ref()
+            .map_or(false, |lease| !lease.is_leader())
+        {
+            return;
+        }
+        for alert in alerts {
+            self.alert(&alert);
+        }
+    }
+
+    /// Renews the leader lease, then publishes presence state as the leader or adopts the
+    /// leader's state as a standby.
+    fn replicate(&mut self) -> Result<()> {
+        let lease = match &self.lease {
+            Some(lease) => lease,
+            None => return Ok(()),
+        };
+        if lease.acquire(self.clock.now())? {
+            lease.publish(&self.tracker.snapshot())
+        } else {
+            if let Some(snapshot) = lease.fetch()? {
+                self.tracker.restore(snapshot);
+            }
+            Ok(())
+        }
+    }
+
+    /// Answers a command sent to the bot, leaving it to the leader if running as a replica.
+    fn answer(&mut self, chat_id: i64, text: &str) -> Result<()> {
+        let (commands, command) = match (&mut self.commands, commands::Command::parse(text)) {
+            (Some(commands), Some(command)) => (commands, command),
+            _ => return Ok(()),
+        };
+        if self
+            .lease
+            .as_ref()
+            .map_or(false, |lease| !lease.is_leader())
+        {
+            return Ok(());
+        }
+        let mut actions = Actions {
+            socket: &self.socket,
+            addresses: &self.addresses,
+            tracker: &mut self.tracker,
+        };
+        let reply = commands.handle(chat_id, command, &mut actions, self.clock.now());
+        telegram::Message::new(chat_id, reply, false).send(&self.telegram)
+    }
+
     fn run(&mut self) -> Result<()> {
-        let cap_r = self.start_pcap()?;
+        let mut pcap_r = Some(self.start_pcap()?);
+        let mut capture_failures = 0;
+        let mut capture_restart = None;
+        let (cap_s, cap_r) = crossbeam_channel::unbounded();
+        let mut cap_r = Some(&cap_r);
+        if !self.beacons.is_empty() {
+            #[cfg(all(feature = "ble", target_os = "linux"))]
+            {
+                println!("Scanning for BLE beacons on hci{}", self.ble_adapter);
+                ble::start(self.ble_adapter, &self.beacons, cap_s.clone())?;
+            }
+            #[cfg(not(all(feature = "ble", target_os = "linux")))]
+            return Err(error::Error::UnsupportedBle);
+        }
+        if let Some(config) = &self.dbus {
+            #[cfg(feature = "dbus")]
+            {
+                println!("Exporting presence on the {} bus", config.bus);
+                let presence = dbus::Presence::start(config, self.tracker.devices())?;
+                self.tracker.set_dbus(presence);
+            }
+            #[cfg(not(feature = "dbus"))]
+            {
+                let _ = config;
+                return Err(error::Error::UnsupportedDbus);
+            }
+        }
+        for config in &self.pollers {
+            println!("Polling {} for associated stations", config.url);
+            poller::start(config, cap_s.clone());
+        }
+        if let Some(config) = &self.leases {
+            println!("Watching lease file {}", config.path.display());
+            for lease in leases::start(config, cap_s.clone())? {
+                self.tracker.probe(lease.mac, lease.ip);
+            }
+        }
+        if self.mdns && self.socket.is_none() {
+            println!("Not describing devices over mDNS in passive mode");
+        } else if self.mdns {
+            println!("Describing arriving devices from their mDNS services");
+            self.tracker
+                .set_describer(Box::new(mdns::Mdns::start(cap_s.clone())));
+        }
+        drop(cap_s);
 
         let (resolve_s, resolve_r) = crossbeam_channel::unbounded();
         let resolver = Resolver::new().expect("Failed to create resolver");
-        for device in self.devices.as_ref().unwrap() {
-            let resolve_s2 = resolve_s.clone();
-            let mac = device.mac;
-            resolver.query_a(&device.hostname, move |result| match result {
-                Ok(result) => {
-                    for a_result in result.into_iter() {
-                        if let Err(e) = resolve_s2.send((mac, a_result.ipv4())) {
-                            println!("Failed to send address resolution: {}", e);
-                        }
-                    }
-                }
-                Err(e) => println!("Failed to resolve: {}", e),
-            });
+        for device in &self.devices {
+            resolve(&resolver, device, &resolve_s);
+        }
+        let re_resolve = if self.devices.is_empty() {
+            None
+        } else {
+            Some(crossbeam_channel::tick(RESOLVE_INTERVAL))
+        };
+
+        let api = match &self.api {
+            Some(config) => {
+                println!("Serving status API on {}", config.listen);
+                Some(api::Api::start(config)?)
+            }
+            None => None,
+        };
+        let mut api_r = api.as_ref().map(api::Api::requests);
+
+        let (message_s, message_r) = crossbeam_channel::unbounded();
+        let mut message_r = if self.commands.is_some() {
+            println!("Answering bot commands");
+            telegram::listen(self.telegram.clone(), message_s);
+            Some(&message_r)
+        } else {
+            None
+        };
+
+        // the clock only ticks while devices are online, so the watchdog has its own ticker
+        let watchdog = systemd::watchdog_interval().map(crossbeam_channel::tick);
+        let renew = self
+            .lease
+            .as_ref()
+            .map(|lease| crossbeam_channel::tick(lease.renew_interval()));
+        if let Err(e) = self.replicate() {
+            println!("Failed to replicate presence state: {}", e);
+        }
+        if let Err(e) = systemd::notify("READY=1") {
+            println!("Failed to notify systemd: {}", e);
         }
-        drop(resolve_s);
-        let mut resolve_r = Some(&resolve_r);
+
+        let inventory = crossbeam_channel::tick(INVENTORY_SAVE_INTERVAL);
+        let stats_check = crossbeam_channel::tick(stats::CHECK_INTERVAL);
+        let mut sanity = stats::Sanity::new(stats::SILENCE_WINDOW);
+        let reminders = if self.tracker.has_reminders() {
+            Some(crossbeam_channel::tick(REMINDER_CHECK_INTERVAL))
+        } else {
+            None
+        };
+        let digests = if self.tracker.has_daily_limit() {
+            Some(crossbeam_channel::tick(DIGEST_CHECK_INTERVAL))
+        } else {
+            None
+        };
+
+        let mut unknown_summary = self
+            .alerts
+            .unknown_summary
+            .map(|time| crossbeam_channel::after(until(time, self.clock.now())));
+
+        self.inform(&format!(
+            "houserat {} started on {}, tracking {} devices",
+            env!("CARGO_PKG_VERSION"),
+            self.interface_name,
+            self.tracker.devices().len()
+        ));
 
         let mut t;
         let mut clock = None;
@@ -128,155 +424,738 @@ impl HouseRat {
         #[allow(clippy::drop_copy, clippy::zero_ptr)]
         loop {
             select! {
-                recv(cap_r) -> event => self.handle_event(event?),
-                recv(clock.unwrap_or(&never())) -> _ => self.handle_clock(),
-                recv(resolve_r.unwrap_or(&never())) -> device => match device {
-                    Ok((mac, ip)) => self.handle_resolve(mac, ip),
+                recv(pcap_r.as_ref().unwrap_or(&never())) -> event => match event {
+                    Ok(event) => {
+                        capture_failures = 0;
+                        sanity.captured();
+                        self.handle_event(event);
+                    }
                     Err(_) => {
-                        resolve_r = None;
-                        self.devices = None;
+                        pcap_r = None;
+                        capture_failures += 1;
+                        if capture_failures >= MAX_CAPTURE_FAILURES {
+                            self.alert(&format!("Capture on {} keeps failing, exiting", self.interface_name));
+                            return Err(error::Error::CaptureFailed { failures: capture_failures });
+                        }
+                        let backoff = capture_backoff(capture_failures);
+                        println!("Capture stopped, restarting in {}s", backoff.as_secs());
+                        capture_restart = Some(crossbeam_channel::after(backoff));
+                    }
+                },
+                recv(capture_restart.as_ref().unwrap_or(&never())) -> _ => {
+                    capture_restart = None;
+                    match self.start_pcap() {
+                        Ok(r) => {
+                            self.inform(&format!("Capture restarted on {} after {} failures", self.interface_name, capture_failures));
+                            pcap_r = Some(r);
+                        }
+                        Err(e) => {
+                            capture_failures += 1;
+                            if capture_failures >= MAX_CAPTURE_FAILURES {
+                                self.alert(&format!("Failed to restart capture on {}, exiting: {}", self.interface_name, e));
+                                return Err(e);
+                            }
+                            let backoff = capture_backoff(capture_failures);
+                            println!("Failed to restart capture, retrying in {}s: {}", backoff.as_secs(), e);
+                            capture_restart = Some(crossbeam_channel::after(backoff));
+                        }
+                    }
+                }
+                recv(cap_r.unwrap_or(&never())) -> event => match event {
+                    Ok(event) => self.handle_event(event),
+                    Err(_) => cap_r = None,
+                },
+                recv(clock.unwrap_or(&never())) -> _ => {
+                    let now = self.clock.now();
+                    let id = self.tracker.correlation_id(now);
+                    self.tracker.handle_clock(now, &id);
+                    self.send_alerts();
+                    // the address may have changed, probe the new one before giving up on the device
+                    for mac in self.tracker.unanswered() {
+                        if let Some(device) = self.devices.iter().find(|device| device.mac == mac) {
+                            resolve(&resolver, device, &resolve_s);
+                        }
+                    }
+                }
+                recv(watchdog.as_ref().unwrap_or(&never())) -> _ => {
+                    if let Err(e) = systemd::notify("WATCHDOG=1") {
+                        println!("Failed to notify systemd watchdog: {}", e);
+                    }
+                }
+                recv(stats_check) -> _ => match stats::rx_packets(&self.interface_name) {
+                    Ok(Some(rx_packets)) => match sanity.check(rx_packets, std::time::Instant::now()) {
+                        stats::Check::Silent(received) => self.alert(&format!(
+                            "{} received {} packets in the last {} minutes but none matched capture filter generation {}",
+                            self.interface_name,
+                            received,
+                            stats::SILENCE_WINDOW.as_secs() / 60,
+                            self.filter_generation
+                        )),
+                        stats::Check::Recovered => {
+                            self.alert(&format!("Capturing on {} again", self.interface_name))
+                        }
+                        stats::Check::Ok => (),
+                    },
+                    Ok(None) => (),
+                    Err(e) => println!("Failed to read interface statistics: {}", e),
+                },
+                recv(inventory) -> _ => {
+                    if let Err(e) = self.tracker.save_inventory() {
+                        println!("Failed to save inventory: {}", e);
+                    }
+                }
+                recv(reminders.as_ref().unwrap_or(&never())) -> _ => {
+                    let now = self.clock.now();
+                    let id = self.tracker.correlation_id(now);
+                    self.tracker.remind(now, &id);
+                    self.send_alerts();
+                }
+                recv(digests.as_ref().unwrap_or(&never())) -> _ => {
+                    let now = self.clock.now();
+                    let id = self.tracker.correlation_id(now);
+                    self.tracker.send_digests(now, &id);
+                    self.send_alerts();
+                }
+                recv(renew.as_ref().unwrap_or(&never())) -> _ => {
+                    if let Err(e) = self.replicate() {
+                        println!("Failed to replicate presence state: {}", e);
+                    }
+                }
+                recv(api_r.unwrap_or(&never())) -> request => match request {
+                    Ok(request) => {
+                        if let Some(api) = &api {
+                            api.respond(request, &mut self.tracker, self.clock.now());
+                        }
                     }
+                    Err(_) => api_r = None,
+                },
+                recv(message_r.unwrap_or(&never())) -> message => match message {
+                    Ok((chat_id, text)) => {
+                        if let Err(e) = self.answer(chat_id, &text) {
+                            println!("Failed to answer command: {}", e.report());
+                        }
+                    }
+                    Err(_) => message_r = None,
+                },
+                recv(self.stops) -> signal => {
+                    if let Err(e) = self.tracker.save_inventory() {
+                        println!("Failed to save inventory: {}", e);
+                    }
+                    let name = if signal == Ok(libc::SIGINT) { "SIGINT" } else { "SIGTERM" };
+                    self.inform(&format!("houserat stopping on {}", name));
+                    return Ok(());
+                }
+                recv(unknown_summary.as_ref().unwrap_or(&never())) -> _ => {
+                    let now = self.clock.now();
+                    let is_leader = self.lease.as_ref().map_or(true, |lease| lease.is_leader());
+                    if let (true, Some(summary)) = (is_leader, self.tracker.unknown_summary(now - chrono::Duration::days(1))) {
+                        self.inform(&summary);
+                    }
+                    unknown_summary = self
+                        .alerts
+                        .unknown_summary
+                        .map(|time| crossbeam_channel::after(until(time, now)));
+                }
+                recv(self.vacation_toggles) -> _ => {
+                    let on = !self.tracker.vacation();
+                    println!("Received SIGUSR1, turning vacation mode {}", if on { "on" } else { "off" });
+                    self.tracker.set_vacation(on);
+                }
+                recv(re_resolve.as_ref().unwrap_or(&never())) -> _ => {
+                    for device in &self.devices {
+                        resolve(&resolver, device, &resolve_s);
+                    }
+                }
+                recv(resolve_r) -> device => if let Ok((mac, ip)) = device {
+                    self.tracker.resolved(mac, ip);
                 },
             }
-            match (self.online.is_empty(), clock) {
-                (true, Some(_)) => {
+            match (self.tracker.is_tracking(), clock) {
+                (false, Some(_)) => {
                     println!("No devices online, disabling clock");
                     clock = None;
                 }
-                (false, None) => {
+                (true, None) => {
                     println!("Devices online, enabling clock");
                     t = crossbeam_channel::tick(std::time::Duration::from_secs(TICK_SECS.into()));
                     clock = Some(&t);
                 }
                 _ => (),
             }
+            self.update_filter();
         }
     }
+}
 
-    fn handle_resolve(&self, mac: MacAddr, ip: std::net::Ipv4Addr) {
-        println!("Resolved: {}", ip);
-        if let Err(e) = self
-            .socket
-            .send_arp_request(&self.network_addresses, &NetworkAddresses::new(mac, ip))
-        {
-            println!("Failed to send ARP request to {}: {}", ip, e);
-        }
+/// How long until the next time `time` comes around.
+fn until(time: chrono::NaiveTime, now: chrono::DateTime<chrono::Local>) -> std::time::Duration {
+    let mut next = now
+        .date_naive()
+        .and_time(time)
+        .and_local_timezone(chrono::Local)
+        .earliest()
+        .unwrap_or(now);
+    if next <= now {
+        next += chrono::Duration::days(1);
     }
+    (next - now).to_std().unwrap_or_default()
+}
 
-    fn handle_event(&mut self, event: Event) {
-        match event {
-            Event::Connected(mac) => {
-                if self.online.contains_key(&mac) {
-                    println!("Device {} reconnected, skipping notification", mac);
-                } else {
-                    self.notify(mac, Status::Arrived);
-                }
-            }
-            Event::Alive { mac, ip } => {
-                if self.rules.contains_key(&mac) {
-                    println!("Device {} is alive", mac);
-                    match self.online.entry(mac) {
-                        hash_map::Entry::Occupied(mut occupied) => {
-                            occupied.get_mut().outstanding = 0
-                        }
-                        hash_map::Entry::Vacant(vacant) => {
-                            vacant.insert(Tracking { ip, outstanding: 0 });
-                        }
-                    }
-                }
-            }
-            Event::Ignored => (),
-        }
+fn capture_backoff(failures: u32) -> std::time::Duration {
+    CAPTURE_RESTART_BACKOFF * 2u32.pow(failures.saturating_sub(1))
+}
+
+fn format_duration(duration: Option<std::time::Duration>) -> String {
+    duration.map_or("none".into(), |duration| {
+        humantime::format_duration(duration).to_string()
+    })
+}
+
+/// Logs the effective configuration on startup, with defaults resolved, as `key=value` pairs.
+fn log_effective_config(config: &config::Config) {
+    println!("houserat {} starting", env!("CARGO_PKG_VERSION"));
+    println!(
+        "Config: interface={} mac={} ip={} users={} devices={} hostnames={} beacons={} archived={}",
+        config.interface.name,
+        config.interface.addresses.mac,
+        config.interface.addresses.ip,
+        config.identities.len(),
+        config.rules.len(),
+        config.devices.len(),
+        config.beacons.len(),
+        config.archived.len()
+    );
+    let mut sources = vec![if config.passive.is_some() {
+        "capture(passive)".to_string()
+    } else {
+        "capture(active)".to_string()
+    }];
+    sources.extend(
+        config
+            .pollers
+            .iter()
+            .map(|poller| format!("poller({:?},{})", poller.kind, poller.url)),
+    );
+    sources.extend(
+        config
+            .leases
+            .iter()
+            .map(|leases| format!("leases({:?},{})", leases.format, leases.path.display())),
+    );
+    if !config.beacons.is_empty() {
+        sources.push(format!("ble(hci{})", config.ble.adapter));
+    }
+    if config.mdns {
+        sources.push("mdns".into());
+    }
+    println!("Sources: {}", sources.join(" "));
+    println!(
+        "Capture: promisc={} snaplen={} buffer_size={} timeout={} filter={}",
+        config.capture.promisc,
+        config
+            .capture
+            .snaplen
+            .map_or("default".into(), |snaplen| snaplen.to_string()),
+        config
+            .capture
+            .buffer_size
+            .map_or("default".into(), |size| size.to_string()),
+        format_duration(Some(config.capture.timeout)),
+        config.capture.filter.as_deref().unwrap_or("none")
+    );
+    if let Some(sampling) = &config.capture.sampling {
+        println!(
+            "Sampling: rate=1/{} threshold={}/s interval={}",
+            sampling.rate,
+            sampling.threshold,
+            format_duration(Some(sampling.interval))
+        );
+    }
+    let mut notifiers = vec![if config.dry_run {
+        "dry-run".to_string()
+    } else {
+        format!(
+            "telegram(bots={},parse_mode={:?})",
+            config.bot_tokens.len() + 1,
+            config.parse_mode
+        )
+    }];
+    if !config.dry_run {
+        notifiers.extend(
+            config
+                .webhooks
+                .iter()
+                .map(|webhook| format!("webhook({})", webhook.url)),
+        );
     }
+    println!(
+        "Notifiers: {} admin_chat={} commands={} api={}",
+        notifiers.join(" "),
+        config
+            .admin_chat_id
+            .map_or("none".into(), |chat_id| chat_id.to_string()),
+        config.commands,
+        config.api.as_ref().map_or("none", |api| &api.listen)
+    );
+    let mut timing = format!(
+        "Timing: tick={}s keepalives_lost={} resolve={} cooldown={} quiet_period={} daily_limit={} ble_absence={}",
+        TICK_SECS,
+        ALLOWED_PACKETS_LOST,
+        format_duration(Some(RESOLVE_INTERVAL)),
+        format_duration(config.cooldown.and_then(|cooldown| cooldown.to_std().ok())),
+        config
+            .quiet_period
+            .as_ref()
+            .map_or("none".into(), ToString::to_string),
+        config
+            .daily_limit
+            .map_or("none".into(), |limit| limit.to_string()),
+        format_duration(Some(config.ble.absence))
+    );
+    if config.passive.is_none() {
+        timing += &format!(
+            " keepalive_spread={} keepalive_rate={}/s",
+            format_duration(Some(config.keepalive.spread)),
+            config.keepalive.rate
+        );
+    }
+    if let Some(passive) = &config.passive {
+        timing += &format!(
+            " passive_arp={} passive_dhcp={} passive_mdns={}",
+            format_duration(Some(passive.arp)),
+            format_duration(Some(passive.dhcp)),
+            format_duration(Some(passive.mdns))
+        );
+    }
+    for poller in &config.pollers {
+        timing += &format!(" poll={}", format_duration(Some(poller.interval)));
+    }
+    if let Some(leader) = &config.leader {
+        timing += &format!(
+            " leader={} lease={}",
+            leader.id,
+            format_duration(Some(leader.lease))
+        );
+    }
+    println!("{}", timing);
+    println!(
+        "Storage: backend={} path={} vacation={}",
+        config.storage.backend,
+        config.storage.path.display(),
+        config.vacation
+    );
+}
 
-    fn handle_clock(&mut self) {
-        let mut left = Vec::new();
-        for (mac, tracking) in &mut self.online {
-            if tracking.outstanding < ALLOWED_PACKETS_LOST {
-                println!(
-                    "Sending keepalive to {} ({}), outstanding: {}",
-                    tracking.ip, mac, tracking.outstanding
-                );
-                match self.socket.send_arp_request(
-                    &self.network_addresses,
-                    &NetworkAddresses::new(*mac, tracking.ip),
-                ) {
-                    Ok(()) => tracking.outstanding += 1,
-                    Err(e) => println!("Failed to send keepalive: {}", e),
-                }
+fn check_config(config: &config::Config) {
+    println!(
+        "Interface: {} ({}, {})",
+        config.interface.name, config.interface.addresses.mac, config.interface.addresses.ip
+    );
+    if let Some(cooldown) = config.cooldown {
+        println!("Cooldown: {}s", cooldown.num_seconds());
+    }
+    if let Some(quiet_period) = &config.quiet_period {
+        println!("Quiet period: {}", quiet_period);
+    }
+    if config.dry_run {
+        println!("Dry run: enabled");
+    }
+    let mut bots: Vec<&String> = config.bot_tokens.keys().collect();
+    bots.sort();
+    for user in bots {
+        println!("Bot: {} is notified by a bot of their own", user);
+    }
+    if let Some(passive) = &config.passive {
+        println!(
+            "Passive: ARP counts for {}, DHCP for {}, mDNS for {}",
+            humantime::format_duration(passive.arp),
+            humantime::format_duration(passive.dhcp),
+            humantime::format_duration(passive.mdns)
+        );
+    }
+    if let Some(filter) = &config.capture.filter {
+        println!("Extra capture filter: {}", filter);
+    }
+    if !config.capture.promisc {
+        println!("Promiscuous mode: disabled");
+    }
+    if let Some(sampling) = &config.capture.sampling {
+        println!(
+            "Sampling: 1 in {} ARP packets per device above {} packets/s",
+            sampling.rate, sampling.threshold
+        );
+    }
+    if let Some(chat_id) = config.admin_chat_id {
+        println!("Admin chat: {}", chat_id);
+        println!(
+            "Alerts: after {} failed deliveries{}",
+            config.alerts.delivery_failures,
+            config
+                .alerts
+                .unknown_summary
+                .map_or(String::new(), |time| format!(
+                    ", unknown devices summarized at {}",
+                    time.format("%H:%M")
+                ))
+        );
+    }
+    if config.commands {
+        println!(
+            "Bot commands: enabled{}",
+            if config.confirm_commands {
+                ", changes need /confirm"
             } else {
-                println!(
-                    "Assuming {} left after not receiving response for {} seconds",
-                    mac,
-                    tracking.outstanding * TICK_SECS
-                );
-                left.push(*mac);
+                ""
             }
-        }
-        for mac in left {
-            let _ = self.online.remove(&mac);
-            self.notify(mac, Status::Left);
-        }
+        );
     }
+    if config.mdns {
+        println!("mDNS descriptions: enabled");
+    }
+    if config.vacation {
+        println!("Vacation mode: enabled");
+    }
+    if let Some(daily_limit) = config.daily_limit {
+        println!("Daily limit: {} notifications per user", daily_limit);
+    }
+    println!("Parse mode: {:?}", config.parse_mode);
+    println!(
+        "Storage: {} in {}",
+        config.storage.backend,
+        config.storage.path.display()
+    );
 
-    fn notify(&mut self, mac: MacAddr, status: Status) {
-        let metadata = match self.rules.get_mut(&mac) {
-            Some(metadata) => metadata,
-            None => {
-                println!("Unknown MAC {} connected, ignoring", mac);
-                return;
-            }
-        };
+    let mut rules: Vec<_> = config.rules.iter().collect();
+    rules.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+    println!("Rules:");
+    for (mac, metadata) in rules {
+        println!(
+            "  {} ({}) -> {} ({})",
+            metadata.name, mac, metadata.subscriber_name, metadata.identity
+        );
+    }
 
-        let now = chrono::Local::now();
+    if !config.devices.is_empty() {
+        println!("Resolved on startup:");
+        for device in &config.devices {
+            println!("  {} ({})", device.hostname, device.mac);
+        }
+    }
 
-        if !metadata.should_notify(&self.cooldown, now) {
-            println!(
-                "{} ({}) {} during cooldown, ignoring",
-                metadata.name, mac, status
-            );
-            return;
+    if !config.archived.is_empty() {
+        println!("Archived:");
+        for user in &config.archived {
+            println!("  {}", user);
         }
+    }
+}
 
-        let is_quiet = match &self.quiet_period {
-            Some(quiet_period) => quiet_period.is_between(now.naive_local().time()),
-            None => false,
-        };
+fn list_interfaces() {
+    for interface in config::Interface::all() {
+        println!(
+            "{} (index {}): {}, {}",
+            interface.name, interface.index, interface.addresses.mac, interface.addresses.ip
+        );
+    }
+}
+
+fn send_test(config: &config::Config, user: &str) -> Result<()> {
+    let chat_id = match config.identities.get(user) {
+        Some(identity) => identity
+            .telegram
+            .ok_or_else(|| error::Error::MissingChatId { user: user.into() })?,
+        None => return Err(error::Error::UnknownUser { user: user.into() }),
+    };
+    telegram::Message::new(chat_id, "🐀 Test message from houserat".to_string(), false)
+        .send(&telegram::Client::new(config.bot_token_for(user)))?;
+    println!("Sent test message to {} (chat {})", user, chat_id);
+    Ok(())
+}
+
+fn set_archived(config: &config::Config, user: &str, archived: bool) -> Result<()> {
+    if !config.identities.contains_key(user) {
+        return Err(error::Error::UnknownUser { user: user.into() });
+    }
 
+    let mut store = store::open(&config.storage)?;
+    let mut state = store.load_state()?;
+    let changed = if archived {
+        state.archived.insert(user.into())
+    } else {
+        state.archived.remove(user)
+    };
+    if changed {
+        store.save_state(&state)?;
+        println!(
+            "{} {}, restart houserat to apply",
+            if archived { "Archived" } else { "Unarchived" },
+            user
+        );
+    } else {
         println!(
-            "{} ({}) {}, notifying {} {}",
-            metadata.name,
-            mac,
-            status,
-            metadata.subscriber_name,
-            if is_quiet { "quietly" } else { "loudly" }
+            "{} is already {}",
+            user,
+            if archived { "archived" } else { "not archived" }
         );
+    }
+    if !archived && !changed && config.archived.contains(user) {
+        println!("{} is still archived in config file", user);
+    }
+    Ok(())
+}
 
-        if let Err(err) = telegram::Message::new(
-            metadata.chat_id,
-            format!("{} {}", metadata, status),
-            is_quiet,
-        )
-        .send(&self.client)
-        {
-            println!("Error sending Telegram message: {}", err);
+/// Resolves the hostname of a device in the background, sending its addresses to `resolved`.
+fn resolve(
+    resolver: &Resolver,
+    device: &config::Device,
+    resolved: &crossbeam_channel::Sender<(pnet::util::MacAddr, std::net::Ipv4Addr)>,
+) {
+    let resolved = resolved.clone();
+    let mac = device.mac;
+    let hostname = device.hostname.clone();
+    resolver.query_a(&device.hostname, move |result| match result {
+        Ok(result) => {
+            let mut found = false;
+            for a_result in result.into_iter() {
+                found = true;
+                if let Err(e) = resolved.send((mac, a_result.ipv4())) {
+                    println!("Failed to send address resolution: {}", e);
+                }
+            }
+            if !found {
+                println!(
+                    "Warning: {} has no addresses, not probing {}",
+                    hostname, mac
+                );
+            }
         }
-    }
+        Err(e) => println!(
+            "Warning: failed to resolve {}, not probing {}: {}",
+            hostname, mac, e
+        ),
+    });
 }
 
 fn run() -> Result<()> {
     let opt = Opt::from_args();
-    let config = config::Config::from_file(opt.config_file)?;
+    match opt.command {
+        Command::Run { dry_run, output } => {
+            // before anything starts a thread, which would be killed by the signals
+            let blocked = signals::block(&[libc::SIGUSR1, libc::SIGTERM, libc::SIGINT])?;
+            let vacation_toggles = blocked.listen(&[libc::SIGUSR1]);
+            let stops = blocked.listen(&[libc::SIGTERM, libc::SIGINT]);
+            let mut config = config::Config::from_file(opt.config_file)?;
+            config.dry_run |= dry_run;
+            let store = store::open(&config.storage)?;
+            config.archive(&store.load_state()?.archived);
+            let output = match (output, &config.output) {
+                (output::Format::Json, _) => Some(output::Output::stdout()?),
+                (output::Format::Text, Some(config)) => {
+                    Some(output::Output::socket(&config.socket_path)?)
+                }
+                (output::Format::Text, None) => None,
+            };
+            log_effective_config(&config);
+
+            println!("Listening on interface {}...", config.interface.name);
+            if config.dry_run {
+                println!("Dry run, notifications will not be sent");
+            }
+
+            let socket = if config.passive.is_some() {
+                println!("Passive mode, assuming devices left once their sightings expire");
+                None
+            } else {
+                Some(Arc::new(network::Socket::new(config.interface.index)?))
+            };
+            let commands = if config.commands && !config.dry_run {
+                Some(commands::Commands::new(&config).with_audit(store.audit()?))
+            } else {
+                None
+            };
+            let mut notifier: Box<dyn Notifier> = if config.dry_run {
+                Box::new(notifier::DryRun)
+            } else {
+                let mut notifiers: Vec<Box<dyn Notifier>> =
+                    vec![Box::new(telegram::Bots::new(&config))];
+                for webhook in &config.webhooks {
+                    println!("Sending events to webhook {}", webhook.url);
+                    notifiers.push(Box::new(webhook::Webhook::start(
+                        webhook,
+                        &config.storage.path,
+                    )?));
+                }
+                Box::new(notifier::Fanout(notifiers))
+            };
+            let lease = match &config.leader {
+                Some(leader) => {
+                    println!("Running as replica {}, only the leader notifies", leader.id);
+                    let lease = Arc::new(leader::Lease::new(leader, &config.storage.path)?);
+                    notifier = Box::new(leader::LeaderOnly::new(lease.clone(), notifier));
+                    Some(lease)
+                }
+                None => None,
+            };
+            let pollers = std::mem::take(&mut config.pollers);
+            let leases = config.leases.take();
+            let mut cross_checks: Vec<Box<dyn CrossCheck>> = Vec::new();
+            for poller in pollers.iter().filter(|poller| poller.cross_check) {
+                println!("Cross-checking departures with {}", poller.url);
+                cross_checks.push(Box::new(poller::CrossCheck::new(poller)));
+            }
+            if let Some(leases) = leases.as_ref().filter(|leases| leases.cross_check) {
+                println!("Cross-checking departures with {}", leases.path.display());
+                cross_checks.push(Box::new(leases::CrossCheck::new(leases)));
+            }
+            let paced = socket
+                .clone()
+                .map(|socket| pacing::Paced::start(socket, &config.keepalive));
+            let mut houserat = HouseRat {
+                interface_name: config.interface.name.clone(),
+                devices: std::mem::take(&mut config.devices),
+                lease,
+                pollers,
+                leases,
+                #[cfg(all(feature = "ble", target_os = "linux"))]
+                ble_adapter: config.ble.adapter,
+                beacons: std::mem::take(&mut config.beacons),
+                commands,
+                api: config.api.take(),
+                dbus: config.dbus.take(),
+                mdns: config.mdns,
+                telegram: telegram::Client::new(&config.bot_token),
+                admin_chat_id: config.admin_chat_id,
+                dry_run: config.dry_run,
+                socket,
+                addresses: config.interface.addresses.clone(),
+                capture: config.capture.clone(),
+                capture_filter: network::capture_filter(
+                    &Default::default(),
+                    config.capture.filter.as_deref(),
+                ),
+                filter_generation: 0,
+                filter_updates: None,
+                keepalives: paced.as_ref().map(pacing::Paced::sent),
+                vacation_toggles,
+                stops,
+                alerts: config.alerts.clone(),
+                tracker: cross_checks.into_iter().fold(
+                    match paced {
+                        Some(paced) => {
+                            PresenceTracker::new(config, notifier).with_prober(Box::new(paced))
+                        }
+                        None => PresenceTracker::new(config, notifier),
+                    }
+                    .with_store(store),
+                    PresenceTracker::with_cross_check,
+                ),
+                clock: Box::new(SystemClock),
+            };
+            if let Some(output) = output {
+                houserat.tracker.set_output(output);
+            }
+            houserat.run()
+        }
+        Command::Replay { file, speed } => {
+            let config = config::Config::from_file(opt.config_file)?;
+            let replay = replay::Replay::open(&file)?;
 
-    println!("Listening on interface {}...", config.interface.name);
+            println!("Replaying {}...", file.display());
 
-    let mut houserat = HouseRat::new(config)?;
-    houserat.run()
+            let mut tracker = PresenceTracker::new(config, Box::new(notifier::DryRun));
+            replay::run(&mut tracker, replay, speed)
+        }
+        Command::Simulate {
+            config: candidate,
+            history,
+        } => {
+            let mut current = config::Config::from_file(opt.config_file)?;
+            let mut candidate = config::Config::from_file(candidate)?;
+            let since = chrono::Local::now()
+                - chrono::Duration::from_std(history)
+                    .map_err(|_e| error::Error::InvalidDuration { value: history })?;
+            let store = store::open(&current.storage)?;
+            let archived = store.load_state()?.archived;
+            current.archive(&archived);
+            candidate.archive(&archived);
+            let entries = store.history(since)?;
+            let audit: Vec<_> = store
+                .audit()?
+                .into_iter()
+                .filter(|audited| audited.timestamp >= since)
+                .collect();
+
+            println!(
+                "Simulating {} events since {}",
+                entries.len(),
+                since.to_rfc2822()
+            );
+            simulate::print_comparison(
+                &simulate::simulate(current, &entries, &audit),
+                &simulate::simulate(candidate, &entries, &audit),
+            );
+            Ok(())
+        }
+        Command::CheckConfig => {
+            let config = config::Config::from_file(opt.config_file)?;
+            check_config(&config);
+            println!("Config OK");
+            Ok(())
+        }
+        Command::ListInterfaces => {
+            list_interfaces();
+            Ok(())
+        }
+        Command::Schema => {
+            let schema = serde_json::to_string_pretty(&config::schema())
+                .expect("Failed to serialize schema");
+            println!("{}", schema);
+            Ok(())
+        }
+        Command::SendTest { user } => {
+            let config = config::Config::from_file(opt.config_file)?;
+            send_test(&config, &user)
+        }
+        Command::Backup { file } => {
+            let config = config::Config::from_file(&opt.config_file)?;
+            let store = store::open(&config.storage)?;
+            let backup = backup::Backup::create(&opt.config_file, store.as_ref())?;
+            backup.write(&file)?;
+            println!(
+                "Backed up config, state and {} history entries to {}",
+                backup.history.len(),
+                file.display()
+            );
+            Ok(())
+        }
+        Command::Restore { file, force } => {
+            backup::Backup::read(&file)?.restore(&opt.config_file, force)
+        }
+        Command::Archive { user } => {
+            let config = config::Config::from_file(opt.config_file)?;
+            set_archived(&config, &user, true)
+        }
+        Command::Unarchive { user } => {
+            let config = config::Config::from_file(opt.config_file)?;
+            set_archived(&config, &user, false)
+        }
+        Command::Map { format, vendors } => {
+            let mut config = config::Config::from_file(opt.config_file)?;
+            let state = store::open(&config.storage)?.load_state()?;
+            config.archive(&state.archived);
+            let vendors = inventory::Vendors::load(vendors.as_deref());
+            print!(
+                "{}",
+                inventory::map(&state.inventory, &config, &vendors, format)
+            );
+            Ok(())
+        }
+    }
 }
 
 fn main() {
     if let Err(err) = run() {
-        eprintln!("Error: {}", err);
+        eprintln!("Error: {}", err.report());
         std::process::exit(1);
     }
 }
@@ -0,0 +1,303 @@
+use crate::network::{Event, MDNS_PORT};
+use crossbeam_channel::Sender;
+use pnet::util::MacAddr;
+use std::collections::BTreeSet;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+/// Class IN with the top bit set, asking for a unicast response
+const CLASS_IN_UNICAST: u16 = 0x8001;
+const SERVICES: &str = "_services._dns-sd._udp.local";
+const DEVICE_INFO: &str = "_device-info._tcp.local";
+
+/// Friendly names of service types worth mentioning, the rest are left out.
+const FRIENDLY_SERVICES: &[(&str, &str)] = &[
+    ("_airplay._tcp.local", "AirPlay"),
+    ("_raop._tcp.local", "AirPlay"),
+    ("_companion-link._tcp.local", "Handoff"),
+    ("_googlecast._tcp.local", "Chromecast"),
+    ("_spotify-connect._tcp.local", "Spotify Connect"),
+    ("_hap._tcp.local", "HomeKit"),
+    ("_ipp._tcp.local", "Printer"),
+    ("_smb._tcp.local", "File Sharing"),
+    ("_ssh._tcp.local", "SSH"),
+];
+
+#[derive(Debug, PartialEq)]
+enum Data {
+    Ptr(String),
+    Txt(Vec<String>),
+    Other,
+}
+
+#[derive(Debug, PartialEq)]
+struct Record {
+    name: String,
+    data: Data,
+}
+
+fn encode_query(names: &[&str]) -> Vec<u8> {
+    let mut packet = vec![0, 0, 0, 0, 0, names.len() as u8, 0, 0, 0, 0, 0, 0];
+    packet[4] = (names.len() >> 8) as u8;
+    for name in names {
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN_UNICAST.to_be_bytes());
+    }
+    packet
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    packet
+        .get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// Reads a possibly compressed name, returning it and the offset just past it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // bound the number of pointers followed, so a malicious loop can't hang us
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        } else if len & 0xc0 == 0xc0 {
+            end.get_or_insert(offset + 2);
+            offset = (read_u16(packet, offset)? & 0x3fff) as usize;
+        } else {
+            let label = packet.get(offset + 1..offset + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + len;
+        }
+    }
+    None
+}
+
+fn parse_records(packet: &[u8]) -> Vec<Record> {
+    let mut records = Vec::new();
+    let count = |i| read_u16(packet, i).unwrap_or(0) as usize;
+    let (questions, answers) = (count(4), count(6) + count(8) + count(10));
+    let mut offset = 12;
+    for _ in 0..questions {
+        match read_name(packet, offset) {
+            Some((_, end)) => offset = end + 4,
+            None => return records,
+        }
+    }
+    for _ in 0..answers {
+        let (name, end) = match read_name(packet, offset) {
+            Some(name) => name,
+            None => break,
+        };
+        let (record_type, len) = match (read_u16(packet, end), read_u16(packet, end + 8)) {
+            (Some(record_type), Some(len)) => (record_type, len as usize),
+            _ => break,
+        };
+        let start = end + 10;
+        let rdata = match packet.get(start..start + len) {
+            Some(rdata) => rdata,
+            None => break,
+        };
+        let data = match record_type {
+            TYPE_PTR => match read_name(packet, start) {
+                Some((target, _)) => Data::Ptr(target),
+                None => Data::Other,
+            },
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut i = 0;
+                while let Some(&len) = rdata.get(i) {
+                    let string = rdata.get(i + 1..i + 1 + len as usize).unwrap_or_default();
+                    strings.push(String::from_utf8_lossy(string).into_owned());
+                    i += 1 + len as usize;
+                }
+                Data::Txt(strings)
+            }
+            _ => Data::Other,
+        };
+        records.push(Record { name, data });
+        offset = start + len;
+    }
+    records
+}
+
+/// Sends a query to a device and collects the records in its responses until the timeout.
+fn query(ip: Ipv4Addr, names: &[&str]) -> std::io::Result<Vec<Record>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.send_to(&encode_query(names), (ip, MDNS_PORT))?;
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let mut records = Vec::new();
+    let mut buffer = [0u8; 9000];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Ok(records);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv_from(&mut buffer) {
+            Ok((len, from)) if from.ip() == ip => records.extend(parse_records(&buffer[..len])),
+            Ok(_) => (),
+            Err(ref e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                return Ok(records)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Builds a description such as "Alice's iPhone (AirPlay, Handoff)" from the device's service
+/// instance names, `_device-info` model and the friendly names of its service types.
+fn describe(records: &[Record]) -> Option<String> {
+    let friendly = |service: &str| {
+        FRIENDLY_SERVICES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(service))
+            .map(|(_, friendly)| *friendly)
+    };
+    let mut services = BTreeSet::new();
+    let mut name = None;
+    let mut model = None;
+    for record in records {
+        match &record.data {
+            Data::Ptr(target) if record.name.eq_ignore_ascii_case(SERVICES) => {
+                services.extend(friendly(target));
+            }
+            Data::Ptr(target) if friendly(&record.name).is_some() => {
+                let instance = target
+                    .len()
+                    .checked_sub(record.name.len() + 1)
+                    .and_then(|len| target.get(..len));
+                if let Some(instance) = instance {
+                    name = name.or_else(|| Some(instance.to_string()));
+                }
+                services.extend(friendly(&record.name));
+            }
+            Data::Txt(strings) if record.name.to_lowercase().ends_with(DEVICE_INFO) => {
+                model = model.or_else(|| {
+                    strings
+                        .iter()
+                        .find_map(|s| s.strip_prefix("model="))
+                        .map(String::from)
+                });
+            }
+            _ => (),
+        }
+    }
+    let services: Vec<&str> = services.into_iter().collect();
+    match (name.or(model), services.is_empty()) {
+        (Some(name), true) => Some(name),
+        (Some(name), false) => Some(format!("{} ({})", name, services.join(", "))),
+        (None, false) => Some(services.join(", ")),
+        (None, true) => None,
+    }
+}
+
+/// Describes devices from their mDNS services, e.g. for arrival notifications.
+pub trait Describer {
+    /// Starts describing a device, the description arriving later as `Event::Described`.
+    fn describe(&self, mac: MacAddr, ip: Ipv4Addr);
+}
+
+/// Queries devices over unicast mDNS in a thread of its own, first for the service types they
+/// offer and then for the instances of those, which are usually named after the device.
+pub struct Mdns {
+    queries: Sender<(MacAddr, Ipv4Addr)>,
+}
+
+impl Mdns {
+    pub fn start(events: Sender<Event>) -> Mdns {
+        let (queries, queries_r) = crossbeam_channel::unbounded::<(MacAddr, Ipv4Addr)>();
+        std::thread::spawn(move || {
+            for (mac, ip) in queries_r {
+                let records = query(ip, &[SERVICES, DEVICE_INFO]).and_then(|mut records| {
+                    let types: Vec<String> = records
+                        .iter()
+                        .filter_map(|record| match &record.data {
+                            Data::Ptr(target) if record.name.eq_ignore_ascii_case(SERVICES) => {
+                                Some(target.clone())
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    if !types.is_empty() {
+                        let types: Vec<&str> = types.iter().map(String::as_str).collect();
+                        records.extend(query(ip, &types)?);
+                    }
+                    Ok(records)
+                });
+                let description = match records {
+                    Ok(records) => describe(&records),
+                    Err(e) => {
+                        println!("Failed to query mDNS of {} ({}): {}", ip, mac, e);
+                        continue;
+                    }
+                };
+                if let Some(description) = description {
+                    println!("Described {} ({}) as {}", ip, mac, description);
+                    if let Err(e) = events.send(Event::Described { mac, description }) {
+                        println!("Failed to send event, exiting: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        Mdns { queries }
+    }
+}
+
+impl Describer for Mdns {
+    fn describe(&self, mac: MacAddr, ip: Ipv4Addr) {
+        if let Err(e) = self.queries.send((mac, ip)) {
+            println!("Failed to queue mDNS query: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        // a response to our own query, reusing its name for an answer instead of a question
+        let mut packet = encode_query(&["_airplay._tcp.local"]);
+        packet[5] = 0;
+        packet[7] = 1;
+        packet.truncate(packet.len() - 4);
+        packet.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0x11, 0x94, 0, 17, 14]);
+        packet.extend_from_slice(b"Alice's iPhone");
+        packet.extend_from_slice(&[0xc0, 12]);
+
+        let records = parse_records(&packet);
+        assert_eq!(
+            records,
+            vec![Record {
+                name: "_airplay._tcp.local".into(),
+                data: Data::Ptr("Alice's iPhone._airplay._tcp.local".into()),
+            }]
+        );
+        assert_eq!(
+            describe(&records).as_deref(),
+            Some("Alice's iPhone (AirPlay)")
+        );
+        assert_eq!(
+            describe(&[Record {
+                name: SERVICES.into(),
+                data: Data::Ptr("_companion-link._tcp.local".into()),
+            }])
+            .as_deref(),
+            Some("Handoff")
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::telegram::ParseMode;
+use crate::Identity;
 use chrono::{offset::Local, DateTime, Duration};
 use lazy_static::lazy_static;
 
@@ -11,8 +13,15 @@ pub struct Metadata {
     pub icon: Option<String>,
     pub username: Option<String>,
     pub subscriber_name: String,
-    pub chat_id: i64,
-    last_notified: Option<DateTime<Local>>,
+    /// Where the subscriber is notified
+    pub identity: Identity,
+    pub ping: bool,
+    /// Whether to alert the admin when the device's DHCP hostname changes
+    pub hostname_alert: bool,
+    /// Whether to alert the admin when the device goes offline and when it's back
+    pub always_online: bool,
+    pub reminder: Option<crate::config::Reminder>,
+    pub(crate) last_notified: Option<DateTime<Local>>,
 }
 
 impl Metadata {
@@ -21,14 +30,18 @@ impl Metadata {
         icon: Option<String>,
         username: Option<String>,
         subscriber_name: String,
-        chat_id: i64,
+        identity: Identity,
     ) -> Self {
         Self {
             name,
             icon,
             username,
             subscriber_name,
-            chat_id,
+            identity,
+            ping: false,
+            hostname_alert: false,
+            always_online: false,
+            reminder: None,
             last_notified: None,
         }
     }
@@ -54,23 +67,25 @@ impl Metadata {
             }
         }
     }
+
+    /// The icon and name, linked to the user's Telegram profile when there's a username.
+    pub fn mention(&self, parse_mode: ParseMode) -> String {
+        let text = self.to_string();
+        match &self.username {
+            Some(username) => parse_mode.link(&text, &format!("https://t.me/{}", username)),
+            None => parse_mode.escape(&text),
+        }
+    }
 }
 
 impl std::fmt::Display for Metadata {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if self.username.is_some() {
-            write!(f, "[")?;
-        }
         write!(
             f,
             "{} {}",
             self.icon.as_ref().unwrap_or(&*DEFAULT_ICON),
             self.name
-        )?;
-        if let Some(username) = &self.username {
-            write!(f, "](t.me/{})", username)?;
-        }
-        Ok(())
+        )
     }
 }
 
@@ -80,7 +95,13 @@ mod tests {
 
     #[test]
     fn test_no_cooldown() {
-        let mut notification = Metadata::new("".to_string(), None, None, "".to_string(), 0);
+        let mut notification = Metadata::new(
+            "".to_string(),
+            None,
+            None,
+            "".to_string(),
+            Identity::default(),
+        );
         let now = Local::now();
         assert!(notification.should_notify(&None, now));
         assert!(notification.should_notify(&None, now + Duration::seconds(1)))
@@ -88,7 +109,13 @@ mod tests {
 
     #[test]
     fn test_cooldown() {
-        let mut notification = Metadata::new("".to_string(), None, None, "".to_string(), 0);
+        let mut notification = Metadata::new(
+            "".to_string(),
+            None,
+            None,
+            "".to_string(),
+            Identity::default(),
+        );
         let cooldown = Some(Duration::seconds(5));
         let now = Local::now();
         assert!(notification.should_notify(&cooldown, now));
@@ -98,4 +125,20 @@ mod tests {
         assert!(!notification.should_notify(&cooldown, now + Duration::seconds(9)));
         assert!(notification.should_notify(&cooldown, now + Duration::seconds(10)));
     }
+
+    #[test]
+    fn test_mention() {
+        let metadata = Metadata::new(
+            "Mr_T".to_string(),
+            Some("🚐".to_string()),
+            Some("mr_t".to_string()),
+            "".to_string(),
+            Identity::default(),
+        );
+        assert_eq!(
+            metadata.mention(ParseMode::MarkdownV2),
+            r"[🚐 Mr\_T](https://t.me/mr_t)"
+        );
+        assert_eq!(metadata.to_string(), "🚐 Mr_T");
+    }
 }
@@ -1,23 +1,64 @@
 use crate::config::NetworkAddresses;
 use pnet::{
+    datalink::{self, DataLinkSender},
     packet::{
         arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket},
         ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        icmp::{echo_request::MutableEchoRequestPacket, IcmpPacket, IcmpTypes},
         ip::IpNextHeaderProtocols,
-        ipv4::Ipv4Packet,
+        ipv4::{Ipv4Packet, MutableIpv4Packet},
         udp::UdpPacket,
+        vlan::VlanPacket,
         MutablePacket, Packet,
     },
     util::MacAddr,
 };
 use snafu::ResultExt;
-use std::convert::TryInto;
+use std::collections::BTreeSet;
 use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const MDNS_PORT: u16 = 5353;
+const SSDP_PORT: u16 = 1900;
+/// Where options start in a DHCP message, after the fixed BOOTP fields and the magic cookie
+const DHCP_OPTIONS_OFFSET: usize = 240;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTION_HOSTNAME: u8 = 12;
+const DHCP_OPTION_END: u8 = 255;
+/// Smallest snaplen that captures a whole ARP packet in an 802.1Q tagged frame
+pub const MIN_SNAPLEN: i32 = 14 + 4 + 28;
 
 pub enum Event {
     Ignored,
-    Connected(MacAddr),
-    Alive { mac: MacAddr, ip: Ipv4Addr },
+    Connected {
+        mac: MacAddr,
+        ip: Option<Ipv4Addr>,
+        hostname: Option<String>,
+    },
+    Alive {
+        mac: MacAddr,
+        ip: Ipv4Addr,
+    },
+    /// A multicast DNS or SSDP announcement, a sign of life of devices that rarely ARP
+    Announced {
+        mac: MacAddr,
+        ip: Ipv4Addr,
+    },
+    /// A Bluetooth LE advertisement, identified by the beacon's identity address
+    Beacon {
+        mac: MacAddr,
+    },
+    /// A friendly description of a device, e.g. from its mDNS services
+    Described {
+        mac: MacAddr,
+        description: String,
+    },
+    /// A keepalive answered after `rtt`, or unanswered with `None`
+    RoundTrip {
+        mac: MacAddr,
+        rtt: Option<Duration>,
+    },
 }
 
 macro_rules! try_event {
@@ -35,27 +76,157 @@ macro_rules! try_event {
 }
 
 pub fn parse_packet(data: &[u8]) -> Event {
-    let ethernet = EthernetPacket::new(data).unwrap();
+    let ethernet = try_event!(EthernetPacket::new(data));
+    let source = ethernet.get_source();
     match ethernet.get_ethertype() {
-        EtherTypes::Ipv4 => parse_ipv4_packet(&ethernet),
-        EtherTypes::Arp => parse_arp_packet(&ethernet),
+        EtherTypes::Ipv4 => parse_ipv4_packet(source, ethernet.payload()),
+        EtherTypes::Arp => parse_arp_packet(ethernet.payload()),
+        EtherTypes::Vlan => {
+            let vlan = try_event!(VlanPacket::new(ethernet.payload()));
+            match vlan.get_ethertype() {
+                EtherTypes::Ipv4 => parse_ipv4_packet(source, vlan.payload()),
+                EtherTypes::Arp => parse_arp_packet(vlan.payload()),
+                _ => Event::Ignored,
+            }
+        }
         _ => Event::Ignored,
     }
 }
 
-fn parse_ipv4_packet(ethernet: &EthernetPacket) -> Event {
-    let header = try_event!(Ipv4Packet::new(ethernet.payload()));
-    if let IpNextHeaderProtocols::Udp = header.get_next_level_protocol() {
-        let udp = try_event!(UdpPacket::new(header.payload()));
-        if udp.get_source() == 68 && udp.get_destination() == 67 {
-            return Event::Connected(ethernet.get_source());
+/// The BPF capture filter, taking in echo replies only from the devices being pinged, so it must be
+/// rebuilt as they come and go. An `extra` expression from the config is or'ed with it, for tagged
+/// frames too.
+pub fn capture_filter(pinged: &BTreeSet<MacAddr>, extra: Option<&str>) -> String {
+    let mut filter = "arp or (udp and (port bootpc or dst port 5353 or dst port 1900))".to_string();
+    if !pinged.is_empty() {
+        let sources: Vec<String> = pinged
+            .iter()
+            .map(|mac| format!("ether src {}", mac))
+            .collect();
+        filter.push_str(&format!(
+            " or (icmp[icmptype] == icmp-echoreply and ({}))",
+            sources.join(" or ")
+        ));
+    }
+    if let Some(extra) = extra {
+        filter.push_str(&format!(" or ({})", extra));
+    }
+    // the same again for 802.1Q tagged frames, since `vlan` shifts the offsets of everything after
+    // it, so nothing can follow this clause
+    format!("{} or (vlan and ({}))", filter, filter)
+}
+
+/// The source of an ARP frame, tagged or not, without parsing it any further.
+pub fn arp_source(data: &[u8]) -> Option<MacAddr> {
+    let ethernet = EthernetPacket::new(data)?;
+    let ethertype = match ethernet.get_ethertype() {
+        EtherTypes::Vlan => VlanPacket::new(ethernet.payload())?.get_ethertype(),
+        ethertype => ethertype,
+    };
+    Some(ethernet.get_source()).filter(|_| ethertype == EtherTypes::Arp)
+}
+
+/// The sender of an ARP reply frame, tagged or not, e.g. a device answering a keepalive.
+pub fn arp_reply(data: &[u8]) -> Option<MacAddr> {
+    fn sender(payload: &[u8]) -> Option<MacAddr> {
+        let arp = ArpPacket::new(payload)?;
+        Some(arp.get_sender_hw_addr()).filter(|_| arp.get_operation() == ArpOperations::Reply)
+    }
+    let ethernet = EthernetPacket::new(data)?;
+    match ethernet.get_ethertype() {
+        EtherTypes::Arp => sender(ethernet.payload()),
+        EtherTypes::Vlan => {
+            let vlan = VlanPacket::new(ethernet.payload())?;
+            match vlan.get_ethertype() {
+                EtherTypes::Arp => sender(vlan.payload()),
+                _ => None,
+            }
         }
+        _ => None,
+    }
+}
+
+/// The 802.1Q VLAN ID of a frame, if tagged.
+pub fn vlan_id(data: &[u8]) -> Option<u16> {
+    let ethernet = EthernetPacket::new(data)?;
+    if ethernet.get_ethertype() != EtherTypes::Vlan {
+        return None;
+    }
+    VlanPacket::new(ethernet.payload()).map(|vlan| vlan.get_vlan_identifier())
+}
+
+/// The hostname a client sent in a DHCP message, from option 12.
+fn dhcp_hostname(message: &[u8]) -> Option<String> {
+    if message.get(DHCP_OPTIONS_OFFSET - 4..DHCP_OPTIONS_OFFSET)? != DHCP_MAGIC_COOKIE {
+        return None;
+    }
+    let mut options = &message[DHCP_OPTIONS_OFFSET..];
+    loop {
+        match *options.first()? {
+            0 => options = &options[1..],
+            DHCP_OPTION_END => return None,
+            code => {
+                let len = *options.get(1)? as usize;
+                let data = options.get(2..2 + len)?;
+                if code == DHCP_OPTION_HOSTNAME {
+                    return Some(String::from_utf8_lossy(data).into_owned())
+                        .filter(|h| !h.is_empty());
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+}
+
+fn parse_ipv4_packet(source: MacAddr, payload: &[u8]) -> Event {
+    let header = try_event!(Ipv4Packet::new(payload));
+    match header.get_next_level_protocol() {
+        IpNextHeaderProtocols::Udp => {
+            let udp = try_event!(UdpPacket::new(header.payload()));
+            if udp.get_source() == 68 && udp.get_destination() == 67 {
+                return Event::Connected {
+                    mac: source,
+                    ip: None,
+                    hostname: dhcp_hostname(udp.payload()),
+                };
+            }
+            let ip = header.get_source();
+            // link-local addresses are useless for keepalives, and unspecified ones while joining
+            if ip.is_unspecified() || ip.is_link_local() {
+                return Event::Ignored;
+            }
+            let announced = match udp.get_destination() {
+                MDNS_PORT => true,
+                // a device saying goodbye is no sign of life
+                SSDP_PORT => !contains(udp.payload(), b"ssdp:byebye"),
+                _ => false,
+            };
+            if announced {
+                return Event::Announced { mac: source, ip };
+            }
+        }
+        IpNextHeaderProtocols::Icmp => {
+            let icmp = try_event!(IcmpPacket::new(header.payload()));
+            if icmp.get_icmp_type() == IcmpTypes::EchoReply {
+                return Event::Alive {
+                    mac: source,
+                    ip: header.get_source(),
+                };
+            }
+        }
+        _ => (),
     }
     Event::Ignored
 }
 
-fn parse_arp_packet(ethernet: &EthernetPacket) -> Event {
-    let header = try_event!(ArpPacket::new(ethernet.payload()));
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window.eq_ignore_ascii_case(needle))
+}
+
+fn parse_arp_packet(payload: &[u8]) -> Event {
+    let header = try_event!(ArpPacket::new(payload));
     let op = header.get_operation();
     if (op == ArpOperations::Request
         && header.get_sender_proto_addr() == header.get_target_proto_addr())
@@ -69,35 +240,49 @@ fn parse_arp_packet(ethernet: &EthernetPacket) -> Event {
     Event::Ignored
 }
 
+/// Sends Ethernet frames on an interface through pnet's datalink channel, e.g. an `AF_PACKET`
+/// socket on Linux or BPF on the BSDs and macOS. It never puts the interface in promiscuous mode,
+/// that's up to `capture.promisc`, though the kernel still queues received frames for it until its
+/// receive buffer is full since nothing reads them.
 pub struct Socket {
-    socket: socket2::Socket,
-    address: socket2::SockAddr,
+    sender: Mutex<Box<dyn DataLinkSender>>,
 }
 
 impl Socket {
     pub fn new(interface_index: u32) -> crate::Result<Socket> {
-        Ok(Socket {
-            socket: socket2::Socket::new(
-                libc::AF_PACKET.into(),
-                socket2::Type::raw(),
-                Some(libc::ETH_P_ALL.to_be().into()),
-            )
-            .with_context(|| crate::error::SendError)?,
-            address: unsafe {
-                let mut addr: libc::sockaddr_ll = std::mem::zeroed();
-                addr.sll_family = libc::AF_PACKET.try_into().unwrap();
-                addr.sll_ifindex = interface_index.try_into().unwrap();
-                addr.sll_halen = 6;
-                addr.sll_protocol = (libc::ETH_P_ARP as u16).to_be();
-                socket2::SockAddr::from_raw_parts(
-                    (&addr as *const libc::sockaddr_ll) as *const _,
-                    std::mem::size_of::<libc::sockaddr_ll>().try_into().unwrap(),
-                )
-            },
-        })
-    }
-
-    pub fn send_arp_request(
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.index == interface_index)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+            .with_context(|| crate::error::SendError)?;
+        // nothing is read from the channel, so it only needs a small buffer
+        let config = datalink::Config {
+            read_buffer_size: 64,
+            promiscuous: false,
+            ..Default::default()
+        };
+        match datalink::channel(&interface, config).with_context(|| crate::error::SendError)? {
+            datalink::Channel::Ethernet(sender, _) => Ok(Socket {
+                sender: Mutex::new(sender),
+            }),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "not an Ethernet channel",
+            ))
+            .with_context(|| crate::error::SendError),
+        }
+    }
+
+    fn send(&self, frame: &[u8]) -> crate::Result<()> {
+        self.sender
+            .lock()
+            .unwrap()
+            .send_to(frame, None)
+            .unwrap_or_else(|| Err(std::io::ErrorKind::WriteZero.into()))
+            .with_context(|| crate::error::SendError)
+    }
+
+    fn send_arp_request(
         &self,
         us: &NetworkAddresses,
         them: &NetworkAddresses,
@@ -121,10 +306,168 @@ impl Socket {
         arp.set_target_hw_addr(them.mac);
         arp.set_target_proto_addr(them.ip);
 
-        self.socket
-            .send_to(ethernet.packet(), &self.address)
-            .with_context(|| crate::error::SendError)?;
+        self.send(ethernet.packet())
+    }
+
+    fn send_echo_request(
+        &self,
+        us: &NetworkAddresses,
+        them: &NetworkAddresses,
+    ) -> crate::Result<()> {
+        let mut buffer = [0u8; 42];
+        let mut ethernet = MutableEthernetPacket::new(&mut buffer).unwrap();
+
+        ethernet.set_destination(them.mac);
+        ethernet.set_source(us.mac);
+        ethernet.set_ethertype(EtherTypes::Ipv4);
+
+        let mut ipv4 = MutableIpv4Packet::new(ethernet.payload_mut()).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(28);
+        ipv4.set_ttl(64);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        ipv4.set_source(us.ip);
+        ipv4.set_destination(them.ip);
+        ipv4.set_checksum(pnet::packet::ipv4::checksum(&ipv4.to_immutable()));
+
+        let mut echo = MutableEchoRequestPacket::new(ipv4.payload_mut()).unwrap();
+        echo.set_icmp_type(IcmpTypes::EchoRequest);
+        echo.set_identifier(std::process::id() as u16);
+        let checksum = pnet::packet::icmp::checksum(&IcmpPacket::new(echo.packet()).unwrap());
+        echo.set_checksum(checksum);
+
+        self.send(ethernet.packet())
+    }
+
+    /// Broadcasts a Wake-on-LAN magic packet, 6 bytes of `0xff` followed by the MAC 16 times.
+    pub fn send_wol(&self, us: &NetworkAddresses, mac: MacAddr) -> crate::Result<()> {
+        let mut buffer = [0u8; 116];
+        let mut ethernet = MutableEthernetPacket::new(&mut buffer).unwrap();
+
+        ethernet.set_destination(MacAddr::broadcast());
+        ethernet.set_source(us.mac);
+        ethernet.set_ethertype(EtherTypes::WakeOnLan);
+
+        let payload = ethernet.payload_mut();
+        payload[..6].copy_from_slice(&[0xff; 6]);
+        let MacAddr(a, b, c, d, e, f) = mac;
+        for target in payload[6..].chunks_mut(6) {
+            target.copy_from_slice(&[a, b, c, d, e, f]);
+        }
+
+        self.send(ethernet.packet())
+    }
+}
+
+impl crate::tracker::Prober for Socket {
+    fn probe(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()> {
+        self.send_arp_request(us, them)
+    }
+
+    fn ping(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()> {
+        self.send_echo_request(us, them)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_filter() {
+        let mut pinged = BTreeSet::new();
+        let udp = "udp and (port bootpc or dst port 5353 or dst port 1900)";
+        assert_eq!(
+            capture_filter(&pinged, None),
+            format!("arp or ({}) or (vlan and (arp or ({})))", udp, udp)
+        );
+        // inside the vlan clause as well, as it would otherwise match tagged frames only
+        assert_eq!(
+            capture_filter(&pinged, Some("tcp port 62078")),
+            format!(
+                "arp or ({}) or (tcp port 62078) or (vlan and (arp or ({}) or (tcp port 62078)))",
+                udp, udp
+            )
+        );
+        pinged.insert(MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55));
+        pinged.insert(MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x56));
+        assert!(capture_filter(&pinged, None).starts_with(
+            "arp or (udp and (port bootpc or dst port 5353 or dst port 1900)) or \
+             (icmp[icmptype] == icmp-echoreply and \
+             (ether src 00:11:22:33:44:55 or ether src 00:11:22:33:44:56)) or (vlan and ("
+        ));
+    }
+
+    #[test]
+    fn test_announcement() {
+        let mac = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let udp = |source: [u8; 4], port: u16, payload: &[u8]| {
+            let len = 28 + payload.len() as u16;
+            let mut packet = vec![
+                0x45,
+                0,
+                (len >> 8) as u8,
+                len as u8,
+                0,
+                0,
+                0,
+                0,
+                1,
+                17,
+                0,
+                0,
+            ];
+            packet.extend_from_slice(&source);
+            packet.extend_from_slice(&[239, 255, 255, 250, 0x07, 0x6c]);
+            packet.extend_from_slice(&port.to_be_bytes());
+            packet.extend_from_slice(&(len - 20).to_be_bytes());
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(payload);
+            parse_ipv4_packet(mac, &packet)
+        };
+
+        let alive = b"NOTIFY * HTTP/1.1\r\nNTS: ssdp:alive\r\n\r\n";
+        assert!(matches!(
+            udp([192, 168, 1, 10], SSDP_PORT, alive),
+            Event::Announced { ip, .. } if ip == Ipv4Addr::new(192, 168, 1, 10)
+        ));
+        assert!(matches!(
+            udp([192, 168, 1, 10], MDNS_PORT, &[0; 12]),
+            Event::Announced { .. }
+        ));
+        let byebye = b"NOTIFY * HTTP/1.1\r\nNTS: ssdp:byebye\r\n\r\n";
+        assert!(matches!(
+            udp([192, 168, 1, 10], SSDP_PORT, byebye),
+            Event::Ignored
+        ));
+        assert!(matches!(
+            udp([169, 254, 1, 10], SSDP_PORT, alive),
+            Event::Ignored
+        ));
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert!(matches!(parse_packet(&[0xff; 10]), Event::Ignored));
+        // an ARP frame cut off after the Ethernet header by a small snaplen
+        let mut frame = vec![0xff; 12];
+        frame.extend_from_slice(&[0x08, 0x06]);
+        assert!(matches!(parse_packet(&frame), Event::Ignored));
+    }
+
+    #[test]
+    fn test_dhcp_hostname() {
+        let mut message = vec![0u8; DHCP_OPTIONS_OFFSET - 4];
+        message.extend_from_slice(&DHCP_MAGIC_COOKIE);
+        message.extend_from_slice(&[53, 1, 3, 0, DHCP_OPTION_HOSTNAME, 6]);
+        message.extend_from_slice(b"iPhone");
+        message.push(DHCP_OPTION_END);
+        assert_eq!(dhcp_hostname(&message).as_deref(), Some("iPhone"));
 
-        Ok(())
+        message.truncate(DHCP_OPTIONS_OFFSET + 4);
+        message.push(DHCP_OPTION_END);
+        assert_eq!(dhcp_hostname(&message), None);
+        assert_eq!(dhcp_hostname(&message[..100]), None);
     }
 }
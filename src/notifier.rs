@@ -0,0 +1,69 @@
+use crate::tracker::Status;
+use chrono::{DateTime, Local};
+use pnet::util::MacAddr;
+
+/// An ID unique to an event, from when it's dequeued, the instance dequeuing it and the IDs minted
+/// before it since startup, so it can be followed through logs, retries and backends.
+pub fn correlation_id(now: DateTime<Local>, instance: &str, sequence: u64) -> String {
+    format!("{:x}-{}-{:x}", now.timestamp_millis(), instance, sequence)
+}
+
+/// The ID of the `nth` notification caused by the event with correlation ID `id`.
+pub fn notification_id(id: &str, nth: u32) -> String {
+    format!("{}-{}", id, nth)
+}
+
+#[derive(Debug)]
+pub struct Notification {
+    /// Appears in every log line, request and history entry about this notification
+    pub correlation_id: String,
+    pub mac: MacAddr,
+    pub name: String,
+    pub status: Status,
+    pub timestamp: DateTime<Local>,
+    pub subscriber_name: String,
+    pub identity: crate::Identity,
+    pub text: String,
+    pub quiet: bool,
+    /// A "still away"/"still home" reminder rather than an arrival or departure
+    pub reminder: bool,
+    /// A digest of the arrivals and departures held back once over the daily limit
+    pub digest: bool,
+}
+
+/// Delivers notifications to subscribers.
+pub trait Notifier {
+    fn notify(&self, notification: &Notification) -> crate::Result<()>;
+}
+
+/// Delivers notifications through several notifiers, e.g. Telegram and webhooks.
+pub struct Fanout(pub Vec<Box<dyn Notifier>>);
+
+impl Notifier for Fanout {
+    fn notify(&self, notification: &Notification) -> crate::Result<()> {
+        let mut result = Ok(());
+        for notifier in &self.0 {
+            if let Err(e) = notifier.notify(notification) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+/// Logs notifications instead of sending them.
+pub struct DryRun;
+
+impl Notifier for DryRun {
+    fn notify(&self, notification: &Notification) -> crate::Result<()> {
+        println!(
+            "[{}] Dry run, not sending to {} ({}, disable_notification: {}): {}",
+            notification.correlation_id,
+            notification.subscriber_name,
+            notification.identity,
+            notification.quiet,
+            notification.text
+        );
+        Ok(())
+    }
+}
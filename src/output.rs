@@ -0,0 +1,204 @@
+use crate::network::Event;
+use crate::Status;
+use chrono::{DateTime, Local};
+use crossbeam_channel::Sender;
+use pnet::util::MacAddr;
+use serde::Serialize;
+use snafu::ResultExt;
+use std::fs::File;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a slow socket client may block a write before it's dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Format of presence events on stdout, `text` leaving stdout to the logs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format '{}', expected text or json", s)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    mac: String,
+    ip: Option<Ipv4Addr>,
+    name: Option<&'a str>,
+    timestamp: DateTime<Local>,
+}
+
+fn kind(event: &Event) -> Option<(&'static str, MacAddr, Option<Ipv4Addr>)> {
+    match event {
+        Event::Connected { mac, ip, .. } => Some(("connected", *mac, *ip)),
+        Event::Alive { mac, ip } => Some(("alive", *mac, Some(*ip))),
+        Event::Announced { mac, ip } => Some(("announced", *mac, Some(*ip))),
+        Event::Beacon { mac } => Some(("beacon", *mac, None)),
+        Event::Described { .. } | Event::RoundTrip { .. } | Event::Ignored => None,
+    }
+}
+
+/// Emits one JSON object per line for every presence event, arrivals and departures included,
+/// for piping into other tools. Lines are written in a thread of their own.
+#[derive(Clone)]
+pub struct Output {
+    lines: Sender<String>,
+}
+
+impl Output {
+    /// Writes to stdout, moving the human-readable logs to stderr so the two don't mix.
+    pub fn stdout() -> crate::Result<Output> {
+        let target = || crate::error::OutputError {
+            target: "stdout".to_string(),
+        };
+        let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if fd < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(target);
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let (lines, lines_r) = crossbeam_channel::unbounded::<String>();
+        std::thread::spawn(move || {
+            for line in lines_r {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    println!("Failed to write event to stdout, exiting: {}", e);
+                    return;
+                }
+            }
+        });
+        Ok(Output { lines })
+    }
+
+    /// Listens on a Unix domain socket, writing to every client connected at the time.
+    pub fn socket(path: &Path) -> crate::Result<Output> {
+        let target = || crate::error::OutputError {
+            target: path.display().to_string(),
+        };
+        // left over from a previous run
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(target)
+            }
+            _ => (),
+        }
+        let listener = UnixListener::bind(path).with_context(target)?;
+        let clients: Arc<Mutex<Vec<UnixStream>>> = Arc::default();
+        let accepted = clients.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream.and_then(|stream| {
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    Ok(stream)
+                }) {
+                    Ok(stream) => accepted.lock().unwrap().push(stream),
+                    Err(e) => println!("Failed to accept event output client: {}", e),
+                }
+            }
+        });
+        let (lines, lines_r) = crossbeam_channel::unbounded::<String>();
+        std::thread::spawn(move || {
+            for line in lines_r {
+                clients
+                    .lock()
+                    .unwrap()
+                    .retain(|mut client| writeln!(client, "{}", line).is_ok());
+            }
+        });
+        Ok(Output { lines })
+    }
+
+    fn write(&self, record: &Record) {
+        let line = serde_json::to_string(record).expect("Failed to serialize event");
+        if let Err(e) = self.lines.send(line) {
+            println!("Failed to output event: {}", e);
+        }
+    }
+
+    /// Outputs an event from the network, named after its user if the device is known.
+    pub fn event(&self, event: &Event, name: Option<&str>, now: DateTime<Local>) {
+        if let Some((kind, mac, ip)) = kind(event) {
+            self.write(&Record {
+                kind,
+                mac: mac.to_string(),
+                ip,
+                name,
+                timestamp: now,
+            });
+        }
+    }
+
+    /// Outputs an arrival or departure.
+    pub fn presence(
+        &self,
+        mac: MacAddr,
+        ip: Option<Ipv4Addr>,
+        name: &str,
+        status: Status,
+        now: DateTime<Local>,
+    ) {
+        self.write(&Record {
+            kind: match status {
+                Status::Arrived => "arrived",
+                Status::Left => "left",
+            },
+            mac: mac.to_string(),
+            ip,
+            name: Some(name),
+            timestamp: now,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event() {
+        let (lines, lines_r) = crossbeam_channel::unbounded();
+        let output = Output { lines };
+        let now = Local::now();
+        let mac = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let ip = Ipv4Addr::new(192, 168, 1, 10);
+
+        output.event(&Event::Alive { mac, ip }, Some("Alice"), now);
+        output.event(
+            &Event::Described {
+                mac,
+                description: "iPhone".into(),
+            },
+            None,
+            now,
+        );
+        output.presence(mac, None, "Alice", Status::Left, now);
+
+        let records: Vec<serde_json::Value> = lines_r
+            .try_iter()
+            .map(|line| serde_json::from_str(&line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["type"], "alive");
+        assert_eq!(records[0]["mac"], "00:11:22:33:44:55");
+        assert_eq!(records[0]["ip"], "192.168.1.10");
+        assert_eq!(records[0]["name"], "Alice");
+        assert_eq!(records[1]["type"], "left");
+        assert_eq!(records[1]["ip"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::config::{self, NetworkAddresses};
+use crate::tracker::{Prober, TICK_SECS};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use pnet::util::MacAddr;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant, SystemTime};
+
+/// ARP keepalives whose send times may queue up unread, e.g. while the capture restarts
+const SENT_BACKLOG: usize = 1024;
+
+enum Kind {
+    Arp,
+    Ping,
+}
+
+struct Probe {
+    kind: Kind,
+    us: NetworkAddresses,
+    them: NetworkAddresses,
+    at: Instant,
+}
+
+/// Where within the spread a device is probed, fixed per MAC so that every device is still probed
+/// once a tick, while different devices don't synchronize.
+fn offset(mac: MacAddr, spread: Duration) -> Duration {
+    let mut hasher = DefaultHasher::new();
+    let MacAddr(a, b, c, d, e, f) = mac;
+    [a, b, c, d, e, f].hash(&mut hasher);
+    spread.mul_f64((hasher.finish() % 1000) as f64 / 1000.0)
+}
+
+/// Sends keepalives from a thread of its own, spread over part of each tick instead of in one
+/// burst and at most `rate` per second.
+pub struct Paced {
+    probes: Sender<Probe>,
+    spread: Duration,
+    sent: Receiver<(MacAddr, SystemTime)>,
+}
+
+impl Paced {
+    pub fn start<P: Prober + Send + 'static>(prober: P, config: &config::Keepalive) -> Paced {
+        // leave the rest of the tick for the replies to arrive before the next one
+        let max_spread = Duration::from_secs(TICK_SECS.into()) / 2;
+        if config.spread > max_spread {
+            println!(
+                "Keepalive spread {} exceeds half a tick, using {}",
+                humantime::format_duration(config.spread),
+                humantime::format_duration(max_spread)
+            );
+        }
+        let spread = config.spread.min(max_spread);
+        let gap = Duration::from_secs(1) / config.rate.max(1);
+        let (probes, probes_r) = crossbeam_channel::unbounded::<Probe>();
+        let (sent_s, sent) = crossbeam_channel::bounded(SENT_BACKLOG);
+        std::thread::spawn(move || {
+            let mut pending: BTreeMap<(Instant, u64), Probe> = BTreeMap::new();
+            let mut sequence = 0u64;
+            let mut next_send = Instant::now();
+            loop {
+                let due = pending.keys().next().map(|(at, _)| *at);
+                let received = match due {
+                    Some(due) => {
+                        let now = Instant::now();
+                        probes_r.recv_timeout(due.max(next_send).saturating_duration_since(now))
+                    }
+                    None => probes_r.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(probe) => {
+                        // the sequence keeps probes due at the same time, e.g. ARP and then ping,
+                        // in order
+                        pending.insert((probe.at, sequence), probe);
+                        sequence += 1;
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                let key = match pending.keys().next() {
+                    Some(key) if key.0 <= Instant::now() => *key,
+                    _ => continue,
+                };
+                let probe = pending.remove(&key).expect("Probe is pending");
+                let sent = match probe.kind {
+                    Kind::Arp => {
+                        // recorded before sending, so it's always there by the time the reply is
+                        let _ = sent_s.try_send((probe.them.mac, SystemTime::now()));
+                        prober.probe(&probe.us, &probe.them)
+                    }
+                    Kind::Ping => prober.ping(&probe.us, &probe.them),
+                };
+                if let Err(e) = sent {
+                    println!("Failed to send keepalive to {}: {}", probe.them.ip, e);
+                }
+                next_send = Instant::now() + gap;
+            }
+        });
+        Paced {
+            probes,
+            spread,
+            sent,
+        }
+    }
+
+    /// When each ARP keepalive was sent, to time the replies with.
+    pub fn sent(&self) -> Receiver<(MacAddr, SystemTime)> {
+        self.sent.clone()
+    }
+
+    fn queue(&self, kind: Kind, us: &NetworkAddresses, them: &NetworkAddresses) {
+        let probe = Probe {
+            kind,
+            us: us.clone(),
+            them: them.clone(),
+            at: Instant::now() + offset(them.mac, self.spread),
+        };
+        if let Err(e) = self.probes.send(probe) {
+            println!("Failed to queue keepalive: {}", e);
+        }
+    }
+}
+
+impl Prober for Paced {
+    fn probe(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()> {
+        self.queue(Kind::Arp, us, them);
+        Ok(())
+    }
+
+    fn ping(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()> {
+        self.queue(Kind::Ping, us, them);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    struct Recorder(Sender<(MacAddr, Instant)>);
+
+    impl Prober for Recorder {
+        fn probe(&self, _us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()> {
+            let _ = self.0.send((them.mac, Instant::now()));
+            Ok(())
+        }
+
+        fn ping(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()> {
+            self.probe(us, them)
+        }
+    }
+
+    #[test]
+    fn test_paced() {
+        let spread = Duration::from_millis(200);
+        let macs: Vec<MacAddr> = (0..10).map(|i| MacAddr(0, 0x11, 0x22, 0, 0, i)).collect();
+        let offsets: Vec<Duration> = macs.iter().map(|mac| offset(*mac, spread)).collect();
+        assert!(offsets.iter().all(|offset| *offset < spread));
+        assert_eq!(offset(macs[0], spread), offsets[0]);
+        assert!(offsets.iter().any(|offset| *offset != offsets[0]));
+
+        let (sent, sent_r) = crossbeam_channel::unbounded();
+        let paced = Paced::start(Recorder(sent), &config::Keepalive { spread, rate: 100 });
+        let us = NetworkAddresses::new(MacAddr::zero(), Ipv4Addr::new(192, 168, 1, 1));
+        let start = Instant::now();
+        for mac in &macs {
+            paced
+                .probe(
+                    &us,
+                    &NetworkAddresses::new(*mac, Ipv4Addr::new(192, 168, 1, 10)),
+                )
+                .unwrap();
+        }
+        let sent: Vec<(MacAddr, Instant)> = (0..macs.len())
+            .map(|_| sent_r.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        // in order of their offsets, no closer together than the rate allows
+        let mut expected = macs.clone();
+        expected.sort_by_key(|mac| offset(*mac, spread));
+        assert_eq!(
+            sent.iter().map(|(mac, _)| *mac).collect::<Vec<_>>(),
+            expected
+        );
+        assert!(sent
+            .windows(2)
+            .all(|w| w[1].1 - w[0].1 >= Duration::from_millis(9)));
+        assert!(sent[0].1 - start >= offset(expected[0], spread));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::config::{self, PollerKind};
+use crate::network::Event;
+use crossbeam_channel::Sender;
+use pnet::util::MacAddr;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+
+/// A Wi-Fi client currently associated with an access point.
+#[derive(Debug)]
+pub struct Station {
+    pub mac: MacAddr,
+    pub ip: Option<Ipv4Addr>,
+}
+
+/// Something that knows which stations are associated, e.g. a UniFi controller or OpenWrt AP.
+pub trait Source {
+    fn stations(&mut self) -> crate::Result<Vec<Station>>;
+}
+
+fn poller_error(url: &str, message: impl ToString) -> crate::error::Error {
+    crate::error::Error::PollerError {
+        url: url.into(),
+        message: message.to_string(),
+    }
+}
+
+fn http_client(config: &config::Poller) -> reqwest::Client {
+    reqwest::Client::builder()
+        .cookie_store(true)
+        .danger_accept_invalid_certs(config.accept_invalid_certs)
+        .build()
+        .expect("Failed to create HTTP client")
+}
+
+/// Polls the classic UniFi controller API (`/api/login` and `/api/s/<site>/stat/sta`).
+pub struct UniFi {
+    http: reqwest::Client,
+    url: String,
+    site: String,
+    username: String,
+    password: String,
+    logged_in: bool,
+}
+
+impl UniFi {
+    pub fn new(config: &config::Poller) -> Self {
+        UniFi {
+            http: http_client(config),
+            url: config.url.trim_end_matches('/').into(),
+            site: config.site.clone().unwrap_or_else(|| "default".into()),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            logged_in: false,
+        }
+    }
+
+    fn login(&mut self) -> crate::Result<()> {
+        self.http
+            .post(&format!("{}/api/login", self.url))
+            .json(&json!({"username": self.username, "password": self.password}))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| poller_error(&self.url, e))?;
+        self.logged_in = true;
+        Ok(())
+    }
+}
+
+impl Source for UniFi {
+    fn stations(&mut self) -> crate::Result<Vec<Station>> {
+        if !self.logged_in {
+            self.login()?;
+        }
+        let response = self
+            .http
+            .get(&format!("{}/api/s/{}/stat/sta", self.url, self.site))
+            .send()
+            .map_err(|e| poller_error(&self.url, e))?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.logged_in = false;
+            return Err(poller_error(&self.url, "session expired"));
+        }
+        let body: Value = response
+            .error_for_status()
+            .and_then(|mut response| response.json())
+            .map_err(|e| poller_error(&self.url, e))?;
+        Ok(body["data"]
+            .as_array()
+            .ok_or_else(|| poller_error(&self.url, "missing data in response"))?
+            .iter()
+            .filter_map(|sta| {
+                Some(Station {
+                    mac: sta["mac"].as_str()?.parse().ok()?,
+                    ip: sta["ip"].as_str().and_then(|ip| ip.parse().ok()),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Polls hostapd on an OpenWrt AP over ubus JSON-RPC (requires `uhttpd-mod-ubus` and an rpcd user
+/// allowed to call `hostapd.*` and `luci-rpc`).
+pub struct OpenWrt {
+    http: reqwest::Client,
+    url: String,
+    username: String,
+    password: String,
+    interfaces: Vec<String>,
+    session: Option<String>,
+}
+
+const NO_SESSION: &str = "00000000000000000000000000000000";
+
+impl OpenWrt {
+    pub fn new(config: &config::Poller) -> Self {
+        OpenWrt {
+            http: http_client(config),
+            url: format!("{}/ubus", config.url.trim_end_matches('/')),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            interfaces: config
+                .interfaces
+                .iter()
+                .map(|i| format!("hostapd.{}", i))
+                .collect(),
+            session: None,
+        }
+    }
+
+    fn rpc(&self, method: &str, params: Value) -> crate::Result<Value> {
+        let mut body: Value = self
+            .http
+            .post(&self.url)
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|mut response| response.json())
+            .map_err(|e| poller_error(&self.url, e))?;
+        if let Some(error) = body.get("error") {
+            return Err(poller_error(&self.url, &error["message"]));
+        }
+        Ok(body["result"].take())
+    }
+
+    /// Calls a ubus object method, whose result is `[status, data]`.
+    fn call(&mut self, object: &str, method: &str) -> crate::Result<Value> {
+        let session = match &self.session {
+            Some(session) => session.clone(),
+            None => {
+                let login = json!({"username": self.username, "password": self.password});
+                let result = self.rpc("call", json!([NO_SESSION, "session", "login", login]))?;
+                let session = result[1]["ubus_rpc_session"]
+                    .as_str()
+                    .ok_or_else(|| poller_error(&self.url, "login failed"))?
+                    .to_string();
+                self.session = Some(session.clone());
+                session
+            }
+        };
+        let mut result = self.rpc("call", json!([session, object, method, {}]))?;
+        match result[0].as_u64() {
+            Some(0) => Ok(result[1].take()),
+            Some(6) => {
+                // permission denied, most likely an expired session
+                self.session = None;
+                Err(poller_error(&self.url, "access denied"))
+            }
+            status => Err(poller_error(
+                &self.url,
+                format!("{}.{} failed with status {:?}", object, method, status),
+            )),
+        }
+    }
+}
+
+impl Source for OpenWrt {
+    fn stations(&mut self) -> crate::Result<Vec<Station>> {
+        if self.interfaces.is_empty() {
+            let session = self.session.clone().unwrap_or_else(|| NO_SESSION.into());
+            let objects = self.rpc("list", json!([session, "hostapd.*"]))?;
+            self.interfaces = objects
+                .as_object()
+                .map(|objects| objects.keys().cloned().collect())
+                .unwrap_or_default();
+        }
+        let hints = self.call("luci-rpc", "getHostHints").unwrap_or(Value::Null);
+        let mut stations = Vec::new();
+        for interface in self.interfaces.clone() {
+            let clients = self.call(&interface, "get_clients")?;
+            if let Some(clients) = clients["clients"].as_object() {
+                for mac in clients.keys() {
+                    let mac: MacAddr = match mac.parse() {
+                        Ok(mac) => mac,
+                        Err(_) => continue,
+                    };
+                    // host hints are keyed by upper case MAC
+                    let ip = hints[mac.to_string().to_uppercase()]["ipaddrs"][0]
+                        .as_str()
+                        .and_then(|ip| ip.parse().ok());
+                    stations.push(Station { mac, ip });
+                }
+            }
+        }
+        Ok(stations)
+    }
+}
+
+fn source(config: &config::Poller) -> Box<dyn Source + Send> {
+    match config.kind {
+        PollerKind::UniFi => Box::new(UniFi::new(config)),
+        PollerKind::OpenWrt => Box::new(OpenWrt::new(config)),
+    }
+}
+
+/// Asks a source of its own whether a device is still associated, for `cross_check`.
+pub struct CrossCheck(Box<dyn Source + Send>);
+
+impl CrossCheck {
+    pub fn new(config: &config::Poller) -> Self {
+        CrossCheck(source(config))
+    }
+}
+
+impl crate::tracker::CrossCheck for CrossCheck {
+    fn is_present(&mut self, mac: MacAddr) -> crate::Result<bool> {
+        Ok(self.0.stations()?.iter().any(|station| station.mac == mac))
+    }
+}
+
+/// Polls a source on an interval in a thread of its own, sending `Event::Connected` for newly
+/// associated stations and `Event::Alive` for every station with a known IP. Stations associated
+/// at the first poll are only reported alive, like devices found by hostname on startup.
+pub fn start(config: &config::Poller, events: Sender<Event>) {
+    let mut source = source(config);
+    let interval = config.interval;
+    let url = config.url.clone();
+    std::thread::spawn(move || {
+        let mut associated: Option<HashSet<MacAddr>> = None;
+        loop {
+            match source.stations() {
+                Ok(stations) => {
+                    let mut current = HashSet::new();
+                    for station in stations {
+                        current.insert(station.mac);
+                        let mut station_events = Vec::new();
+                        if associated
+                            .as_ref()
+                            .map_or(false, |associated| !associated.contains(&station.mac))
+                        {
+                            station_events.push(Event::Connected {
+                                mac: station.mac,
+                                ip: station.ip,
+                                hostname: None,
+                            });
+                        }
+                        if let Some(ip) = station.ip {
+                            station_events.push(Event::Alive {
+                                mac: station.mac,
+                                ip,
+                            });
+                        }
+                        for event in station_events {
+                            if let Err(e) = events.send(event) {
+                                println!("Failed to send event, exiting: {}", e);
+                                return;
+                            }
+                        }
+                    }
+                    associated = Some(current);
+                }
+                Err(e) => println!("Failed to poll {}: {}", url, e),
+            }
+            std::thread::sleep(interval);
+        }
+    });
+}
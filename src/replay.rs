@@ -0,0 +1,324 @@
+use crate::filter::Filter;
+use crate::network::{self, Event};
+use crate::tracker::{PresenceTracker, TICK_SECS};
+use crate::{config, rtt, sampling};
+use chrono::{DateTime, Local, TimeZone};
+use crossbeam_channel::{Receiver, Sender};
+use pnet::util::MacAddr;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Speed(f64);
+
+impl std::str::FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "max" {
+            return Ok(Speed(0.0));
+        }
+        match s.trim_end_matches('x').parse::<f64>() {
+            Ok(speed) if speed >= 0.0 && speed.is_finite() => Ok(Speed(speed)),
+            _ => Err(format!("invalid speed '{}', expected e.g. 10x or max", s)),
+        }
+    }
+}
+
+impl Speed {
+    /// Sleeps for the real time corresponding to the time between `from` and `to` in the capture.
+    pub fn sleep(self, from: DateTime<Local>, to: DateTime<Local>) {
+        if self.0 == 0.0 {
+            return;
+        }
+        if let Ok(duration) = (to - from).to_std() {
+            std::thread::sleep(duration.div_f64(self.0));
+        }
+    }
+}
+
+/// An event from a source, with when it happened and the VLAN its frame was tagged with, if any.
+pub struct Sourced {
+    pub timestamp: DateTime<Local>,
+    pub event: Event,
+    pub vlan: Option<u16>,
+}
+
+/// Where events come from in the order they happened, e.g. a capture file or a scripted scenario
+/// in tests.
+pub trait Source {
+    /// The next event, or `None` once exhausted.
+    fn next_event(&mut self) -> crate::Result<Option<Sourced>>;
+}
+
+/// When a packet was captured, by its header.
+fn timestamp(header: &pcap::PacketHeader) -> DateTime<Local> {
+    Local
+        .timestamp_opt(header.ts.tv_sec, (header.ts.tv_usec * 1000) as u32)
+        .single()
+        .unwrap_or_else(Local::now)
+}
+
+/// The live capture on an interface, timing keepalive replies and thinning out ARP on the way.
+/// Capture timeouts are waited out, so it's never exhausted.
+pub struct Live {
+    capture: pcap::Capture<pcap::Active>,
+    /// Filters to apply to the running capture, rebuilt as the devices they depend on change
+    filters: Receiver<String>,
+    /// When ARP keepalives were sent, `None` in passive mode
+    keepalives: Option<Receiver<(MacAddr, SystemTime)>>,
+    matcher: rtt::Matcher,
+    sampler: Option<sampling::Sampler>,
+    ignore: Filter,
+    /// Events to hand out before reading another packet
+    pending: VecDeque<Sourced>,
+}
+
+impl Live {
+    /// Starts capturing on `interface` with `filter`, the timeout also bounding how long `filters`
+    /// wait on a quiet network. Frames on VLANs `ignore` ignores are dropped unseen.
+    pub fn open(
+        interface: &str,
+        config: &config::Capture,
+        filter: &str,
+        filters: Receiver<String>,
+        keepalives: Option<Receiver<(MacAddr, SystemTime)>>,
+        ignore: Filter,
+    ) -> crate::Result<Live> {
+        let mut capture = pcap::Capture::from_device(interface)?
+            .promisc(config.promisc)
+            .timeout(config.timeout.as_millis().clamp(1, i32::MAX as u128) as i32);
+        if let Some(snaplen) = config.snaplen {
+            capture = capture.snaplen(snaplen);
+        }
+        if let Some(buffer_size) = config.buffer_size {
+            capture = capture.buffer_size(buffer_size);
+        }
+        let mut capture = capture.open()?;
+        capture.direction(pcap::Direction::In)?;
+        capture.filter(filter)?;
+        Ok(Live {
+            capture,
+            filters,
+            keepalives,
+            matcher: rtt::Matcher::default(),
+            sampler: config
+                .sampling
+                .as_ref()
+                .map(|sampling| sampling::Sampler::new(sampling, Instant::now())),
+            ignore,
+            pending: VecDeque::new(),
+        })
+    }
+}
+
+impl Source for Live {
+    fn next_event(&mut self) -> crate::Result<Option<Sourced>> {
+        loop {
+            if let Some(sourced) = self.pending.pop_front() {
+                return Ok(Some(sourced));
+            }
+            for filter in self.filters.try_iter() {
+                self.capture.filter(&filter)?;
+            }
+            let packet = self.capture.next();
+            for (mac, at) in self
+                .keepalives
+                .iter()
+                .flat_map(|keepalives| keepalives.try_iter())
+            {
+                if self.matcher.sent(mac, at) {
+                    self.pending.push_back(Sourced {
+                        timestamp: Local::now(),
+                        event: Event::RoundTrip { mac, rtt: None },
+                        vlan: None,
+                    });
+                }
+            }
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(pcap::Error::TimeoutExpired) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if self.ignore.ignores_frame(packet.data) {
+                continue;
+            }
+            let timestamp = timestamp(packet.header);
+            if let Some(mac) = network::arp_reply(packet.data) {
+                let ts = packet.header.ts;
+                let at = std::time::UNIX_EPOCH
+                    + std::time::Duration::new(ts.tv_sec as u64, ts.tv_usec as u32 * 1000);
+                if let Some(rtt) = self.matcher.replied(mac, at) {
+                    self.pending.push_back(Sourced {
+                        timestamp,
+                        event: Event::RoundTrip {
+                            mac,
+                            rtt: Some(rtt),
+                        },
+                        vlan: None,
+                    });
+                }
+            }
+            if let Some(sampler) = &mut self.sampler {
+                if !sampler.keep(packet.data, Instant::now()) {
+                    continue;
+                }
+            }
+            self.pending.push_back(Sourced {
+                timestamp,
+                event: network::parse_packet(packet.data),
+                vlan: network::vlan_id(packet.data),
+            });
+        }
+    }
+}
+
+pub struct Replay {
+    capture: pcap::Capture<pcap::Offline>,
+}
+
+impl Replay {
+    pub fn open<P: AsRef<Path>>(path: P) -> crate::Result<Replay> {
+        Ok(Replay {
+            capture: pcap::Capture::from_file(path)?,
+        })
+    }
+}
+
+impl Source for Replay {
+    fn next_event(&mut self) -> crate::Result<Option<Sourced>> {
+        let packet = match self.capture.next() {
+            Ok(packet) => packet,
+            Err(pcap::Error::NoMorePackets) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(Sourced {
+            timestamp: timestamp(packet.header),
+            event: network::parse_packet(packet.data),
+            vlan: network::vlan_id(packet.data),
+        }))
+    }
+}
+
+/// The event as the tracker should see it, `Event::Ignored` if its VLAN is ignored.
+fn filtered(sourced: Sourced, filter: &Filter) -> (DateTime<Local>, Event) {
+    match sourced.vlan {
+        Some(vlan) if filter.ignores_vlan(vlan) => (sourced.timestamp, Event::Ignored),
+        _ => (sourced.timestamp, sourced.event),
+    }
+}
+
+/// Sends the events from `source` to `events` as the tracker should see them, until `source` is
+/// exhausted or `events` disconnects, for the run loop to handle as they come.
+pub fn forward<S: Source>(
+    mut source: S,
+    filter: &Filter,
+    events: &Sender<Event>,
+) -> crate::Result<()> {
+    while let Some(sourced) = source.next_event()? {
+        if events.send(filtered(sourced, filter).1).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Drives `tracker` with events from `source`, ticking the clock according to their timestamps.
+pub fn run<S: Source>(
+    tracker: &mut PresenceTracker,
+    mut source: S,
+    speed: Speed,
+) -> crate::Result<()> {
+    let tick = chrono::Duration::seconds(TICK_SECS.into());
+    let mut last = None;
+    let mut next_tick = None;
+
+    loop {
+        let packet = source
+            .next_event()?
+            .map(|sourced| filtered(sourced, tracker.filter()));
+        let until = packet.as_ref().map(|(timestamp, _)| *timestamp);
+
+        while let Some(tick_at) = next_tick {
+            if until.map_or(false, |until| tick_at > until) {
+                break;
+            }
+            if let Some(last) = last {
+                speed.sleep(last, tick_at);
+            }
+            last = Some(tick_at);
+            let id = tracker.correlation_id(tick_at);
+            tracker.handle_clock(tick_at, &id);
+            tracker.remind(tick_at, &id);
+            tracker.send_digests(tick_at, &id);
+            next_tick = if tracker.is_tracking() {
+                Some(tick_at + tick)
+            } else {
+                None
+            };
+        }
+
+        let (timestamp, event) = match packet {
+            Some(packet) => packet,
+            None => break,
+        };
+        if let Some(last) = last {
+            speed.sleep(last, timestamp);
+        }
+        last = Some(timestamp);
+        let id = tracker.correlation_id(timestamp);
+        tracker.handle_event(event, timestamp, &id);
+        if next_tick.is_none() && tracker.is_tracking() {
+            next_tick = Some(timestamp + tick);
+        }
+    }
+
+    println!("Replay finished");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed() {
+        assert_eq!("10x".parse::<Speed>().unwrap().0, 10.0);
+        assert_eq!("0.5".parse::<Speed>().unwrap().0, 0.5);
+        assert_eq!("max".parse::<Speed>().unwrap().0, 0.0);
+        assert!("-1x".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+    }
+
+    impl Source for VecDeque<Sourced> {
+        fn next_event(&mut self) -> crate::Result<Option<Sourced>> {
+            Ok(self.pop_front())
+        }
+    }
+
+    #[test]
+    fn test_forward() {
+        let mac = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let beacon = |vlan| Sourced {
+            timestamp: Local::now(),
+            event: Event::Beacon { mac },
+            vlan,
+        };
+        let source: VecDeque<Sourced> =
+            vec![beacon(None), beacon(Some(10)), beacon(Some(20))].into();
+        let filter = Filter {
+            vlans: vec![20].into_iter().collect(),
+            ..Filter::default()
+        };
+        let (s, r) = crossbeam_channel::unbounded();
+        forward(source, &filter, &s).unwrap();
+        drop(s);
+
+        let events: Vec<Event> = r.iter().collect();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0], Event::Beacon { .. }));
+        assert!(matches!(events[1], Event::Beacon { .. }));
+        assert!(matches!(events[2], Event::Ignored));
+    }
+}
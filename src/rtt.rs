@@ -0,0 +1,157 @@
+use chrono::{DateTime, Local};
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// How long after a keepalive a reply still counts towards its round-trip time
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Samples kept per device, about an hour's worth at one keepalive a tick
+const HISTORY: usize = 180;
+
+/// Matches ARP replies to the keepalives sent to the same device. It runs in the capture thread so
+/// replies are timed by their capture timestamps, unaffected by how long packets are buffered.
+#[derive(Debug, Default)]
+pub struct Matcher {
+    sent: HashMap<MacAddr, SystemTime>,
+}
+
+impl Matcher {
+    /// Records a keepalive sent, returning whether the previous one to the device went unanswered.
+    pub fn sent(&mut self, mac: MacAddr, at: SystemTime) -> bool {
+        self.sent.insert(mac, at).is_some()
+    }
+
+    /// The round-trip time of a reply, if it answers a keepalive in time.
+    pub fn replied(&mut self, mac: MacAddr, at: SystemTime) -> Option<Duration> {
+        let sent = self.sent.remove(&mac)?;
+        at.duration_since(sent).ok().filter(|rtt| *rtt <= TIMEOUT)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// A keepalive's round-trip time, `None` if it went unanswered.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Sample {
+    pub timestamp: DateTime<Local>,
+    pub rtt_ms: Option<f64>,
+}
+
+/// Round-trip times of a device over the kept samples, in milliseconds.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Summary {
+    pub last_ms: Option<f64>,
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+    /// Share of keepalives that went unanswered, from 0 to 1
+    pub loss: f64,
+    pub samples: usize,
+}
+
+/// The latest round-trip times of a device.
+#[derive(Debug, Default)]
+pub struct History {
+    samples: VecDeque<Sample>,
+}
+
+impl History {
+    pub fn record(&mut self, rtt: Option<Duration>, now: DateTime<Local>) {
+        if self.samples.len() == HISTORY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            timestamp: now,
+            rtt_ms: rtt.map(millis),
+        });
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter()
+    }
+
+    pub fn summary(&self) -> Option<Summary> {
+        let last = self.samples.back()?;
+        let answered: Vec<f64> = self.samples.iter().filter_map(|s| s.rtt_ms).collect();
+        let fold = |f: fn(f64, f64) -> f64| answered.iter().copied().reduce(f);
+        Some(Summary {
+            last_ms: last.rtt_ms,
+            min_ms: fold(f64::min),
+            avg_ms: fold(|a, b| a + b).map(|sum| sum / answered.len() as f64),
+            max_ms: fold(f64::max),
+            loss: (self.samples.len() - answered.len()) as f64 / self.samples.len() as f64,
+            samples: self.samples.len(),
+        })
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.avg_ms {
+            Some(avg) => write!(
+                f,
+                "RTT avg {:.1} ms, max {:.1} ms",
+                avg,
+                self.max_ms.unwrap_or(avg)
+            )?,
+            None => write!(f, "no keepalives answered")?,
+        }
+        write!(
+            f,
+            ", {:.0}% lost of {} keepalives",
+            self.loss * 100.0,
+            self.samples
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt() {
+        let mac = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut matcher = Matcher::default();
+        assert_eq!(matcher.replied(mac, start), None);
+        assert!(!matcher.sent(mac, start));
+        assert!(matcher.sent(mac, start + Duration::from_secs(20)));
+        assert_eq!(
+            matcher.replied(mac, start + Duration::from_millis(20_030)),
+            Some(Duration::from_millis(30))
+        );
+        // answered already
+        assert_eq!(matcher.replied(mac, start + Duration::from_secs(21)), None);
+        matcher.sent(mac, start + Duration::from_secs(40));
+        assert_eq!(matcher.replied(mac, start + Duration::from_secs(50)), None);
+
+        let now = Local::now();
+        let mut history = History::default();
+        assert_eq!(history.summary(), None);
+        history.record(Some(Duration::from_millis(10)), now);
+        history.record(None, now);
+        history.record(Some(Duration::from_millis(30)), now);
+        history.record(None, now);
+        let summary = history.summary().unwrap();
+        assert_eq!(summary.last_ms, None);
+        assert_eq!(
+            (summary.min_ms, summary.avg_ms, summary.max_ms),
+            (Some(10.0), Some(20.0), Some(30.0))
+        );
+        assert_eq!(summary.loss, 0.5);
+        assert_eq!(
+            summary.to_string(),
+            "RTT avg 20.0 ms, max 30.0 ms, 50% lost of 4 keepalives"
+        );
+
+        for _ in 0..HISTORY {
+            history.record(Some(Duration::from_millis(1)), now);
+        }
+        assert_eq!(history.samples().count(), HISTORY);
+        assert_eq!(history.summary().unwrap().loss, 0.0);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::config;
+use crate::network;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Samples ARP under sustained high packet rates, passing only 1 in `rate` ARP packets of each
+/// device per interval. The first of each interval always passes, so presence is still detected,
+/// and anything else, DHCP in particular, is never dropped.
+pub struct Sampler {
+    rate: u32,
+    /// Packets per interval above which the next interval is sampled
+    threshold: u64,
+    interval: Duration,
+    started: Instant,
+    packets: u64,
+    sampling: bool,
+    arp: HashMap<MacAddr, u32>,
+}
+
+impl Sampler {
+    pub fn new(config: &config::Sampling, now: Instant) -> Self {
+        Sampler {
+            rate: config.rate.max(1),
+            threshold: u64::from(config.threshold) * config.interval.as_secs().max(1),
+            interval: config.interval,
+            started: now,
+            packets: 0,
+            sampling: false,
+            arp: HashMap::new(),
+        }
+    }
+
+    /// Whether to process a captured frame, counting it towards the packet rate.
+    pub fn keep(&mut self, data: &[u8], now: Instant) -> bool {
+        if now.duration_since(self.started) >= self.interval {
+            let sampling = self.packets > self.threshold;
+            if sampling != self.sampling {
+                println!(
+                    "{} sampling ARP, {} packets in the last {}",
+                    if sampling { "Started" } else { "Stopped" },
+                    self.packets,
+                    humantime::format_duration(self.interval)
+                );
+            }
+            self.sampling = sampling;
+            self.started = now;
+            self.packets = 0;
+            self.arp.clear();
+        }
+        self.packets += 1;
+        if !self.sampling {
+            return true;
+        }
+        match network::arp_source(data) {
+            Some(mac) => {
+                let seen = self.arp.entry(mac).or_insert(0);
+                *seen += 1;
+                (*seen - 1) % self.rate == 0
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(source: u8, ethertype: [u8; 2]) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[0, 0x11, 0x22, 0x33, 0x44, source]);
+        frame.extend_from_slice(&ethertype);
+        frame.resize(60, 0);
+        frame
+    }
+
+    #[test]
+    fn test_keep() {
+        let now = Instant::now();
+        let config = config::Sampling {
+            rate: 3,
+            threshold: 1,
+            interval: Duration::from_secs(1),
+        };
+        let mut sampler = Sampler::new(&config, now);
+        let (arp, other_arp, ipv4) = (frame(1, [8, 6]), frame(2, [8, 6]), frame(1, [8, 0]));
+
+        // not sampling until an interval was busy
+        assert!((0..5).all(|_| sampler.keep(&arp, now)));
+
+        let now = now + Duration::from_secs(1);
+        let kept: Vec<bool> = (0..4).map(|_| sampler.keep(&arp, now)).collect();
+        assert_eq!(kept, vec![true, false, false, true]);
+        assert!(sampler.keep(&other_arp, now));
+        assert!((0..5).all(|_| sampler.keep(&ipv4, now)));
+
+        // a quiet interval stops sampling again
+        let now = now + Duration::from_secs(1);
+        assert!(sampler.keep(&arp, now));
+        let now = now + Duration::from_secs(1);
+        assert!(sampler.keep(&arp, now));
+        assert!(sampler.keep(&arp, now));
+    }
+}
@@ -0,0 +1,49 @@
+use crossbeam_channel::Receiver;
+use snafu::ResultExt;
+
+/// Signals blocked in the calling thread, and so in every thread it starts from then on.
+pub struct Blocked(());
+
+fn set_of(signals: &[libc::c_int]) -> libc::sigset_t {
+    let mut set = unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        set
+    };
+    for signal in signals {
+        unsafe { libc::sigaddset(&mut set, *signal) };
+    }
+    set
+}
+
+/// Blocks all of `signals` at once, to be waited for with `Blocked::listen`. Must be called before
+/// any other thread is started, since threads inherit the signal mask of the thread that starts
+/// them and a signal delivered to a thread that doesn't block it would kill the process.
+pub fn block(signals: &[libc::c_int]) -> crate::Result<Blocked> {
+    let set = set_of(signals);
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result)).context(crate::error::SignalError);
+    }
+    Ok(Blocked(()))
+}
+
+impl Blocked {
+    /// Waits for `signals`, which must have been blocked, in a thread of its own, sending on the
+    /// returned channel every time one is received.
+    pub fn listen(&self, signals: &[libc::c_int]) -> Receiver<libc::c_int> {
+        let set = set_of(signals);
+        let (s, r) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || loop {
+            let mut received = 0;
+            if unsafe { libc::sigwait(&set, &mut received) } != 0 {
+                println!("Failed to wait for signals, exiting");
+                return;
+            }
+            if s.send(received).is_err() {
+                return;
+            }
+        });
+        r
+    }
+}
@@ -0,0 +1,252 @@
+use crate::commands::{Audited, Change};
+use crate::config::Config;
+use crate::history::Entry;
+use crate::notifier::{Notification, Notifier};
+use crate::tracker::PresenceTracker;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Counts {
+    pub loud: u32,
+    pub quiet: u32,
+    pub digests: u32,
+    /// Held back by the cooldown, vacation mode, muting or the daily limit
+    pub suppressed: u32,
+    pub untracked: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub total: Counts,
+    pub users: BTreeMap<String, Counts>,
+}
+
+impl Counts {
+    pub fn notified(&self) -> u32 {
+        self.loud + self.quiet + self.digests
+    }
+}
+
+impl Report {
+    fn count(&mut self, name: &str, count: impl Fn(&mut Counts)) {
+        count(&mut self.total);
+        count(self.users.entry(name.to_string()).or_default());
+    }
+}
+
+/// Notifications the simulated tracker sends: whose, whether quiet and whether a digest.
+type Sent = Rc<RefCell<Vec<(String, bool, bool)>>>;
+
+struct Recorder(Sent);
+
+impl Notifier for Recorder {
+    fn notify(&self, notification: &Notification) -> crate::Result<()> {
+        self.0.borrow_mut().push((
+            notification.name.clone(),
+            notification.quiet,
+            notification.digest,
+        ));
+        Ok(())
+    }
+}
+
+/// Runs recorded history entries through a tracker with `config`, toggling vacation mode as the
+/// `audit` log did, and counts the notifications it would have sent. Digests of the last day are
+/// counted as if it had ended.
+pub fn simulate(config: Config, entries: &[Entry], audit: &[Audited]) -> Report {
+    let sent = Sent::default();
+    let mut tracker = PresenceTracker::new(config, Box::new(Recorder(sent.clone())));
+    let mut report = Report::default();
+    let mut changes = audit.iter().peekable();
+    for entry in entries {
+        while let Some(audited) = changes.next_if(|audited| audited.timestamp <= entry.timestamp) {
+            match audited.change {
+                Change::Vacation { to, .. } => tracker.set_vacation(to),
+            }
+        }
+        if !tracker.is_configured(entry.mac) {
+            report.count(&entry.name, |counts| counts.untracked += 1);
+            continue;
+        }
+        let id = tracker.correlation_id(entry.timestamp);
+        tracker.replay_entry(entry, &id);
+        let mut notified = false;
+        for (name, quiet, digest) in sent.borrow_mut().drain(..) {
+            notified |= !digest;
+            report.count(&name, |counts| match (digest, quiet) {
+                (true, _) => counts.digests += 1,
+                (false, true) => counts.quiet += 1,
+                (false, false) => counts.loud += 1,
+            });
+        }
+        if !notified {
+            report.count(&entry.name, |counts| counts.suppressed += 1);
+        }
+    }
+    if let Some(last) = entries.last() {
+        let id = tracker.correlation_id(last.timestamp);
+        tracker.send_digests(last.timestamp + chrono::Duration::days(1), &id);
+        for (name, _, _) in sent.borrow_mut().drain(..) {
+            report.count(&name, |counts| counts.digests += 1);
+        }
+    }
+    report
+}
+
+pub fn print_comparison(current: &Report, candidate: &Report) {
+    let row = |title: &str, before: u32, after: u32| {
+        println!("{:<24}{:>10}{:>10}", title, before, after);
+    };
+    println!("{:<24}{:>10}{:>10}", "", "current", "candidate");
+    row("Notified loudly", current.total.loud, candidate.total.loud);
+    row(
+        "Notified quietly",
+        current.total.quiet,
+        candidate.total.quiet,
+    );
+    row("Digests", current.total.digests, candidate.total.digests);
+    row(
+        "Suppressed",
+        current.total.suppressed,
+        candidate.total.suppressed,
+    );
+    row(
+        "Untracked",
+        current.total.untracked,
+        candidate.total.untracked,
+    );
+
+    let empty = Counts::default();
+    let users: std::collections::BTreeSet<_> =
+        current.users.keys().chain(candidate.users.keys()).collect();
+    println!();
+    println!("Notifications per user:");
+    for user in users {
+        let before = current.users.get(user).unwrap_or(&empty).notified();
+        let after = candidate.users.get(user).unwrap_or(&empty).notified();
+        println!("  {}: {} -> {}", user, before, after);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Identity, Metadata, Status};
+    use chrono::{DateTime, Duration, Local, TimeZone};
+    use pnet::util::MacAddr;
+    use std::collections::HashMap;
+
+    const MAC: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+    fn config() -> Config {
+        let mut rules = HashMap::new();
+        rules.insert(
+            MAC,
+            Metadata::new(
+                "User".to_string(),
+                None,
+                None,
+                "Sub".to_string(),
+                Identity {
+                    telegram: Some(1),
+                    ..Default::default()
+                },
+            ),
+        );
+        Config {
+            rules,
+            ..Config::for_tests()
+        }
+    }
+
+    fn day(n: i64) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 3, 2, 0, 0, 0).unwrap() + Duration::days(n)
+    }
+
+    fn entry(mac: MacAddr, timestamp: DateTime<Local>, status: Status) -> Entry {
+        Entry {
+            timestamp,
+            mac,
+            name: "User".to_string(),
+            status,
+            stay: None,
+            rtt: None,
+            correlation_id: None,
+        }
+    }
+
+    /// Two days of coming and going twice a day, and a device that's no one's.
+    fn entries() -> Vec<Entry> {
+        let mut entries = vec![entry(MacAddr::zero(), day(0), Status::Arrived)];
+        for n in 0..2 {
+            for (hour, status) in &[
+                (9, Status::Arrived),
+                (12, Status::Left),
+                (15, Status::Arrived),
+                (18, Status::Left),
+            ] {
+                entries.push(entry(MAC, day(n) + Duration::hours(*hour), *status));
+            }
+        }
+        entries
+    }
+
+    #[test]
+    fn test_baseline() {
+        let report = simulate(config(), &entries(), &[]);
+        assert_eq!(
+            report.total,
+            Counts {
+                loud: 8,
+                untracked: 1,
+                ..Counts::default()
+            }
+        );
+        assert_eq!(report.users["User"].notified(), 8);
+    }
+
+    #[test]
+    fn test_daily_limit() {
+        let candidate = Config {
+            daily_limit: Some(2),
+            ..config()
+        };
+        // the digest of each day's held back notifications is sent the next
+        let report = simulate(candidate, &entries(), &[]);
+        assert_eq!(
+            report.total,
+            Counts {
+                loud: 4,
+                digests: 2,
+                suppressed: 4,
+                untracked: 1,
+                ..Counts::default()
+            }
+        );
+        assert_eq!(report.users["User"].notified(), 6);
+    }
+
+    #[test]
+    fn test_vacation() {
+        let candidate = Config {
+            vacation: true,
+            ..config()
+        };
+        let report = simulate(candidate, &entries(), &[]);
+        assert_eq!((report.total.loud, report.total.suppressed), (4, 4));
+
+        // turned on for the afternoon of the first day
+        let toggled = |hour, from, to| Audited {
+            timestamp: day(0) + Duration::hours(hour),
+            chat_id: 1,
+            change: Change::Vacation { from, to },
+            undo: false,
+            undone: false,
+        };
+        let audit = [toggled(13, false, true), toggled(24, true, false)];
+        let report = simulate(config(), &entries(), &audit);
+        assert_eq!((report.total.loud, report.total.suppressed), (7, 1));
+    }
+}
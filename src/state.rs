@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    #[serde(default)]
+    pub archived: BTreeSet<String>,
+    #[serde(default)]
+    pub inventory: crate::inventory::Inventory,
+}
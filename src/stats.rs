@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+/// How often to compare interface counters with captured packets
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How long the interface may receive traffic without any of it being captured
+pub const SILENCE_WINDOW: Duration = Duration::from_secs(30 * 60);
+
+/// Packets received by an interface according to the kernel, whether captured or not.
+#[cfg(target_os = "linux")]
+pub fn rx_packets(interface: &str) -> std::io::Result<Option<u64>> {
+    let path: std::path::PathBuf = ["/sys/class/net", interface, "statistics", "rx_packets"]
+        .iter()
+        .collect();
+    std::fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Without `/sys/class/net` the counters aren't known, so the capture is never deemed silent.
+#[cfg(not(target_os = "linux"))]
+pub fn rx_packets(_interface: &str) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    Ok,
+    /// The interface received this many packets during the window but none were captured
+    Silent(u64),
+    /// Packets are captured again after being silent
+    Recovered,
+}
+
+/// Catches a capture that sees nothing while its interface is busy, e.g. a broken filter or a
+/// capture on the wrong bridge member.
+#[derive(Debug)]
+pub struct Sanity {
+    window: Duration,
+    since: Option<(Instant, u64)>,
+    captured: bool,
+    silent: bool,
+}
+
+impl Sanity {
+    pub fn new(window: Duration) -> Self {
+        Sanity {
+            window,
+            since: None,
+            captured: false,
+            silent: false,
+        }
+    }
+
+    /// Records a packet captured by our filter.
+    pub fn captured(&mut self) {
+        self.captured = true;
+    }
+
+    pub fn check(&mut self, rx_packets: u64, now: Instant) -> Check {
+        if std::mem::replace(&mut self.captured, false) {
+            self.since = Some((now, rx_packets));
+            return if std::mem::replace(&mut self.silent, false) {
+                Check::Recovered
+            } else {
+                Check::Ok
+            };
+        }
+        let (start, start_rx) = *self.since.get_or_insert((now, rx_packets));
+        // counters reset when the interface is recreated
+        let received = rx_packets.saturating_sub(start_rx);
+        if !self.silent && received > 0 && now.duration_since(start) >= self.window {
+            self.silent = true;
+            return Check::Silent(received);
+        }
+        Check::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanity() {
+        let minute = Duration::from_secs(60);
+        let mut sanity = Sanity::new(minute * 10);
+        let start = Instant::now();
+
+        assert_eq!(sanity.check(100, start), Check::Ok);
+        assert_eq!(sanity.check(200, start + minute * 5), Check::Ok);
+        assert_eq!(sanity.check(300, start + minute * 10), Check::Silent(200));
+        assert_eq!(sanity.check(400, start + minute * 20), Check::Ok);
+        sanity.captured();
+        assert_eq!(sanity.check(500, start + minute * 21), Check::Recovered);
+        assert_eq!(sanity.check(500, start + minute * 40), Check::Ok);
+    }
+}
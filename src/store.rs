@@ -0,0 +1,116 @@
+use crate::commands::Audited;
+use crate::config::{Backend, Storage};
+use crate::history::Entry;
+use crate::state::State;
+use chrono::{DateTime, Local};
+
+mod json;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/// Persistence of state, history and the audit log, implemented by each storage backend.
+pub trait Store {
+    fn load_state(&self) -> crate::Result<State>;
+    fn save_state(&mut self, state: &State) -> crate::Result<()>;
+    fn append_history(&mut self, entry: &Entry) -> crate::Result<()>;
+    fn history(&self, since: DateTime<Local>) -> crate::Result<Vec<Entry>>;
+    /// Records a change made by a bot command.
+    fn append_audit(&mut self, audited: &Audited) -> crate::Result<()>;
+    /// Every change made by bot commands, oldest first.
+    fn audit(&self) -> crate::Result<Vec<Audited>>;
+}
+
+pub fn open(storage: &Storage) -> crate::Result<Box<dyn Store>> {
+    match storage.backend {
+        Backend::Json => Ok(Box::new(json::JsonStore::open(&storage.path)?)),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Ok(Box::new(sqlite::SqliteStore::open(&storage.path)?)),
+        #[cfg(feature = "sled")]
+        Backend::Sled => Ok(Box::new(self::sled::SledStore::open(&storage.path)?)),
+        #[allow(unreachable_patterns)]
+        backend => Err(crate::error::Error::UnsupportedStorage { backend }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    /// Saves state, history and the audit log with the store `open` opens in a new directory,
+    /// then checks they load back the same once it's reopened.
+    fn round_trip<S: Store>(backend: &str, open: impl Fn(&std::path::Path) -> crate::Result<S>) {
+        let dir =
+            std::env::temp_dir().join(format!("houserat-test-{}-{}", backend, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let now = Local::now();
+        let audited = Audited {
+            timestamp: now,
+            chat_id: 1,
+            change: crate::commands::Change::Vacation {
+                from: false,
+                to: true,
+            },
+            undo: false,
+            undone: false,
+        };
+
+        {
+            let mut store = open(&dir).unwrap();
+            assert!(store.load_state().unwrap().archived.is_empty());
+            let mut state = State::default();
+            state.archived.insert("User".to_string());
+            store.save_state(&state).unwrap();
+
+            for (age, status) in &[(10, crate::Status::Arrived), (1, crate::Status::Left)] {
+                store
+                    .append_history(&Entry {
+                        timestamp: now - Duration::hours(*age),
+                        mac: pnet::util::MacAddr::zero(),
+                        name: "User".to_string(),
+                        status: *status,
+                        stay: Some(std::time::Duration::from_secs(*age as u64 * 60)),
+                        rtt: None,
+                        correlation_id: Some(format!("18e2-1f-{}-0", age)),
+                    })
+                    .unwrap();
+            }
+
+            assert!(store.audit().unwrap().is_empty());
+            store.append_audit(&audited).unwrap();
+        }
+
+        let store = open(&dir).unwrap();
+        assert!(store.load_state().unwrap().archived.contains("User"));
+        let entries = store.history(now - Duration::hours(5)).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].status, crate::Status::Left);
+        assert_eq!(entries[0].name, "User");
+        assert_eq!(entries[0].stay, Some(std::time::Duration::from_secs(60)));
+        assert_eq!(entries[0].correlation_id.as_deref(), Some("18e2-1f-1-0"));
+        assert_eq!(store.history(now - Duration::hours(11)).unwrap().len(), 2);
+        assert_eq!(store.audit().unwrap(), vec![audited]);
+        drop(store);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_store() {
+        round_trip("json", json::JsonStore::open);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store() {
+        round_trip("sqlite", sqlite::SqliteStore::open);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_store() {
+        round_trip("sled", self::sled::SledStore::open);
+    }
+}
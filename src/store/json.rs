@@ -0,0 +1,124 @@
+use super::Store;
+use crate::commands::Audited;
+use crate::history::Entry;
+use crate::state::State;
+use chrono::{DateTime, Local};
+use serde::{de::DeserializeOwned, Serialize};
+use snafu::ResultExt;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+pub struct JsonStore {
+    state_path: PathBuf,
+    history_path: PathBuf,
+    history: Option<std::fs::File>,
+    audit_path: PathBuf,
+    audit: Option<std::fs::File>,
+}
+
+impl JsonStore {
+    pub fn open(dir: &Path) -> crate::Result<JsonStore> {
+        Ok(JsonStore {
+            state_path: dir.join("state.json"),
+            history_path: dir.join("history.jsonl"),
+            history: None,
+            audit_path: dir.join("audit.jsonl"),
+            audit: None,
+        })
+    }
+}
+
+/// Appends a line of JSON to a file, opening it on first use.
+fn append_line<T: Serialize>(
+    file: &mut Option<std::fs::File>,
+    path: &Path,
+    value: &T,
+) -> crate::Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => {
+            let opened = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| crate::error::HistoryError {
+                    path: path.to_path_buf(),
+                })?;
+            file.get_or_insert(opened)
+        }
+    };
+    let mut line = serde_json::to_string(value).expect("Failed to serialize JSON line");
+    line.push('\n');
+    file.write_all(line.as_bytes())
+        .with_context(|| crate::error::HistoryError {
+            path: path.to_path_buf(),
+        })
+}
+
+/// Reads a file of JSON lines, which is empty if it doesn't exist yet.
+fn read_lines<T: DeserializeOwned>(path: &Path) -> crate::Result<Vec<T>> {
+    let path = path.to_path_buf();
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(crate::error::Error::HistoryError { path, source: e }),
+    };
+    let mut values = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line.with_context(|| crate::error::HistoryError { path: path.clone() })?;
+        if line.is_empty() {
+            continue;
+        }
+        values.push(
+            serde_json::from_str(&line)
+                .with_context(|| crate::error::InvalidHistory { path: path.clone() })?,
+        );
+    }
+    Ok(values)
+}
+
+impl Store for JsonStore {
+    fn load_state(&self) -> crate::Result<State> {
+        let content = match std::fs::read_to_string(&self.state_path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => {
+                return Err(crate::error::Error::StateReadError {
+                    path: self.state_path.clone(),
+                    source: e,
+                })
+            }
+        };
+        serde_json::from_str(&content).with_context(|| crate::error::InvalidState {
+            path: self.state_path.clone(),
+        })
+    }
+
+    fn save_state(&mut self, state: &State) -> crate::Result<()> {
+        let content = serde_json::to_string_pretty(state).expect("Failed to serialize state");
+        let tmp_path = self.state_path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)
+            .and_then(|()| std::fs::rename(&tmp_path, &self.state_path))
+            .with_context(|| crate::error::StateWriteError {
+                path: self.state_path.clone(),
+            })
+    }
+
+    fn append_history(&mut self, entry: &Entry) -> crate::Result<()> {
+        append_line(&mut self.history, &self.history_path, entry)
+    }
+
+    fn history(&self, since: DateTime<Local>) -> crate::Result<Vec<Entry>> {
+        let mut entries: Vec<Entry> = read_lines(&self.history_path)?;
+        entries.retain(|entry| entry.timestamp >= since);
+        Ok(entries)
+    }
+
+    fn append_audit(&mut self, audited: &Audited) -> crate::Result<()> {
+        append_line(&mut self.audit, &self.audit_path, audited)
+    }
+
+    fn audit(&self) -> crate::Result<Vec<Audited>> {
+        read_lines(&self.audit_path)
+    }
+}
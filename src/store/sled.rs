@@ -0,0 +1,95 @@
+use super::Store;
+use crate::commands::Audited;
+use crate::history::Entry;
+use crate::state::State;
+use chrono::{DateTime, Local};
+use std::convert::TryInto;
+use std::path::Path;
+
+const STATE_KEY: &[u8] = b"state";
+
+pub struct SledStore {
+    db: ::sled::Db,
+    history: ::sled::Tree,
+    /// Keyed by ids from the database, which increase so the changes scan in order
+    audit: ::sled::Tree,
+}
+
+fn storage_error(e: impl std::fmt::Display) -> crate::error::Error {
+    crate::error::Error::StorageError {
+        backend: crate::config::Backend::Sled,
+        message: e.to_string(),
+    }
+}
+
+/// History keys sort by timestamp so entries can be range scanned, with a unique id appended.
+fn history_key(timestamp: DateTime<Local>, id: u64) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    let millis: u64 = timestamp.timestamp_millis().try_into().unwrap_or(0);
+    key[..8].copy_from_slice(&millis.to_be_bytes());
+    key[8..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+impl SledStore {
+    pub fn open(dir: &Path) -> crate::Result<SledStore> {
+        let db = ::sled::open(dir.join("houserat.sled")).map_err(storage_error)?;
+        let history = db.open_tree("history").map_err(storage_error)?;
+        let audit = db.open_tree("audit").map_err(storage_error)?;
+        Ok(SledStore { db, history, audit })
+    }
+}
+
+impl Store for SledStore {
+    fn load_state(&self) -> crate::Result<State> {
+        match self.db.get(STATE_KEY).map_err(storage_error)? {
+            Some(data) => serde_json::from_slice(&data).map_err(storage_error),
+            None => Ok(State::default()),
+        }
+    }
+
+    fn save_state(&mut self, state: &State) -> crate::Result<()> {
+        let data = serde_json::to_vec(state).expect("Failed to serialize state");
+        self.db.insert(STATE_KEY, data).map_err(storage_error)?;
+        self.db.flush().map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn append_history(&mut self, entry: &Entry) -> crate::Result<()> {
+        let data = serde_json::to_vec(entry).expect("Failed to serialize history entry");
+        let id = self.db.generate_id().map_err(storage_error)?;
+        self.history
+            .insert(&history_key(entry.timestamp, id)[..], data)
+            .map_err(storage_error)?;
+        self.history.flush().map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn history(&self, since: DateTime<Local>) -> crate::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for item in self.history.range(&history_key(since, 0)[..]..) {
+            let (_, data) = item.map_err(storage_error)?;
+            entries.push(serde_json::from_slice(&data).map_err(storage_error)?);
+        }
+        Ok(entries)
+    }
+
+    fn append_audit(&mut self, audited: &Audited) -> crate::Result<()> {
+        let data = serde_json::to_vec(audited).expect("Failed to serialize audit log");
+        let id = self.db.generate_id().map_err(storage_error)?;
+        self.audit
+            .insert(&id.to_be_bytes()[..], data)
+            .map_err(storage_error)?;
+        self.audit.flush().map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn audit(&self) -> crate::Result<Vec<Audited>> {
+        let mut audit = Vec::new();
+        for item in self.audit.iter() {
+            let (_, data) = item.map_err(storage_error)?;
+            audit.push(serde_json::from_slice(&data).map_err(storage_error)?);
+        }
+        Ok(audit)
+    }
+}
@@ -0,0 +1,139 @@
+use super::Store;
+use crate::commands::Audited;
+use crate::history::Entry;
+use crate::state::State;
+use chrono::{DateTime, Local};
+use rusqlite::{params, OptionalExtension, NO_PARAMS};
+use std::path::Path;
+
+pub struct SqliteStore {
+    connection: rusqlite::Connection,
+}
+
+fn storage_error(e: impl std::fmt::Display) -> crate::error::Error {
+    crate::error::Error::StorageError {
+        backend: crate::config::Backend::Sqlite,
+        message: e.to_string(),
+    }
+}
+
+impl SqliteStore {
+    pub fn open(dir: &Path) -> crate::Result<SqliteStore> {
+        let connection =
+            rusqlite::Connection::open(dir.join("houserat.sqlite3")).map_err(storage_error)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS state (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    data TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS history (
+                    id INTEGER PRIMARY KEY,
+                    timestamp INTEGER NOT NULL,
+                    mac TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    status TEXT NOT NULL,
+                    data TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS history_timestamp ON history (timestamp);
+                CREATE TABLE IF NOT EXISTS audit (
+                    id INTEGER PRIMARY KEY,
+                    timestamp INTEGER NOT NULL,
+                    chat_id INTEGER NOT NULL,
+                    data TEXT NOT NULL
+                );",
+            )
+            .map_err(storage_error)?;
+        Ok(SqliteStore { connection })
+    }
+}
+
+impl Store for SqliteStore {
+    fn load_state(&self) -> crate::Result<State> {
+        let data: Option<String> = self
+            .connection
+            .query_row("SELECT data FROM state WHERE id = 0", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(storage_error)?;
+        match data {
+            Some(data) => serde_json::from_str(&data).map_err(storage_error),
+            None => Ok(State::default()),
+        }
+    }
+
+    fn save_state(&mut self, state: &State) -> crate::Result<()> {
+        let data = serde_json::to_string(state).expect("Failed to serialize state");
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO state (id, data) VALUES (0, ?1)",
+                params![data],
+            )
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn append_history(&mut self, entry: &Entry) -> crate::Result<()> {
+        let data = serde_json::to_string(entry).expect("Failed to serialize history entry");
+        self.connection
+            .execute(
+                "INSERT INTO history (timestamp, mac, name, status, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    entry.timestamp.timestamp_millis(),
+                    entry.mac.to_string(),
+                    entry.name,
+                    entry.status.to_string(),
+                    data
+                ],
+            )
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn history(&self, since: DateTime<Local>) -> crate::Result<Vec<Entry>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT data FROM history WHERE timestamp >= ?1 ORDER BY id")
+            .map_err(storage_error)?;
+        let rows = statement
+            .query_map(params![since.timestamp_millis()], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(storage_error)?;
+        let mut entries = Vec::new();
+        for data in rows {
+            let data = data.map_err(storage_error)?;
+            entries.push(serde_json::from_str(&data).map_err(storage_error)?);
+        }
+        Ok(entries)
+    }
+
+    fn append_audit(&mut self, audited: &Audited) -> crate::Result<()> {
+        let data = serde_json::to_string(audited).expect("Failed to serialize audit log");
+        self.connection
+            .execute(
+                "INSERT INTO audit (timestamp, chat_id, data) VALUES (?1, ?2, ?3)",
+                params![audited.timestamp.timestamp_millis(), audited.chat_id, data],
+            )
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn audit(&self) -> crate::Result<Vec<Audited>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT data FROM audit ORDER BY id")
+            .map_err(storage_error)?;
+        let rows = statement
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .map_err(storage_error)?;
+        let mut audit = Vec::new();
+        for data in rows {
+            let data = data.map_err(storage_error)?;
+            audit.push(serde_json::from_str(&data).map_err(storage_error)?);
+        }
+        Ok(audit)
+    }
+}
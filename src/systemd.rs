@@ -0,0 +1,43 @@
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// Sends a state update (e.g. `READY=1`) to systemd, doing nothing when not run as a notify service.
+pub fn notify(state: &str) -> std::io::Result<()> {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    if let Some(name) = path.strip_prefix('@') {
+        send_abstract(&socket, name, state)?;
+    } else {
+        socket.send_to(state.as_bytes(), path.as_ref())?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    let address = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// Abstract socket names only exist on Linux, as does systemd.
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, _name: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Interval at which to send `WATCHDOG=1`, half the watchdog timeout, if enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID") {
+        if pid.to_string_lossy() != std::process::id().to_string() {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
@@ -1,21 +1,112 @@
+use crate::config::Config;
+use crossbeam_channel::Sender;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use url::Url;
 
 const API_URL: &str = "https://api.telegram.org";
+/// How long `getUpdates` waits for messages, below the HTTP client's 30 second timeout
+const POLL_TIMEOUT_SECS: u64 = 25;
+const POLL_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
 
 #[derive(Debug, Deserialize)]
 struct Response {
     ok: bool,
+    error_code: Option<u16>,
     description: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+struct Updates {
+    result: Vec<Update>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<IncomingMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IncomingMessage {
+    chat: Chat,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Chat {
+    id: i64,
+}
+
 trait Type: Serialize {
     fn method() -> &'static str;
 }
 
+/// How Telegram parses the text of a message, see https://core.telegram.org/bots/api#formatting-options.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum ParseMode {
+    #[default]
+    #[serde(rename(serialize = "Markdown", deserialize = "markdown"))]
+    Markdown,
+    #[serde(rename(serialize = "MarkdownV2", deserialize = "markdownv2"))]
+    MarkdownV2,
+    #[serde(rename(serialize = "HTML", deserialize = "html"))]
+    Html,
+}
+
+impl ParseMode {
+    /// Escapes text that isn't ours, such as names and hostnames, so it's shown as is.
+    pub fn escape(self, text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match (self, c) {
+                (ParseMode::Markdown, '_' | '*' | '`' | '[') => escaped.push('\\'),
+                (
+                    ParseMode::MarkdownV2,
+                    '_' | '*' | '[' | ']' | '(' | ')' | '~' | '`' | '>' | '#' | '+' | '-' | '='
+                    | '|' | '{' | '}' | '.' | '!' | '\\',
+                ) => escaped.push('\\'),
+                (ParseMode::Html, '&') => {
+                    escaped.push_str("&amp;");
+                    continue;
+                }
+                (ParseMode::Html, '<') => {
+                    escaped.push_str("&lt;");
+                    continue;
+                }
+                (ParseMode::Html, '>') => {
+                    escaped.push_str("&gt;");
+                    continue;
+                }
+                _ => (),
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    /// Links text, which is escaped, to a URL.
+    pub fn link(self, text: &str, url: &str) -> String {
+        match self {
+            ParseMode::Markdown | ParseMode::MarkdownV2 => {
+                format!("[{}]({})", self.escape(text), url)
+            }
+            ParseMode::Html => format!(
+                "<a href=\"{}\">{}</a>",
+                url.replace('"', "&quot;"),
+                self.escape(text)
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Client {
     url: Url,
     http: reqwest::Client,
+    /// How notifications are formatted, other messages are plain text
+    parse_mode: ParseMode,
 }
 
 impl Client {
@@ -28,25 +119,163 @@ impl Client {
         Client {
             url,
             http: reqwest::Client::new(),
+            parse_mode: ParseMode::default(),
         }
     }
 
-    fn post<T: Type>(&self, message: &T) -> reqwest::Result<()> {
-        let _response = self
+    pub fn with_parse_mode(mut self, parse_mode: ParseMode) -> Client {
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    /// Posts a request, telling apart the errors caused by configuration or by people from the
+    /// description Telegram sends along with them.
+    fn post<T: Type>(&self, message: &T, chat_id: i64) -> crate::Result<()> {
+        let mut response = self
             .http
             .post(self.url.join(T::method()).unwrap())
             .json(&message)
+            .send()?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response.json::<Response>().ok().filter(|body| !body.ok);
+        let code = body
+            .as_ref()
+            .and_then(|body| body.error_code)
+            .unwrap_or_else(|| status.as_u16());
+        let description = body
+            .and_then(|body| body.description)
+            .unwrap_or_else(|| status.to_string());
+        Err(refusal(code, description, chat_id))
+    }
+
+    fn get_updates(&self, offset: i64, timeout_secs: u64) -> reqwest::Result<Vec<Update>> {
+        let mut url = self.url.join("getUpdates").unwrap();
+        url.query_pairs_mut()
+            .append_pair("offset", &offset.to_string())
+            .append_pair("timeout", &timeout_secs.to_string())
+            .append_pair("allowed_updates", r#"["message"]"#);
+        Ok(self
+            .http
+            .get(url)
             .send()?
-            .json::<Response>();
-        Ok(())
+            .error_for_status()?
+            .json::<Updates>()?
+            .result)
+    }
+
+    /// Forgets the messages sent while not listening, e.g. commands from before a restart that
+    /// shouldn't run now, returning the offset to continue from.
+    fn skip_backlog(&self) -> reqwest::Result<i64> {
+        // a negative offset returns the latest update only, confirming all those before it
+        let updates = self.get_updates(-1, 0)?;
+        Ok(updates.last().map_or(0, |update| update.update_id + 1))
     }
 }
 
+fn refusal(code: u16, description: String, chat_id: i64) -> crate::error::Error {
+    match code {
+        401 | 404 => crate::error::Error::InvalidBotToken,
+        400 if description.contains("chat not found") => {
+            crate::error::Error::ChatNotFound { chat_id }
+        }
+        403 if description.contains("blocked by the user") => {
+            crate::error::Error::BotBlockedByUser { chat_id }
+        }
+        _ => crate::error::Error::TelegramRefused { description },
+    }
+}
+
+/// A client per bot, sending each notification through the bot of its subscriber, or the global
+/// one when the subscriber has none of their own.
+pub struct Bots {
+    /// Clients by bot token
+    clients: HashMap<String, Client>,
+    /// Bot tokens by subscriber
+    subscribers: HashMap<String, String>,
+    default: String,
+}
+
+impl Bots {
+    pub fn new(config: &Config) -> Bots {
+        let clients = config
+            .bot_tokens
+            .values()
+            .chain(std::iter::once(&config.bot_token))
+            .map(|token| {
+                (
+                    token.clone(),
+                    Client::new(token).with_parse_mode(config.parse_mode),
+                )
+            })
+            .collect();
+        Bots {
+            clients,
+            subscribers: config.bot_tokens.clone(),
+            default: config.bot_token.clone(),
+        }
+    }
+
+    fn client(&self, subscriber: &str) -> &Client {
+        let token = self.subscribers.get(subscriber).unwrap_or(&self.default);
+        &self.clients[token]
+    }
+}
+
+impl crate::Notifier for Bots {
+    fn notify(&self, notification: &crate::Notification) -> crate::Result<()> {
+        self.client(&notification.subscriber_name)
+            .notify(notification)
+    }
+}
+
+/// Long polls for messages sent to the bot in a thread of its own, sending the chat ID and text of
+/// each.
+pub fn listen(client: Client, messages: Sender<(i64, String)>) {
+    std::thread::spawn(move || {
+        let mut offset = loop {
+            match client.skip_backlog() {
+                Ok(offset) => break offset,
+                Err(e) => {
+                    println!("Failed to get Telegram updates: {}", e);
+                    std::thread::sleep(POLL_RETRY_DELAY);
+                }
+            }
+        };
+        loop {
+            let updates = match client.get_updates(offset, POLL_TIMEOUT_SECS) {
+                Ok(updates) => updates,
+                Err(e) => {
+                    println!("Failed to get Telegram updates: {}", e);
+                    std::thread::sleep(POLL_RETRY_DELAY);
+                    continue;
+                }
+            };
+            for update in updates {
+                offset = offset.max(update.update_id + 1);
+                if let Some(IncomingMessage {
+                    chat,
+                    text: Some(text),
+                }) = update.message
+                {
+                    if let Err(e) = messages.send((chat.id, text)) {
+                        println!("Failed to send message, exiting: {}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    });
+}
+
 #[derive(Debug, Serialize)]
 pub struct Message {
     chat_id: i64,
     text: String,
-    parse_mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parse_mode: Option<ParseMode>,
     disable_web_page_preview: bool,
     disable_notification: bool,
 }
@@ -58,17 +287,85 @@ impl Type for Message {
 }
 
 impl Message {
+    /// A message in plain text.
     pub fn new(chat_id: i64, text: String, disable_notification: bool) -> Message {
         Message {
             chat_id,
             text,
-            parse_mode: "Markdown".to_string(),
+            parse_mode: None,
             disable_web_page_preview: true,
             disable_notification,
         }
     }
 
+    /// Formats the text, which must already be escaped for the parse mode.
+    pub fn with_parse_mode(mut self, parse_mode: ParseMode) -> Message {
+        self.parse_mode = Some(parse_mode);
+        self
+    }
+
     pub fn send(self, client: &Client) -> crate::Result<()> {
-        Ok(client.post(&self)?)
+        client.post(&self, self.chat_id)
+    }
+}
+
+impl crate::Notifier for Client {
+    fn notify(&self, notification: &crate::Notification) -> crate::Result<()> {
+        let chat_id = match notification.identity.telegram {
+            Some(chat_id) => chat_id,
+            // reached by other backends only
+            None => return Ok(()),
+        };
+        Message::new(chat_id, notification.text.clone(), notification.quiet)
+            .with_parse_mode(self.parse_mode)
+            .send(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn test_refusal() {
+        let refusal = |code, description: &str| refusal(code, description.into(), 42);
+        assert!(matches!(
+            refusal(401, "Unauthorized"),
+            Error::InvalidBotToken
+        ));
+        assert!(matches!(
+            refusal(400, "Bad Request: chat not found"),
+            Error::ChatNotFound { chat_id: 42 }
+        ));
+        assert!(matches!(
+            refusal(403, "Forbidden: bot was blocked by the user"),
+            Error::BotBlockedByUser { chat_id: 42 }
+        ));
+        let error = refusal(400, "Bad Request: message is too long");
+        assert!(matches!(error, Error::TelegramRefused { .. }));
+        assert_eq!(error.remediation(), None);
+    }
+
+    #[test]
+    fn test_escape() {
+        let name = "Bob's_phone (v2.0) <3 [*]";
+        assert_eq!(
+            ParseMode::Markdown.escape(name),
+            r"Bob's\_phone (v2.0) <3 \[\*]"
+        );
+        assert_eq!(
+            ParseMode::MarkdownV2.escape(name),
+            r"Bob's\_phone \(v2\.0\) <3 \[\*\]"
+        );
+        assert_eq!(ParseMode::Html.escape(name), "Bob's_phone (v2.0) &lt;3 [*]");
+        assert_eq!(
+            ParseMode::Html.link("A & B", "https://t.me/ab"),
+            r#"<a href="https://t.me/ab">A &amp; B</a>"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ParseMode::Html).unwrap(),
+            r#""HTML""#
+        );
     }
 }
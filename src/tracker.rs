@@ -0,0 +1,1912 @@
+use crate::config::{self, NetworkAddresses};
+use crate::filter::Filter;
+use crate::history;
+use crate::inventory::Inventory;
+use crate::mdns::Describer;
+use crate::metadata::Metadata;
+use crate::network::Event;
+use crate::notifier::{Notification, Notifier};
+use crate::output::Output;
+use crate::rtt;
+use crate::store::Store;
+use crate::telegram::ParseMode;
+use chrono::{DateTime, Local, NaiveDate};
+use pnet::util::MacAddr;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map, BTreeSet, HashMap};
+use std::net::Ipv4Addr;
+
+pub const TICK_SECS: u32 = 20;
+pub const ALLOWED_PACKETS_LOST: u32 = 3;
+/// Unanswered keepalives after which a device's hostname is resolved again, in case its address
+/// changed
+const UNANSWERED_BEFORE_RESOLVE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Arrived,
+    Left,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Arrived => write!(f, "arrived"),
+            Self::Left => write!(f, "left"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Decision {
+    Cooldown,
+    Quiet,
+    Loud,
+}
+
+pub fn decide(
+    metadata: &mut Metadata,
+    cooldown: &Option<chrono::Duration>,
+    quiet_period: &Option<config::Period>,
+    now: DateTime<Local>,
+) -> Decision {
+    if !metadata.should_notify(cooldown, now) {
+        return Decision::Cooldown;
+    }
+    match quiet_period {
+        Some(quiet_period) if quiet_period.is_between(now.naive_local().time()) => Decision::Quiet,
+        _ => Decision::Loud,
+    }
+}
+
+/// Formats how long someone stayed to the minute, e.g. "5h 23m".
+fn format_stay(stay: chrono::Duration) -> String {
+    let minutes = stay.num_minutes();
+    if minutes < 1 {
+        return "less than a minute".into();
+    }
+    humantime::format_duration(std::time::Duration::from_secs(minutes as u64 * 60)).to_string()
+}
+
+/// The device a sighting is of and how long it counts as presence in passive mode.
+fn evidence_ttl(
+    passive: &config::Passive,
+    event: &Event,
+) -> Option<(MacAddr, std::time::Duration)> {
+    match event {
+        Event::Connected { mac, .. } => Some((*mac, passive.dhcp)),
+        Event::Alive { mac, .. } => Some((*mac, passive.arp)),
+        Event::Announced { mac, .. } => Some((*mac, passive.mdns)),
+        Event::Beacon { .. }
+        | Event::Described { .. }
+        | Event::RoundTrip { .. }
+        | Event::Ignored => None,
+    }
+}
+
+/// Sends keepalive requests to devices, usually an ARP request over `network::Socket`.
+pub trait Prober {
+    fn probe(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()>;
+    /// Sends an ICMP echo request, for devices that don't always answer ARP.
+    fn ping(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()>;
+}
+
+/// Probes through a prober shared with other senders, e.g. the socket Wake-on-LAN goes out on.
+impl<P: Prober + ?Sized> Prober for std::sync::Arc<P> {
+    fn probe(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()> {
+        (**self).probe(us, them)
+    }
+
+    fn ping(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> crate::Result<()> {
+        (**self).ping(us, them)
+    }
+}
+
+/// Tells the time the tracker is driven with, so that tests can drive it on a fake clock.
+pub trait Clock {
+    fn now(&self) -> DateTime<Local>;
+}
+
+/// The wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// A second opinion on whether a device is still connected, e.g. from a router or lease file,
+/// asked before announcing a departure so that a glitch in our own capture doesn't cause one.
+pub trait CrossCheck {
+    fn is_present(&mut self, mac: MacAddr) -> crate::Result<bool>;
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Tracking {
+    ip: Ipv4Addr,
+    outstanding: u32,
+    /// When the device came online, unknown when replicated from an older leader
+    #[serde(default)]
+    arrived: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Replica {
+    mac: MacAddr,
+    #[serde(flatten)]
+    tracking: Tracking,
+}
+
+/// Presence state replicated from the leader to standby replicas, so that a failover neither
+/// announces everyone who is already home again nor forgets cooldowns.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    online: Vec<Replica>,
+    last_notified: HashMap<MacAddr, DateTime<Local>>,
+}
+
+/// The presence of a configured device, as reported by the status API.
+#[derive(Debug, Serialize)]
+pub struct DeviceStatus {
+    pub mac: MacAddr,
+    pub user: String,
+    pub online: bool,
+    pub ip: Option<Ipv4Addr>,
+    pub last_seen: Option<DateTime<Local>>,
+    /// When the device came online, if it's online
+    pub arrived: Option<DateTime<Local>>,
+    /// Round-trip times of its keepalives
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt: Option<rtt::Summary>,
+}
+
+/// The latest failure to deliver a notification, as reported by the status API.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub timestamp: DateTime<Local>,
+    pub error: String,
+    /// What the user can do about it, if it's caused by configuration or by people
+    pub remediation: Option<&'static str>,
+}
+
+impl Failure {
+    fn new(error: &crate::error::Error, timestamp: DateTime<Local>) -> Self {
+        Failure {
+            timestamp,
+            error: error.to_string(),
+            remediation: error.remediation(),
+        }
+    }
+}
+
+/// Notifications sent about a user on a day, and the events held back once over the daily limit.
+#[derive(Debug)]
+struct Budget {
+    date: NaiveDate,
+    sent: u32,
+    arrived: u32,
+    left: u32,
+    /// The latest event held back
+    last: Option<(MacAddr, Status, DateTime<Local>)>,
+}
+
+/// Since when a user with reminders has been home or away, and how many reminders were sent.
+#[derive(Debug)]
+struct Stay {
+    status: Status,
+    since: DateTime<Local>,
+    reminded: u32,
+}
+
+/// The presence state machine: turns network events and clock ticks into notifications.
+pub struct PresenceTracker {
+    network_addresses: NetworkAddresses,
+    notifier: Box<dyn Notifier>,
+    prober: Option<Box<dyn Prober>>,
+    store: Option<Box<dyn Store>>,
+    describer: Option<Box<dyn Describer>>,
+    cross_checks: Vec<Box<dyn CrossCheck>>,
+    output: Option<Output>,
+    #[cfg(feature = "dbus")]
+    dbus: Option<crate::dbus::Presence>,
+    cooldown: Option<chrono::Duration>,
+    quiet_period: Option<config::Period>,
+    rules: HashMap<MacAddr, Metadata>,
+    /// Users whose devices, and devices they're subscribed to, are neither tracked nor notified
+    archived: BTreeSet<String>,
+    /// Rules of the archived users, restored when they're unarchived
+    parked: HashMap<MacAddr, Metadata>,
+    online: HashMap<MacAddr, Tracking>,
+    beacon_absence: chrono::Duration,
+    /// How long sightings count as presence in passive mode, where nothing is probed
+    passive: Option<config::Passive>,
+    /// Until when each device is considered present in passive mode, from its latest sightings
+    evidence: HashMap<MacAddr, DateTime<Local>>,
+    beacons: HashMap<MacAddr, DateTime<Local>>,
+    /// Round-trip times of keepalives to configured devices
+    rtts: HashMap<MacAddr, rtt::History>,
+    inventory: Inventory,
+    filter: Filter,
+    stays: HashMap<String, Stay>,
+    muted_until: Option<DateTime<Local>>,
+    /// Alerts for the admin chat, taken with `take_alerts`
+    alerts: Vec<String>,
+    /// Devices expected to always be online that went offline, since when
+    down: HashMap<MacAddr, DateTime<Local>>,
+    /// Not announcing arrivals but alerting about unknown devices, while everyone is away
+    vacation: bool,
+    /// Cleared once a notification is delivered again
+    last_failure: Option<Failure>,
+    /// Notifications failed to deliver in a row
+    failed_deliveries: u32,
+    /// Failed deliveries in a row that raise an alert, 0 for none
+    failure_threshold: u32,
+    daily_limit: Option<u32>,
+    parse_mode: ParseMode,
+    /// Today's budget of each user, by name
+    budgets: HashMap<String, Budget>,
+    /// Tells apart the correlation IDs of replicas, which may well share a pid in containers
+    instance: String,
+    /// Correlation IDs minted since startup
+    minted: u64,
+    /// The event the last notification was caused by, and how many it has caused so far
+    cause: (String, u32),
+}
+
+impl PresenceTracker {
+    pub fn new(config: config::Config, notifier: Box<dyn Notifier>) -> Self {
+        Self {
+            network_addresses: config.interface.addresses,
+            notifier,
+            prober: None,
+            store: None,
+            describer: None,
+            cross_checks: Vec::new(),
+            output: None,
+            #[cfg(feature = "dbus")]
+            dbus: None,
+            cooldown: config.cooldown,
+            quiet_period: config.quiet_period,
+            rules: config.rules,
+            archived: config.archived,
+            parked: config.parked,
+            online: HashMap::new(),
+            beacon_absence: chrono::Duration::from_std(config.ble.absence)
+                .unwrap_or_else(|_| chrono::Duration::days(1)),
+            beacons: HashMap::new(),
+            passive: config.passive,
+            evidence: HashMap::new(),
+            rtts: HashMap::new(),
+            inventory: Inventory::default(),
+            filter: config.ignore,
+            stays: HashMap::new(),
+            muted_until: None,
+            alerts: Vec::new(),
+            down: HashMap::new(),
+            vacation: config.vacation,
+            last_failure: None,
+            failed_deliveries: 0,
+            failure_threshold: config.alerts.delivery_failures,
+            daily_limit: config.daily_limit,
+            parse_mode: config.parse_mode,
+            budgets: HashMap::new(),
+            instance: match &config.leader {
+                Some(leader) => leader.id.clone(),
+                None => format!("{:x}", std::process::id()),
+            },
+            minted: 0,
+            cause: (String::new(), 0),
+        }
+    }
+
+    pub fn with_prober(mut self, prober: Box<dyn Prober>) -> Self {
+        self.prober = Some(prober);
+        self
+    }
+
+    /// Asks `cross_check` too before announcing that a tracked device left, which is announced
+    /// only if no cross-check finds it present.
+    pub fn with_cross_check(mut self, cross_check: Box<dyn CrossCheck>) -> Self {
+        self.cross_checks.push(cross_check);
+        self
+    }
+
+    /// Outputs every presence event as JSON, separately from notifications.
+    pub fn set_output(&mut self, output: Output) {
+        self.output = Some(output);
+    }
+
+    /// Signals arrivals and departures on D-Bus, whether or not they're notified.
+    #[cfg(feature = "dbus")]
+    pub fn set_dbus(&mut self, dbus: crate::dbus::Presence) {
+        self.dbus = Some(dbus);
+    }
+
+    /// Whether any cross-check still finds a device present, failing ones counting as absent.
+    fn is_cross_checked(&mut self, mac: MacAddr) -> bool {
+        self.cross_checks
+            .iter_mut()
+            .any(|cross_check| match cross_check.is_present(mac) {
+                Ok(present) => present,
+                Err(e) => {
+                    println!("Failed to cross-check {}: {}", mac, e);
+                    false
+                }
+            })
+    }
+
+    /// Persists history and the inventory in `store`, starting from the inventory already in it
+    /// so that device descriptions found by earlier runs are reused.
+    pub fn with_store(mut self, store: Box<dyn Store>) -> Self {
+        match store.load_state() {
+            Ok(state) => self.inventory = state.inventory,
+            Err(e) => println!("Failed to load inventory: {}", e),
+        }
+        self.store = Some(store);
+        self
+    }
+
+    /// Describes devices as they come online, set once the event channel the descriptions arrive
+    /// on exists.
+    pub fn set_describer(&mut self, describer: Box<dyn Describer>) {
+        self.describer = Some(describer);
+    }
+
+    /// Whether any device is online, i.e. the clock should be ticking.
+    pub fn is_tracking(&self) -> bool {
+        !self.online.is_empty() || !self.beacons.is_empty()
+    }
+
+    /// Whether a device is online, by responding to keepalives or advertising as a beacon.
+    /// Whether `mac` is a device of a user, who isn't archived.
+    pub fn is_configured(&self, mac: MacAddr) -> bool {
+        self.rules.contains_key(&mac)
+    }
+
+    pub fn is_online(&self, mac: MacAddr) -> bool {
+        self.online.contains_key(&mac) || self.beacons.contains_key(&mac)
+    }
+
+    pub fn probe(&self, mac: MacAddr, ip: Ipv4Addr) {
+        if let Some(prober) = &self.prober {
+            if let Err(e) = prober.probe(&self.network_addresses, &NetworkAddresses::new(mac, ip)) {
+                println!("Failed to send ARP request to {}: {}", ip, e);
+            }
+        }
+    }
+
+    /// Updates the address of a device resolved from its hostname, probing it if not yet online.
+    pub fn resolved(&mut self, mac: MacAddr, ip: Ipv4Addr) {
+        match self.online.get_mut(&mac) {
+            Some(tracking) if tracking.ip != ip => {
+                println!("Device {} moved from {} to {}", mac, tracking.ip, ip);
+                tracking.ip = ip;
+            }
+            Some(_) => (),
+            None => {
+                println!("Resolved: {}", ip);
+                self.probe(mac, ip);
+            }
+        }
+    }
+
+    /// Online devices that are pinged as well as probed, whose echo replies must be captured.
+    pub fn pinged(&self) -> BTreeSet<MacAddr> {
+        if self.passive.is_some() {
+            return BTreeSet::new();
+        }
+        self.online
+            .keys()
+            .filter(|mac| self.rules.get(mac).map_or(false, |metadata| metadata.ping))
+            .copied()
+            .collect()
+    }
+
+    /// Devices that just stopped answering keepalives, whose hostname is worth resolving again.
+    pub fn unanswered(&self) -> Vec<MacAddr> {
+        self.online
+            .iter()
+            .filter(|(_, tracking)| tracking.outstanding == UNANSWERED_BEFORE_RESOLVE)
+            .map(|(mac, _)| *mac)
+            .collect()
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Mints the correlation ID of an event dequeued at `now`, for the run loop to pass along with
+    /// it. The notifications it causes get IDs derived from it.
+    pub fn correlation_id(&mut self, now: DateTime<Local>) -> String {
+        let id = crate::notifier::correlation_id(now, &self.instance, self.minted);
+        self.minted += 1;
+        id
+    }
+
+    /// The ID of the next notification caused by the event with correlation ID `id`.
+    fn notification_id(&mut self, id: &str) -> String {
+        if self.cause.0 != id {
+            self.cause = (id.to_string(), 0);
+        }
+        self.cause.1 += 1;
+        crate::notifier::notification_id(id, self.cause.1 - 1)
+    }
+
+    pub fn handle_event(&mut self, event: Event, now: DateTime<Local>, id: &str) {
+        if self.filter.ignores(&event) {
+            return;
+        }
+        if let Some(output) = &self.output {
+            let name = match &event {
+                Event::Connected { mac, .. }
+                | Event::Alive { mac, .. }
+                | Event::Announced { mac, .. }
+                | Event::Beacon { mac } => self.rules.get(mac).map(|metadata| &*metadata.name),
+                Event::Described { .. } | Event::RoundTrip { .. } | Event::Ignored => None,
+            };
+            output.event(&event, name, now);
+        }
+        if let Event::Connected {
+            mac,
+            hostname: Some(hostname),
+            ..
+        } = &event
+        {
+            self.check_hostname(*mac, hostname);
+        }
+        if self.vacation {
+            self.check_unknown(&event);
+        }
+        self.inventory.record(&event, now);
+        if let Some((mac, ttl)) = self.passive.as_ref().and_then(|p| evidence_ttl(p, &event)) {
+            if self.rules.contains_key(&mac) {
+                let expires = now
+                    + chrono::Duration::from_std(ttl).unwrap_or_else(|_| chrono::Duration::days(1));
+                let evidence = self.evidence.entry(mac).or_insert(expires);
+                *evidence = (*evidence).max(expires);
+            }
+        }
+        match event {
+            Event::Connected { mac, .. } => {
+                if self.is_online(mac) {
+                    println!("[{}] Device {} reconnected, skipping notification", id, mac);
+                } else {
+                    self.notify(mac, Status::Arrived, None, now, id);
+                }
+            }
+            Event::Alive { mac, ip } | Event::Announced { mac, ip } => self.alive(mac, ip, now, id),
+            Event::Beacon { mac } => {
+                if self.rules.contains_key(&mac) {
+                    let online = self.is_online(mac);
+                    if self.beacons.insert(mac, now).is_none() && !online {
+                        self.check_back_online(mac, now);
+                        self.notify(mac, Status::Arrived, None, now, id);
+                    }
+                }
+            }
+            Event::RoundTrip { mac, rtt } => {
+                if self.rules.contains_key(&mac) {
+                    self.rtts.entry(mac).or_default().record(rtt, now);
+                }
+            }
+            Event::Described { .. } | Event::Ignored => (),
+        }
+    }
+
+    /// Decides on an arrival or departure recorded in the history as if it just happened, sending
+    /// the notification it would have led to, for `houserat simulate` to try out another config.
+    pub fn replay_entry(&mut self, entry: &history::Entry, id: &str) {
+        let stay = entry
+            .stay
+            .and_then(|stay| chrono::Duration::from_std(stay).ok());
+        self.notify(entry.mac, entry.status, stay, entry.timestamp, id);
+    }
+
+    /// Round-trip times of keepalives to a configured device, once any was sent.
+    pub fn rtt(&self, mac: MacAddr) -> Option<&rtt::History> {
+        self.rtts.get(&mac)
+    }
+
+    /// Alerts when a device that opted in sends a DHCP hostname other than the one last seen, which
+    /// may mean a factory reset, a new owner of a recycled MAC or spoofing.
+    fn check_hostname(&mut self, mac: MacAddr, hostname: &str) {
+        let metadata = match self.rules.get(&mac) {
+            Some(metadata) if metadata.hostname_alert => metadata,
+            _ => return,
+        };
+        let previous = self
+            .inventory
+            .get(&mac)
+            .and_then(|sighting| sighting.hostname.as_deref());
+        if let Some(previous) = previous.filter(|previous| *previous != hostname) {
+            self.alerts.push(format!(
+                "{}'s device {} changed its hostname from {} to {}",
+                metadata.name, mac, previous, hostname
+            ));
+        }
+    }
+
+    /// Alerts about a device houserat has never seen before, for vacation mode.
+    fn check_unknown(&mut self, event: &Event) {
+        let (mac, ip, hostname) = match event {
+            Event::Connected { mac, ip, hostname } => (*mac, *ip, hostname.as_deref()),
+            Event::Alive { mac, ip } | Event::Announced { mac, ip } => (*mac, Some(*ip), None),
+            Event::Beacon { .. }
+            | Event::Described { .. }
+            | Event::RoundTrip { .. }
+            | Event::Ignored => return,
+        };
+        if self.rules.contains_key(&mac) || self.inventory.get(&mac).is_some() {
+            return;
+        }
+        let mut details: Vec<String> = ip.iter().map(Ipv4Addr::to_string).collect();
+        details.extend(hostname.map(String::from));
+        self.alerts.push(if details.is_empty() {
+            format!("Unknown device {} joined the network during vacation", mac)
+        } else {
+            format!(
+                "Unknown device {} ({}) joined the network during vacation",
+                mac,
+                details.join(", ")
+            )
+        });
+    }
+
+    /// Alerts when a device expected to always be online goes offline, e.g. a NAS or doorbell.
+    fn check_offline(&mut self, mac: MacAddr, now: DateTime<Local>) {
+        let metadata = match self.rules.get(&mac) {
+            Some(metadata) if metadata.always_online => metadata,
+            _ => return,
+        };
+        let last_seen = match self.inventory.get(&mac) {
+            Some(sighting) => sighting.last_seen.format("%a %-d %b %H:%M").to_string(),
+            None => "never".to_string(),
+        };
+        self.alerts.push(format!(
+            "{}'s device {} went offline, last seen {}",
+            metadata.name, mac, last_seen
+        ));
+        self.down.insert(mac, now);
+    }
+
+    fn check_back_online(&mut self, mac: MacAddr, now: DateTime<Local>) {
+        if let (Some(since), Some(metadata)) = (self.down.remove(&mac), self.rules.get(&mac)) {
+            self.alerts.push(format!(
+                "{}'s device {} is back online after {}",
+                metadata.name,
+                mac,
+                format_stay(now - since)
+            ));
+        }
+    }
+
+    /// The latest failure to deliver a notification, unless one was delivered since.
+    pub fn last_failure(&self) -> Option<&Failure> {
+        self.last_failure.as_ref()
+    }
+
+    pub fn vacation(&self) -> bool {
+        self.vacation
+    }
+
+    /// Turns vacation mode on or off.
+    pub fn set_vacation(&mut self, on: bool) {
+        self.vacation = on;
+    }
+
+    /// Takes the alerts for the admin chat raised since last called.
+    pub fn take_alerts(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.alerts)
+    }
+
+    fn alive(&mut self, mac: MacAddr, ip: Ipv4Addr, now: DateTime<Local>, id: &str) {
+        if !self.rules.contains_key(&mac) {
+            return;
+        }
+        println!("[{}] Device {} is alive", id, mac);
+        match self.online.entry(mac) {
+            hash_map::Entry::Occupied(mut occupied) => occupied.get_mut().outstanding = 0,
+            hash_map::Entry::Vacant(vacant) => {
+                vacant.insert(Tracking {
+                    ip,
+                    outstanding: 0,
+                    arrived: Some(now),
+                });
+                if let Some(describer) = &self.describer {
+                    describer.describe(mac, ip);
+                }
+                self.check_back_online(mac, now);
+            }
+        }
+    }
+
+    pub fn handle_clock(&mut self, now: DateTime<Local>, id: &str) {
+        let mut left = Vec::new();
+        if let Some(passive) = &self.passive {
+            let evidence = &mut self.evidence;
+            let arp = chrono::Duration::from_std(passive.arp)
+                .unwrap_or_else(|_| chrono::Duration::days(1));
+            for mac in self.online.keys() {
+                // e.g. after taking over from the leader, which doesn't replicate sightings
+                let expires = *evidence.entry(*mac).or_insert(now + arp);
+                if now >= expires {
+                    println!("[{}] Assuming {} left after its sightings expired", id, mac);
+                    left.push(*mac);
+                }
+            }
+            evidence.retain(|_, expires| now < *expires);
+        } else {
+            for (mac, tracking) in &mut self.online {
+                if tracking.outstanding < ALLOWED_PACKETS_LOST {
+                    println!(
+                        "Sending keepalive to {} ({}), outstanding: {}",
+                        tracking.ip, mac, tracking.outstanding
+                    );
+                    let them = NetworkAddresses::new(*mac, tracking.ip);
+                    let ping = tracking.outstanding > 0
+                        && self.rules.get(mac).map_or(false, |metadata| metadata.ping);
+                    let sent = match &self.prober {
+                        Some(prober) => {
+                            let sent = prober.probe(&self.network_addresses, &them);
+                            if ping {
+                                println!("ARP unanswered, also pinging {} ({})", tracking.ip, mac);
+                                sent.and(prober.ping(&self.network_addresses, &them))
+                            } else {
+                                sent
+                            }
+                        }
+                        None => Ok(()),
+                    };
+                    match sent {
+                        Ok(()) => tracking.outstanding += 1,
+                        Err(e) => println!("Failed to send keepalive: {}", e),
+                    }
+                } else {
+                    println!(
+                        "[{}] Assuming {} left after not receiving response for {} seconds",
+                        id,
+                        mac,
+                        tracking.outstanding * TICK_SECS
+                    );
+                    left.push(*mac);
+                }
+            }
+        }
+        let beacon_absence = self.beacon_absence;
+        self.beacons.retain(|mac, last_seen| {
+            let present = now - *last_seen < beacon_absence;
+            if !present {
+                println!(
+                    "[{}] Assuming {} left after not advertising for {} seconds",
+                    id,
+                    mac,
+                    beacon_absence.num_seconds()
+                );
+                left.push(*mac);
+            }
+            present
+        });
+        for mac in left {
+            if self.online.contains_key(&mac) && self.is_cross_checked(mac) {
+                println!(
+                    "[{}] {} is still present according to a cross-check, not leaving",
+                    id, mac
+                );
+                // start over with keepalives, or with the grace period of a failover in passive mode
+                if let Some(tracking) = self.online.get_mut(&mac) {
+                    tracking.outstanding = 0;
+                }
+                continue;
+            }
+            let arrived = self
+                .online
+                .remove(&mac)
+                .and_then(|tracking| tracking.arrived);
+            if self.is_online(mac) {
+                println!(
+                    "[{}] {} is still seen by another source, not notifying",
+                    id, mac
+                );
+            } else {
+                self.check_offline(mac, now);
+                let stay = arrived.map(|arrived| now - arrived);
+                self.notify(mac, Status::Left, stay, now, id);
+            }
+        }
+    }
+
+    /// Configured devices ordered by user, with their IP and when they were last seen if known.
+    pub fn devices(&self) -> Vec<DeviceStatus> {
+        let mut devices: Vec<DeviceStatus> = self
+            .rules
+            .iter()
+            .map(|(mac, metadata)| {
+                let sighting = self.inventory.get(mac);
+                DeviceStatus {
+                    mac: *mac,
+                    user: metadata.name.clone(),
+                    online: self.is_online(*mac),
+                    ip: self
+                        .online
+                        .get(mac)
+                        .map(|tracking| tracking.ip)
+                        .or_else(|| sighting.and_then(|sighting| sighting.ip)),
+                    last_seen: sighting.map(|sighting| sighting.last_seen),
+                    arrived: self.online.get(mac).and_then(|tracking| tracking.arrived),
+                    rtt: self.rtts.get(mac).and_then(rtt::History::summary),
+                }
+            })
+            .collect();
+        devices.sort_by(|a, b| (&a.user, a.mac).cmp(&(&b.user, b.mac)));
+        devices
+    }
+
+    /// Persists a change made by a bot command in the store, if there's one.
+    pub fn append_audit(&mut self, audited: &crate::commands::Audited) -> crate::Result<()> {
+        match &mut self.store {
+            Some(store) => store.append_audit(audited),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops or resumes tracking and notifying the devices of `user` and those `user` is subscribed
+    /// to, recording it in the store's state like `houserat archive` does. Returns whether it changed.
+    pub fn set_archived(&mut self, user: &str, archived: bool) -> crate::Result<bool> {
+        let known = self.archived.contains(user)
+            || self
+                .rules
+                .values()
+                .chain(self.parked.values())
+                .any(|metadata| metadata.name == user || metadata.subscriber_name == user);
+        if !known {
+            return Err(crate::error::Error::UnknownUser { user: user.into() });
+        }
+        if let Some(store) = &mut self.store {
+            let mut state = store.load_state()?;
+            if !archived && self.archived.contains(user) && !state.archived.contains(user) {
+                return Err(crate::error::Error::ArchivedInConfig { user: user.into() });
+            }
+            let changed = if archived {
+                state.archived.insert(user.into())
+            } else {
+                state.archived.remove(user)
+            };
+            if changed {
+                store.save_state(&state)?;
+            }
+        }
+
+        let changed = if archived {
+            self.archived.insert(user.into())
+        } else {
+            self.archived.remove(user)
+        };
+        let is_archived = |archived: &BTreeSet<String>, metadata: &Metadata| {
+            archived.contains(&metadata.name) || archived.contains(&metadata.subscriber_name)
+        };
+        if archived {
+            let (parked, rules): (HashMap<_, _>, _) = std::mem::take(&mut self.rules)
+                .into_iter()
+                .partition(|(_, metadata)| is_archived(&self.archived, metadata));
+            self.rules = rules;
+            for mac in parked.keys() {
+                self.online.remove(mac);
+                self.beacons.remove(mac);
+                self.evidence.remove(mac);
+            }
+            self.parked.extend(parked);
+        } else {
+            let (parked, rules) = std::mem::take(&mut self.parked)
+                .into_iter()
+                .partition(|(_, metadata)| is_archived(&self.archived, metadata));
+            self.parked = parked;
+            self.rules.extend(rules);
+        }
+        Ok(changed)
+    }
+
+    /// Stops sending notifications until `until`, or resumes them if `None`.
+    pub fn mute(&mut self, until: Option<DateTime<Local>>) {
+        self.muted_until = until;
+    }
+
+    pub fn muted_until(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        self.muted_until.filter(|until| *until > now)
+    }
+
+    /// Whether any user has reminders, i.e. `remind` should be called periodically.
+    pub fn has_reminders(&self) -> bool {
+        self.rules
+            .values()
+            .any(|metadata| metadata.reminder.is_some())
+    }
+
+    /// Sends the "still away"/"still home" reminders that are due, a user being home while any of
+    /// their devices is online. Absences and stays are counted from when houserat first saw them.
+    /// `id` is the correlation ID minted for the tick.
+    pub fn remind(&mut self, now: DateTime<Local>, id: &str) {
+        let mut users: HashMap<&str, (MacAddr, bool)> = HashMap::new();
+        for (mac, metadata) in &self.rules {
+            if metadata.reminder.is_some() {
+                let online = self.is_online(*mac);
+                let user = users.entry(&metadata.name).or_insert((*mac, online));
+                if online && !user.1 {
+                    *user = (*mac, online);
+                }
+            }
+        }
+
+        let muted = self.muted_until(now).is_some();
+        let mut due = Vec::new();
+        for (name, (mac, home)) in users {
+            let status = if home { Status::Arrived } else { Status::Left };
+            let stay = self.stays.entry(name.into()).or_insert(Stay {
+                status,
+                since: now,
+                reminded: 0,
+            });
+            if stay.status != status {
+                *stay = Stay {
+                    status,
+                    since: now,
+                    reminded: 0,
+                };
+                continue;
+            }
+            let reminder = self.rules[&mac].reminder.as_ref().unwrap();
+            let interval = match status {
+                Status::Arrived => reminder.home,
+                Status::Left => reminder.away,
+            };
+            let interval = match interval.and_then(|i| chrono::Duration::from_std(i).ok()) {
+                Some(interval) => interval,
+                None => continue,
+            };
+            let elapsed = interval * (stay.reminded + 1) as i32;
+            if stay.reminded < reminder.max && now - stay.since >= elapsed {
+                stay.reminded += 1;
+                if muted {
+                    println!("Muted, not reminding about {}", name);
+                } else {
+                    due.push((mac, status, elapsed));
+                }
+            }
+        }
+
+        for (mac, status, elapsed) in due {
+            let metadata = &self.rules[&mac];
+            let quiet = self
+                .quiet_period
+                .as_ref()
+                .map_or(false, |period| period.is_between(now.naive_local().time()));
+            let text = format!(
+                "{} {}",
+                metadata.mention(self.parse_mode),
+                self.parse_mode.escape(&format!(
+                    "has been {} for {}",
+                    if status == Status::Arrived {
+                        "home"
+                    } else {
+                        "away"
+                    },
+                    humantime::format_duration(elapsed.to_std().unwrap_or_default())
+                ))
+            );
+            let correlation_id = self.notification_id(id);
+            let metadata = &self.rules[&mac];
+            println!(
+                "[{}] Reminding {}: {}",
+                correlation_id, metadata.subscriber_name, text
+            );
+            let notification = Notification {
+                correlation_id,
+                mac,
+                name: metadata.name.clone(),
+                status,
+                timestamp: now,
+                subscriber_name: metadata.subscriber_name.clone(),
+                identity: metadata.identity.clone(),
+                text,
+                quiet,
+                reminder: true,
+                digest: false,
+            };
+            let result = self.notifier.notify(&notification);
+            self.delivered("reminder", &notification.correlation_id, result, now);
+        }
+    }
+
+    /// Whether there's a daily limit, i.e. `send_digests` should be called periodically.
+    pub fn has_daily_limit(&self) -> bool {
+        self.daily_limit.is_some()
+    }
+
+    /// Sends a digest of the events held back on previous days, once per user and day. `id` is the
+    /// correlation ID minted for the tick, or of the event that rolled the day over.
+    pub fn send_digests(&mut self, now: DateTime<Local>, id: &str) {
+        let today = now.date_naive();
+        let past: Vec<String> = self
+            .budgets
+            .iter()
+            .filter(|(_, budget)| budget.date != today)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in past {
+            let budget = self.budgets.remove(&name).unwrap();
+            let (mac, status, timestamp) = match budget.last {
+                Some(last) => last,
+                None => continue,
+            };
+            let metadata = match self.rules.get(&mac) {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            let quiet = self
+                .quiet_period
+                .as_ref()
+                .map_or(false, |period| period.is_between(now.naive_local().time()));
+            let text = format!(
+                "{} {}",
+                metadata.mention(self.parse_mode),
+                self.parse_mode.escape(&format!(
+                    "arrived {} and left {} more times on {}, last {} at {}",
+                    budget.arrived,
+                    budget.left,
+                    budget.date.format("%a %-d %b"),
+                    status,
+                    timestamp.format("%H:%M")
+                ))
+            );
+            let correlation_id = self.notification_id(id);
+            let metadata = &self.rules[&mac];
+            println!(
+                "[{}] Sending digest to {}: {}",
+                correlation_id, metadata.subscriber_name, text
+            );
+            let notification = Notification {
+                correlation_id,
+                mac,
+                name: metadata.name.clone(),
+                status,
+                timestamp: now,
+                subscriber_name: metadata.subscriber_name.clone(),
+                identity: metadata.identity.clone(),
+                text,
+                quiet,
+                reminder: false,
+                digest: true,
+            };
+            let result = self.notifier.notify(&notification);
+            self.delivered("digest", &notification.correlation_id, result, now);
+        }
+    }
+
+    /// Merges devices seen since startup into the inventory kept in the store's state.
+    pub fn save_inventory(&mut self) -> crate::Result<()> {
+        if let Some(store) = &mut self.store {
+            let mut state = store.load_state()?;
+            state.inventory.merge(self.inventory.clone());
+            store.save_state(&state)?;
+        }
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            online: self
+                .online
+                .iter()
+                .map(|(mac, tracking)| Replica {
+                    mac: *mac,
+                    tracking: Tracking {
+                        ip: tracking.ip,
+                        outstanding: tracking.outstanding,
+                        arrived: tracking.arrived,
+                    },
+                })
+                .collect(),
+            last_notified: self
+                .rules
+                .iter()
+                .filter_map(|(mac, metadata)| metadata.last_notified.map(|t| (*mac, t)))
+                .collect(),
+        }
+    }
+
+    /// Replaces presence state with the leader's, keeping the latest notification time of each.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let rules = &self.rules;
+        self.online = snapshot
+            .online
+            .into_iter()
+            .filter(|replica| rules.contains_key(&replica.mac))
+            .map(|replica| (replica.mac, replica.tracking))
+            .collect();
+        for (mac, last_notified) in snapshot.last_notified {
+            if let Some(metadata) = self.rules.get_mut(&mac) {
+                metadata.last_notified = metadata.last_notified.max(Some(last_notified));
+            }
+        }
+    }
+
+    /// Notifies about an arrival or departure, `stay` being how long a departing device was home
+    /// and `id` the correlation ID minted when the event causing it was dequeued.
+    fn notify(
+        &mut self,
+        mac: MacAddr,
+        status: Status,
+        stay: Option<chrono::Duration>,
+        now: DateTime<Local>,
+        id: &str,
+    ) {
+        if !self.rules.contains_key(&mac) {
+            println!("[{}] Unknown MAC {} connected, ignoring", id, mac);
+            return;
+        }
+        if self.daily_limit.is_some() {
+            // a budget from yesterday must be summed up before today's replaces it
+            self.send_digests(now, id);
+        }
+        // taken by this notification even if it's held back, as it's recorded in the history
+        let id = self.notification_id(id);
+        let metadata = self.rules.get_mut(&mac).unwrap();
+
+        if let Some(output) = &self.output {
+            let ip = self.inventory.get(&mac).and_then(|sighting| sighting.ip);
+            output.presence(mac, ip, &metadata.name, status, now);
+        }
+        #[cfg(feature = "dbus")]
+        if let Some(dbus) = &self.dbus {
+            dbus.presence(mac, self.inventory.get(&mac).and_then(|s| s.ip), status);
+        }
+
+        if let Some(store) = &mut self.store {
+            let entry = history::Entry {
+                timestamp: now,
+                mac,
+                name: metadata.name.clone(),
+                status,
+                stay: stay.and_then(|stay| stay.to_std().ok()),
+                rtt: match status {
+                    Status::Left => self.rtts.get(&mac).and_then(rtt::History::summary),
+                    Status::Arrived => None,
+                },
+                correlation_id: Some(id.clone()),
+            };
+            if let Err(e) = store.append_history(&entry) {
+                println!("[{}] Failed to record history: {}", id, e);
+            }
+        }
+
+        if let Some(until) = self.muted_until.filter(|until| *until > now) {
+            println!(
+                "[{}] {} ({}) {} while muted until {}, ignoring",
+                id, metadata.name, mac, status, until
+            );
+            return;
+        }
+
+        if self.vacation && status == Status::Arrived {
+            println!(
+                "[{}] {} ({}) arrived during vacation, ignoring",
+                id, metadata.name, mac
+            );
+            return;
+        }
+
+        let is_quiet = match decide(metadata, &self.cooldown, &self.quiet_period, now) {
+            Decision::Cooldown => {
+                println!(
+                    "[{}] {} ({}) {} during cooldown, ignoring",
+                    id, metadata.name, mac, status
+                );
+                return;
+            }
+            Decision::Quiet => true,
+            Decision::Loud => false,
+        };
+
+        if let Some(limit) = self.daily_limit {
+            let date = now.date_naive();
+            let budget = self
+                .budgets
+                .entry(metadata.name.clone())
+                .or_insert_with(|| Budget {
+                    date,
+                    sent: 0,
+                    arrived: 0,
+                    left: 0,
+                    last: None,
+                });
+            if budget.sent >= limit {
+                println!(
+                    "[{}] {} ({}) {} after {} notifications today, holding back for the digest",
+                    id, metadata.name, mac, status, limit
+                );
+                match status {
+                    Status::Arrived => budget.arrived += 1,
+                    Status::Left => budget.left += 1,
+                }
+                budget.last = Some((mac, status, now));
+                return;
+            }
+            budget.sent += 1;
+        }
+
+        println!(
+            "[{}] {} ({}) {}, notifying {} {}",
+            id,
+            metadata.name,
+            mac,
+            status,
+            metadata.subscriber_name,
+            if is_quiet { "quietly" } else { "loudly" }
+        );
+
+        let notification = Notification {
+            correlation_id: id,
+            mac,
+            name: metadata.name.clone(),
+            status,
+            timestamp: now,
+            subscriber_name: metadata.subscriber_name.clone(),
+            identity: metadata.identity.clone(),
+            text: format!(
+                "{} {}",
+                metadata.mention(self.parse_mode),
+                self.parse_mode
+                    .escape(&match self.inventory.description(&mac) {
+                        Some(description) if status == Status::Arrived => {
+                            format!("{}: {}", status, description)
+                        }
+                        _ => match stay {
+                            Some(stay) => format!("{}, was home for {}", status, format_stay(stay)),
+                            None => status.to_string(),
+                        },
+                    })
+            ),
+            quiet: is_quiet,
+            reminder: false,
+            digest: false,
+        };
+        let result = self.notifier.notify(&notification);
+        self.delivered("notification", &notification.correlation_id, result, now);
+    }
+
+    /// Records the result of delivering a notification, alerting once deliveries keep failing and
+    /// again when they recover.
+    fn delivered(
+        &mut self,
+        kind: &str,
+        correlation_id: &str,
+        result: crate::Result<()>,
+        now: DateTime<Local>,
+    ) {
+        match result {
+            Ok(()) => {
+                if self.failure_threshold > 0 && self.failed_deliveries >= self.failure_threshold {
+                    self.alerts.push(format!(
+                        "Delivering notifications again after {} failures",
+                        self.failed_deliveries
+                    ));
+                }
+                self.failed_deliveries = 0;
+                self.last_failure = None;
+            }
+            Err(err) => {
+                println!(
+                    "[{}] Error sending {}: {}",
+                    correlation_id,
+                    kind,
+                    err.report()
+                );
+                self.failed_deliveries += 1;
+                if self.failed_deliveries == self.failure_threshold {
+                    self.alerts.push(format!(
+                        "Failed to deliver the last {} notifications: {}",
+                        self.failed_deliveries, err
+                    ));
+                }
+                self.last_failure = Some(Failure::new(&err, now));
+            }
+        }
+    }
+
+    /// A summary of the devices first seen since `since` that belong to no user, if any.
+    pub fn unknown_summary(&self, since: DateTime<Local>) -> Option<String> {
+        let unknown: Vec<String> = self
+            .inventory
+            .iter()
+            .filter(|(mac, sighting)| sighting.first_seen >= since && !self.rules.contains_key(mac))
+            .map(|(mac, sighting)| {
+                let mut details: Vec<String> =
+                    sighting.ip.iter().map(Ipv4Addr::to_string).collect();
+                details.extend(sighting.hostname.clone());
+                if details.is_empty() {
+                    mac.to_string()
+                } else {
+                    format!("{} ({})", mac, details.join(", "))
+                }
+            })
+            .collect();
+        if unknown.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{} unknown device{} first seen since {}: {}",
+            unknown.len(),
+            if unknown.len() == 1 { "" } else { "s" },
+            since.format("%Y-%m-%d %H:%M"),
+            unknown.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Notifications sent, by device and status
+    type Sent = Rc<RefCell<Vec<(MacAddr, Status)>>>;
+
+    struct Recorder(Sent);
+
+    impl Notifier for Recorder {
+        fn notify(&self, notification: &Notification) -> crate::Result<()> {
+            self.0
+                .borrow_mut()
+                .push((notification.mac, notification.status));
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Probes {
+        arp: RefCell<u32>,
+        ping: RefCell<u32>,
+    }
+
+    impl Prober for Rc<Probes> {
+        fn probe(&self, _us: &NetworkAddresses, _them: &NetworkAddresses) -> crate::Result<()> {
+            *self.arp.borrow_mut() += 1;
+            Ok(())
+        }
+
+        fn ping(&self, _us: &NetworkAddresses, _them: &NetworkAddresses) -> crate::Result<()> {
+            *self.ping.borrow_mut() += 1;
+            Ok(())
+        }
+    }
+
+    impl CrossCheck for Rc<RefCell<bool>> {
+        fn is_present(&mut self, _mac: MacAddr) -> crate::Result<bool> {
+            Ok(*self.borrow())
+        }
+    }
+
+    const MAC: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+    /// The correlation ID of events in tests that don't look at notifications' IDs
+    const ID: &str = "test";
+
+    fn tracker() -> (PresenceTracker, Sent) {
+        let mut rules = HashMap::new();
+        rules.insert(
+            MAC,
+            Metadata::new(
+                "User".to_string(),
+                None,
+                None,
+                "Sub".to_string(),
+                Identity {
+                    telegram: Some(1),
+                    ..Default::default()
+                },
+            ),
+        );
+        let config = config::Config {
+            rules,
+            ..config::Config::for_tests()
+        };
+        let notifications = Rc::new(RefCell::new(Vec::new()));
+        let tracker = PresenceTracker::new(config, Box::new(Recorder(notifications.clone())));
+        (tracker, notifications)
+    }
+
+    #[test]
+    fn test_arrive_and_leave() {
+        let (mut tracker, notifications) = tracker();
+        let now = Local::now();
+
+        tracker.handle_event(
+            Event::Connected {
+                mac: MAC,
+                ip: None,
+                hostname: None,
+            },
+            now,
+            ID,
+        );
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        assert!(tracker.is_tracking());
+        assert_eq!(*notifications.borrow(), vec![(MAC, Status::Arrived)]);
+
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(now, ID);
+        }
+        assert!(!tracker.is_tracking());
+        assert_eq!(
+            *notifications.borrow(),
+            vec![(MAC, Status::Arrived), (MAC, Status::Left)]
+        );
+    }
+
+    struct CorrelationIds(Rc<RefCell<Vec<String>>>);
+
+    impl Notifier for CorrelationIds {
+        fn notify(&self, notification: &Notification) -> crate::Result<()> {
+            self.0
+                .borrow_mut()
+                .push(notification.correlation_id.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_correlation_id() {
+        let (tracker, _) = tracker();
+        let other = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x56);
+        let mut rules = tracker.rules;
+        rules.insert(
+            other,
+            Metadata::new(
+                "Other".to_string(),
+                None,
+                None,
+                "Sub".to_string(),
+                Identity::default(),
+            ),
+        );
+        let config = config::Config {
+            rules,
+            leader: Some(config::Leader {
+                id: "replica".to_string(),
+                lease: std::time::Duration::from_secs(30),
+            }),
+            ..config::Config::for_tests()
+        };
+        let ids = Rc::new(RefCell::new(Vec::new()));
+        let mut tracker = PresenceTracker::new(config, Box::new(CorrelationIds(ids.clone())));
+        let now = Local::now();
+        let prefix = format!("{:x}-replica-", now.timestamp_millis());
+
+        // every ID minted is new, even for events that don't notify, like unknown devices
+        let unknown = tracker.correlation_id(now);
+        tracker.handle_event(
+            Event::Connected {
+                mac: MacAddr(0xaa, 0, 0, 0, 0, 1),
+                ip: None,
+                hostname: None,
+            },
+            now,
+            &unknown,
+        );
+        assert_eq!(unknown, format!("{}0", prefix));
+        let mut arrivals = Vec::new();
+        for mac in [MAC, other] {
+            let id = tracker.correlation_id(now);
+            tracker.handle_event(
+                Event::Connected {
+                    mac,
+                    ip: None,
+                    hostname: None,
+                },
+                now,
+                &id,
+            );
+            arrivals.push(id);
+            let id = tracker.correlation_id(now);
+            tracker.handle_event(Event::Alive { mac, ip: IP }, now, &id);
+        }
+        assert_eq!(
+            arrivals,
+            vec![format!("{}1", prefix), format!("{}3", prefix)]
+        );
+        let mut tick = String::new();
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tick = tracker.correlation_id(now);
+            tracker.handle_clock(now, &tick);
+        }
+
+        // a notification's ID is its event's, numbered among the notifications the event caused
+        assert_eq!(
+            *ids.borrow(),
+            vec![
+                format!("{}-0", arrivals[0]),
+                format!("{}-0", arrivals[1]),
+                format!("{}-0", tick),
+                format!("{}-1", tick),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_announced() {
+        let (mut tracker, notifications) = tracker();
+        let now = Local::now();
+
+        tracker.handle_event(
+            Event::Connected {
+                mac: MAC,
+                ip: None,
+                hostname: None,
+            },
+            now,
+            ID,
+        );
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        // a device that stopped answering ARP but keeps announcing itself hasn't left
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(now, ID);
+            tracker.handle_event(Event::Announced { mac: MAC, ip: IP }, now, ID);
+        }
+        assert!(tracker.is_online(MAC));
+        assert_eq!(*notifications.borrow(), vec![(MAC, Status::Arrived)]);
+    }
+
+    #[test]
+    fn test_cross_check() {
+        let (tracker, notifications) = tracker();
+        let present = Rc::new(RefCell::new(true));
+        let mut tracker = tracker.with_cross_check(Box::new(present.clone()));
+        let now = Local::now();
+
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(now, ID);
+        }
+        assert!(tracker.is_online(MAC));
+        assert!(notifications.borrow().is_empty());
+
+        *present.borrow_mut() = false;
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(now, ID);
+        }
+        assert!(!tracker.is_online(MAC));
+        assert_eq!(*notifications.borrow(), vec![(MAC, Status::Left)]);
+    }
+
+    #[test]
+    fn test_format_stay() {
+        assert_eq!(
+            format_stay(chrono::Duration::seconds(5 * 3600 + 23 * 60 + 59)),
+            "5h 23m"
+        );
+        assert_eq!(
+            format_stay(chrono::Duration::seconds(59)),
+            "less than a minute"
+        );
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let (mut tracker, notifications) = tracker();
+        let now = Local::now();
+
+        tracker.handle_event(
+            Event::Connected {
+                mac: MAC,
+                ip: None,
+                hostname: None,
+            },
+            now,
+            ID,
+        );
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        tracker.handle_clock(now, ID);
+        tracker.handle_event(
+            Event::Connected {
+                mac: MAC,
+                ip: None,
+                hostname: None,
+            },
+            now,
+            ID,
+        );
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        for _ in 0..ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(now, ID);
+        }
+        assert!(tracker.is_tracking());
+        assert_eq!(*notifications.borrow(), vec![(MAC, Status::Arrived)]);
+    }
+
+    #[test]
+    fn test_failover() {
+        let (mut leader, _) = tracker();
+        let (mut standby, notifications) = tracker();
+        let now = Local::now();
+
+        leader.handle_event(
+            Event::Connected {
+                mac: MAC,
+                ip: None,
+                hostname: None,
+            },
+            now,
+            ID,
+        );
+        leader.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        standby.restore(leader.snapshot());
+        assert!(standby.is_online(MAC));
+
+        standby.handle_event(
+            Event::Connected {
+                mac: MAC,
+                ip: None,
+                hostname: None,
+            },
+            now,
+            ID,
+        );
+        assert!(notifications.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_beacon_absence() {
+        let (mut tracker, notifications) = tracker();
+        let now = Local::now();
+        let absence = tracker.beacon_absence;
+
+        tracker.handle_event(Event::Beacon { mac: MAC }, now, ID);
+        tracker.handle_event(Event::Beacon { mac: MAC }, now, ID);
+        tracker.handle_clock(now + absence / 2, ID);
+        assert!(tracker.is_online(MAC));
+        tracker.handle_clock(now + absence, ID);
+        assert!(!tracker.is_tracking());
+        assert_eq!(
+            *notifications.borrow(),
+            vec![(MAC, Status::Arrived), (MAC, Status::Left)]
+        );
+    }
+
+    #[test]
+    fn test_ping_fallback() {
+        let (tracker, _) = tracker();
+        let probes = Rc::new(Probes::default());
+        let mut tracker = tracker.with_prober(Box::new(probes.clone()));
+        tracker.rules.get_mut(&MAC).unwrap().ping = true;
+        let now = Local::now();
+
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        tracker.handle_clock(now, ID);
+        assert_eq!((*probes.arp.borrow(), *probes.ping.borrow()), (1, 0));
+        tracker.handle_clock(now, ID);
+        assert_eq!((*probes.arp.borrow(), *probes.ping.borrow()), (2, 1));
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        tracker.handle_clock(now, ID);
+        assert_eq!((*probes.arp.borrow(), *probes.ping.borrow()), (3, 1));
+    }
+
+    #[test]
+    fn test_reminders() {
+        let (mut tracker, notifications) = tracker();
+        tracker.rules.get_mut(&MAC).unwrap().reminder = Some(config::Reminder {
+            away: Some(std::time::Duration::from_secs(3600)),
+            home: None,
+            max: 2,
+        });
+        let hour = chrono::Duration::hours(1);
+        let now = Local::now();
+
+        tracker.remind(now, ID);
+        tracker.remind(now + hour / 2, ID);
+        assert!(notifications.borrow().is_empty());
+        for hours in 1..=4 {
+            tracker.remind(now + hour * hours, ID);
+        }
+        assert_eq!(
+            *notifications.borrow(),
+            vec![(MAC, Status::Left), (MAC, Status::Left)]
+        );
+
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        tracker.remind(now + hour * 10, ID);
+        assert_eq!(notifications.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_mute() {
+        let (mut tracker, notifications) = tracker();
+        let now = Local::now();
+
+        tracker.mute(Some(now + chrono::Duration::hours(1)));
+        tracker.handle_event(
+            Event::Connected {
+                mac: MAC,
+                ip: None,
+                hostname: None,
+            },
+            now,
+            ID,
+        );
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        assert!(notifications.borrow().is_empty());
+        let devices = tracker.devices();
+        assert_eq!(devices.len(), 1);
+        assert!(devices[0].online);
+        assert_eq!(devices[0].ip, Some(IP));
+
+        assert_eq!(tracker.muted_until(now + chrono::Duration::hours(2)), None);
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(now + chrono::Duration::hours(2), ID);
+        }
+        assert_eq!(*notifications.borrow(), vec![(MAC, Status::Left)]);
+    }
+
+    #[test]
+    fn test_hostname_alert() {
+        let (mut tracker, _) = tracker();
+        let now = Local::now();
+        let connected = |hostname: &str| Event::Connected {
+            mac: MAC,
+            ip: None,
+            hostname: Some(hostname.into()),
+        };
+
+        tracker.handle_event(connected("alices-iphone"), now, ID);
+        tracker.handle_event(connected("iPhone"), now, ID);
+        assert!(tracker.take_alerts().is_empty());
+
+        tracker.rules.get_mut(&MAC).unwrap().hostname_alert = true;
+        tracker.handle_event(connected("iPhone"), now, ID);
+        assert!(tracker.take_alerts().is_empty());
+        tracker.handle_event(connected("android-1234"), now, ID);
+        assert_eq!(tracker.take_alerts().len(), 1);
+        assert!(tracker.take_alerts().is_empty());
+    }
+
+    #[test]
+    fn test_always_online() {
+        let (mut tracker, _) = tracker();
+        tracker.rules.get_mut(&MAC).unwrap().always_online = true;
+        let now = Local::now();
+
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        assert!(tracker.take_alerts().is_empty());
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(now, ID);
+        }
+        let alerts = tracker.take_alerts();
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].contains("went offline, last seen"));
+
+        tracker.handle_event(
+            Event::Alive { mac: MAC, ip: IP },
+            now + chrono::Duration::minutes(90),
+            ID,
+        );
+        assert_eq!(
+            tracker.take_alerts(),
+            vec![format!("User's device {} is back online after 1h 30m", MAC)]
+        );
+    }
+
+    #[test]
+    fn test_vacation() {
+        let (mut tracker, notifications) = tracker();
+        let now = Local::now();
+        let stranger = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x66);
+        let connected = |mac| Event::Connected {
+            mac,
+            ip: None,
+            hostname: None,
+        };
+
+        tracker.handle_event(connected(stranger), now, ID);
+        tracker.set_vacation(true);
+        tracker.handle_event(connected(stranger), now, ID);
+        tracker.handle_event(connected(MAC), now, ID);
+        assert!(tracker.take_alerts().is_empty());
+        assert!(notifications.borrow().is_empty());
+
+        let stranger = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x77);
+        tracker.handle_event(connected(stranger), now, ID);
+        tracker.handle_event(
+            Event::Alive {
+                mac: stranger,
+                ip: IP,
+            },
+            now,
+            ID,
+        );
+        assert_eq!(tracker.take_alerts().len(), 1);
+    }
+
+    #[test]
+    fn test_resolved() {
+        let (tracker, notifications) = tracker();
+        let probes = Rc::new(Probes::default());
+        let mut tracker = tracker.with_prober(Box::new(probes.clone()));
+        let now = Local::now();
+        let moved = Ipv4Addr::new(192, 168, 1, 11);
+
+        tracker.resolved(MAC, IP);
+        assert_eq!(*probes.arp.borrow(), 1);
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        for _ in 0..UNANSWERED_BEFORE_RESOLVE {
+            assert!(tracker.unanswered().is_empty());
+            tracker.handle_clock(now, ID);
+        }
+        assert_eq!(tracker.unanswered(), vec![MAC]);
+        tracker.resolved(MAC, moved);
+        assert_eq!(tracker.devices()[0].ip, Some(moved));
+        tracker.handle_event(
+            Event::Alive {
+                mac: MAC,
+                ip: moved,
+            },
+            now,
+            ID,
+        );
+        assert!(tracker.unanswered().is_empty());
+        assert!(notifications.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_daily_limit() {
+        let (mut tracker, notifications) = tracker();
+        tracker.daily_limit = Some(2);
+        let now = Local::now();
+        let connected = || Event::Connected {
+            mac: MAC,
+            ip: None,
+            hostname: None,
+        };
+
+        for _ in 0..3 {
+            tracker.handle_event(connected(), now, ID);
+            tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+            for _ in 0..=ALLOWED_PACKETS_LOST {
+                tracker.handle_clock(now, ID);
+            }
+        }
+        assert_eq!(
+            *notifications.borrow(),
+            vec![(MAC, Status::Arrived), (MAC, Status::Left)]
+        );
+
+        tracker.send_digests(now, ID);
+        assert_eq!(notifications.borrow().len(), 2);
+        tracker.handle_event(connected(), now + chrono::Duration::days(1), ID);
+        assert_eq!(
+            notifications.borrow()[2..],
+            [(MAC, Status::Left), (MAC, Status::Arrived)]
+        );
+    }
+
+    #[test]
+    fn test_archive() {
+        let (mut tracker, notifications) = tracker();
+        tracker.daily_limit = Some(1);
+        let now = Local::now();
+        let tomorrow = now + chrono::Duration::days(1);
+        let connected = || Event::Connected {
+            mac: MAC,
+            ip: None,
+            hostname: None,
+        };
+
+        // the departure is held back for the digest, and the device is back
+        tracker.handle_event(connected(), now, ID);
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(now, ID);
+        }
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        assert!(tracker.set_archived("User", true).unwrap());
+        assert!(!tracker.set_archived("User", true).unwrap());
+        assert!(!tracker.is_tracking());
+        // neither a digest nor a departure or arrival
+        tracker.send_digests(tomorrow, ID);
+        for _ in 0..=ALLOWED_PACKETS_LOST {
+            tracker.handle_clock(tomorrow, ID);
+        }
+        tracker.handle_event(connected(), tomorrow, ID);
+        assert_eq!(*notifications.borrow(), vec![(MAC, Status::Arrived)]);
+
+        assert!(tracker.set_archived("User", false).unwrap());
+        tracker.handle_event(connected(), tomorrow, ID);
+        assert_eq!(
+            *notifications.borrow(),
+            vec![(MAC, Status::Arrived), (MAC, Status::Arrived)]
+        );
+
+        // archiving the subscriber stops the devices they're subscribed to as well
+        assert!(tracker.set_archived("Sub", true).unwrap());
+        assert!(tracker.devices().is_empty());
+        assert!(matches!(
+            tracker.set_archived("Nobody", true),
+            Err(crate::error::Error::UnknownUser { .. })
+        ));
+    }
+
+    #[test]
+    fn test_passive() {
+        let (tracker, notifications) = tracker();
+        let probes = Rc::new(Probes::default());
+        let mut tracker = tracker.with_prober(Box::new(probes.clone()));
+        let minutes = |m: u64| std::time::Duration::from_secs(m * 60);
+        tracker.passive = Some(config::Passive {
+            arp: minutes(5),
+            dhcp: minutes(30),
+            mdns: minutes(10),
+        });
+        let now = Local::now();
+        let after = |m| now + chrono::Duration::minutes(m);
+
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        tracker.handle_event(
+            Event::Connected {
+                mac: MAC,
+                ip: None,
+                hostname: None,
+            },
+            after(1),
+            ID,
+        );
+        tracker.handle_clock(after(10), ID);
+        tracker.handle_event(Event::Announced { mac: MAC, ip: IP }, after(25), ID);
+        tracker.handle_clock(after(31), ID);
+        assert!(tracker.is_tracking());
+        tracker.handle_clock(after(35), ID);
+        assert!(!tracker.is_tracking());
+        assert_eq!(*notifications.borrow(), vec![(MAC, Status::Left)]);
+        assert_eq!((*probes.arp.borrow(), *probes.ping.borrow()), (0, 0));
+    }
+
+    #[test]
+    fn test_admin_alerts() {
+        let (mut tracker, _) = tracker();
+        let now = Local::now();
+        let failed = || Err(crate::error::Error::ChatNotFound { chat_id: 1 });
+
+        tracker.delivered("notification", "1", failed(), now);
+        tracker.delivered("notification", "1", failed(), now);
+        assert!(tracker.take_alerts().is_empty());
+        tracker.delivered("notification", "1", failed(), now);
+        tracker.delivered("notification", "1", failed(), now);
+        assert_eq!(tracker.take_alerts().len(), 1);
+        tracker.delivered("notification", "1", Ok(()), now);
+        assert_eq!(
+            tracker.take_alerts(),
+            vec!["Delivering notifications again after 4 failures".to_string()]
+        );
+
+        let unknown = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x66);
+        tracker.handle_event(Event::Alive { mac: MAC, ip: IP }, now, ID);
+        assert_eq!(tracker.unknown_summary(now), None);
+        tracker.handle_event(
+            Event::Alive {
+                mac: unknown,
+                ip: IP,
+            },
+            now,
+            ID,
+        );
+        assert!(tracker
+            .unknown_summary(now)
+            .unwrap()
+            .starts_with("1 unknown device first seen since"));
+        assert_eq!(
+            tracker.unknown_summary(now + chrono::Duration::seconds(1)),
+            None
+        );
+    }
+}
@@ -0,0 +1,395 @@
+use crate::notifier::{Notification, Notifier};
+use crossbeam_channel::{Receiver, Sender};
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+const SIGNATURE_HEADER: &str = "X-Houserat-Signature";
+const ENCRYPTION_HEADER: &str = "X-Houserat-Encryption";
+const CORRELATION_HEADER: &str = "X-Houserat-Correlation-Id";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Delivery {
+    idempotency_key: String,
+    /// Missing from deliveries queued before correlation IDs were introduced
+    #[serde(default)]
+    correlation_id: Option<String>,
+    attempts: u32,
+    body: serde_json::Value,
+}
+
+impl std::fmt::Display for Delivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.correlation_id {
+            Some(correlation_id) => write!(f, "[{}] {}", correlation_id, self.idempotency_key),
+            None => write!(f, "{}", self.idempotency_key),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    correlation_id: &'a str,
+    event: crate::Status,
+    mac: String,
+    name: &'a str,
+    subscriber: &'a str,
+    /// Where the subscriber is reached, for relays delivering to other backends
+    identity: &'a crate::Identity,
+    timestamp: chrono::DateTime<chrono::Local>,
+    quiet: bool,
+    reminder: bool,
+    digest: bool,
+}
+
+/// Deliveries waiting to be sent, one file each so they survive restarts, named to sort in order.
+struct Outbox {
+    dir: PathBuf,
+    dead_letter_dir: PathBuf,
+    counter: AtomicU64,
+}
+
+fn outbox_error(path: &Path) -> crate::error::OutboxError<PathBuf> {
+    crate::error::OutboxError {
+        path: path.to_path_buf(),
+    }
+}
+
+impl Outbox {
+    fn open(dir: PathBuf) -> crate::Result<Outbox> {
+        let dead_letter_dir = dir.join("dead-letter");
+        std::fs::create_dir_all(&dead_letter_dir)
+            .with_context(|| outbox_error(&dead_letter_dir))?;
+        Ok(Outbox {
+            dir,
+            dead_letter_dir,
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    fn push(&self, delivery: &Delivery) -> crate::Result<()> {
+        let now = chrono::Local::now();
+        let counter = self.counter.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!(
+            "{:012}{:09}-{:06}.json",
+            now.timestamp(),
+            now.timestamp_subsec_nanos(),
+            counter
+        ));
+        self.write(&path, delivery)
+    }
+
+    fn write(&self, path: &Path, delivery: &Delivery) -> crate::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let content = serde_json::to_vec(delivery).expect("Failed to serialize delivery");
+        std::fs::write(&tmp_path, content)
+            .and_then(|()| std::fs::rename(&tmp_path, path))
+            .with_context(|| outbox_error(path))
+    }
+
+    fn first(&self) -> crate::Result<Option<(PathBuf, Delivery)>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&self.dir).with_context(|| outbox_error(&self.dir))? {
+            let path = entry.with_context(|| outbox_error(&self.dir))?.path();
+            if path.extension().map_or(false, |e| e == "json") {
+                paths.push(path);
+            }
+        }
+        let path = match paths.into_iter().min() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let content = std::fs::read(&path).with_context(|| outbox_error(&path))?;
+        match serde_json::from_slice(&content) {
+            Ok(delivery) => Ok(Some((path, delivery))),
+            Err(e) => {
+                println!("Invalid delivery {}: {}", path.display(), e);
+                self.dead_letter(&path)?;
+                self.first()
+            }
+        }
+    }
+
+    fn remove(&self, path: &Path) -> crate::Result<()> {
+        std::fs::remove_file(path).with_context(|| outbox_error(path))
+    }
+
+    fn dead_letter(&self, path: &Path) -> crate::Result<()> {
+        let target = self.dead_letter_dir.join(path.file_name().unwrap());
+        std::fs::rename(path, target).with_context(|| outbox_error(path))
+    }
+}
+
+/// Posts notifications as JSON to a URL with at-least-once delivery, in order, retrying with
+/// exponential backoff and moving deliveries that keep failing to a dead-letter directory.
+pub struct Webhook {
+    outbox: std::sync::Arc<Outbox>,
+    wake: Sender<()>,
+    events: Vec<crate::Status>,
+}
+
+fn headers(config: &crate::config::Webhook) -> crate::Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.headers {
+        let header = HeaderName::from_bytes(name.as_bytes())
+            .ok()
+            .zip(HeaderValue::from_str(value).ok());
+        match header {
+            Some((name, value)) => headers.insert(name, value),
+            None => {
+                return Err(crate::error::Error::InvalidWebhookHeader {
+                    name: name.clone(),
+                    url: config.url.clone(),
+                })
+            }
+        };
+    }
+    Ok(headers)
+}
+
+/// Signs a body with HMAC-SHA256, as `sha256=<hex digest>` like GitHub does, so receivers can
+/// verify it came from us.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.input(body);
+    format!("sha256={:x}", mac.result().code())
+}
+
+impl Webhook {
+    pub fn start(config: &crate::config::Webhook, storage_path: &Path) -> crate::Result<Webhook> {
+        let name = format!("{:x}", Sha256::digest(config.url.as_bytes()));
+        let outbox =
+            std::sync::Arc::new(Outbox::open(storage_path.join("outbox").join(&name[..16]))?);
+        if let Some(encryption) = &config.encrypt {
+            // fail now rather than on every delivery if the key or program is missing
+            crate::encrypt::encrypt(encryption, b"{}")?;
+        }
+        let (wake, wake_r) = crossbeam_channel::unbounded();
+        let worker = Worker {
+            url: config.url.clone(),
+            max_attempts: config.max_attempts,
+            headers: headers(config)?,
+            secret: config.secret.clone(),
+            encrypt: config.encrypt.clone(),
+            outbox: outbox.clone(),
+            http: reqwest::Client::new(),
+            wake: wake_r,
+        };
+        std::thread::spawn(move || worker.run());
+        Ok(Webhook {
+            outbox,
+            wake,
+            events: config.events.clone(),
+        })
+    }
+}
+
+impl Notifier for Webhook {
+    fn notify(&self, notification: &Notification) -> crate::Result<()> {
+        if !self.events.is_empty() && !self.events.contains(&notification.status) {
+            return Ok(());
+        }
+        let payload = Payload {
+            correlation_id: &notification.correlation_id,
+            event: notification.status,
+            mac: notification.mac.to_string(),
+            name: &notification.name,
+            subscriber: &notification.subscriber_name,
+            identity: &notification.identity,
+            timestamp: notification.timestamp,
+            quiet: notification.quiet,
+            reminder: notification.reminder,
+            digest: notification.digest,
+        };
+        let key_source = format!(
+            "{}-{}-{}",
+            std::process::id(),
+            notification.timestamp.to_rfc3339(),
+            self.outbox.counter.load(Ordering::SeqCst)
+        );
+        let key = format!("{:x}", Sha256::digest(key_source.as_bytes()));
+        self.outbox.push(&Delivery {
+            idempotency_key: key[..32].to_string(),
+            correlation_id: Some(notification.correlation_id.clone()),
+            attempts: 0,
+            body: serde_json::to_value(payload).expect("Failed to serialize payload"),
+        })?;
+        let _ = self.wake.send(());
+        Ok(())
+    }
+}
+
+struct Worker {
+    url: String,
+    max_attempts: u32,
+    headers: HeaderMap,
+    secret: Option<String>,
+    encrypt: Option<crate::config::Encryption>,
+    outbox: std::sync::Arc<Outbox>,
+    http: reqwest::Client,
+    wake: Receiver<()>,
+}
+
+enum Failure {
+    Retry(String),
+    Permanent(String),
+}
+
+impl Worker {
+    fn run(self) {
+        loop {
+            let (path, mut delivery) = match self.outbox.first() {
+                Ok(Some(next)) => next,
+                Ok(None) => {
+                    if self.wake.recv().is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    println!("Failed to read webhook outbox: {}", e);
+                    std::thread::sleep(MAX_BACKOFF);
+                    continue;
+                }
+            };
+
+            let result = match self.deliver(&delivery) {
+                Ok(()) => self.outbox.remove(&path),
+                Err(Failure::Permanent(e)) => {
+                    println!(
+                        "Webhook delivery {} to {} failed permanently, moving to dead-letter: {}",
+                        delivery, self.url, e
+                    );
+                    self.outbox.dead_letter(&path)
+                }
+                Err(Failure::Retry(e)) => {
+                    delivery.attempts += 1;
+                    if delivery.attempts >= self.max_attempts {
+                        println!(
+                            "Webhook delivery {} to {} failed {} times, moving to dead-letter: {}",
+                            delivery, self.url, delivery.attempts, e
+                        );
+                        self.outbox.dead_letter(&path)
+                    } else {
+                        let backoff = backoff(delivery.attempts);
+                        println!(
+                            "Webhook delivery {} to {} failed, retrying in {}s: {}",
+                            delivery,
+                            self.url,
+                            backoff.as_secs(),
+                            e
+                        );
+                        let result = self.outbox.write(&path, &delivery);
+                        std::thread::sleep(backoff);
+                        result
+                    }
+                }
+            };
+            if let Err(e) = result {
+                println!("Failed to update webhook outbox: {}", e);
+                std::thread::sleep(MAX_BACKOFF);
+            }
+            while self.wake.try_recv().is_ok() {}
+        }
+    }
+
+    fn deliver(&self, delivery: &Delivery) -> Result<(), Failure> {
+        let mut body = serde_json::to_vec(&delivery.body).expect("Failed to serialize delivery");
+        let mut request = self
+            .http
+            .post(&self.url)
+            .headers(self.headers.clone())
+            .header("Idempotency-Key", delivery.idempotency_key.as_str());
+        if let Some(correlation_id) = &delivery.correlation_id {
+            request = request.header(CORRELATION_HEADER, correlation_id.as_str());
+        }
+        match &self.encrypt {
+            Some(encryption) => {
+                body = crate::encrypt::encrypt(encryption, &body)
+                    .map_err(|e| Failure::Retry(e.to_string()))?;
+                let kind = match encryption.kind {
+                    crate::config::EncryptionKind::Age => "age",
+                    crate::config::EncryptionKind::Pgp => "pgp",
+                };
+                request = request
+                    .header(CONTENT_TYPE, "text/plain")
+                    .header(ENCRYPTION_HEADER, kind);
+            }
+            None => request = request.header(CONTENT_TYPE, "application/json"),
+        }
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, &body).as_str());
+        }
+        let response = request.body(body).send().map_err(|e| {
+            if e.is_timeout() {
+                let url = self.url.clone();
+                Failure::Retry(crate::error::Error::WebhookTimeout { url }.report())
+            } else {
+                Failure::Retry(e.to_string())
+            }
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_client_error()
+            && status != reqwest::StatusCode::REQUEST_TIMEOUT
+            && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+        {
+            Err(Failure::Permanent(status.to_string()))
+        } else {
+            Err(Failure::Retry(status.to_string()))
+        }
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    INITIAL_BACKOFF
+        .checked_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(5), Duration::from_secs(16));
+        assert_eq!(backoff(20), MAX_BACKOFF);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_delivery() {
+        // queued before correlation IDs
+        let old: Delivery =
+            serde_json::from_str(r#"{"idempotency_key": "abc", "attempts": 2, "body": {}}"#)
+                .unwrap();
+        assert_eq!(old.correlation_id, None);
+        assert_eq!(old.to_string(), "abc");
+
+        let delivery = Delivery {
+            correlation_id: Some("18e2-1f-7".to_string()),
+            ..old
+        };
+        assert_eq!(delivery.to_string(), "[18e2-1f-7] abc");
+    }
+}
@@ -0,0 +1,330 @@
+//! Scenarios driving the presence state machine end to end, with synthetic events, a fake clock and
+//! a phone that answers keepalives while it's home.
+
+use chrono::{DateTime, Duration, Local, TimeZone};
+use houserat::config::{self, NetworkAddresses};
+use houserat::notifier::Fanout;
+use houserat::replay::{self, Source, Sourced};
+use houserat::tracker::{Clock, PresenceTracker, Prober, ALLOWED_PACKETS_LOST, TICK_SECS};
+use houserat::{Event, Identity, Metadata, Notification, Notifier, Status};
+use pnet::util::MacAddr;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::rc::Rc;
+
+const PHONE: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+
+type Notifications = Rc<RefCell<Vec<(Status, DateTime<Local>)>>>;
+
+struct Recorder(Notifications);
+
+impl Notifier for Recorder {
+    fn notify(&self, notification: &Notification) -> houserat::Result<()> {
+        self.0
+            .borrow_mut()
+            .push((notification.status, notification.timestamp));
+        Ok(())
+    }
+}
+
+/// Fails every delivery, like a bot the subscriber blocked.
+struct Blocked;
+
+impl Notifier for Blocked {
+    fn notify(&self, _notification: &Notification) -> houserat::Result<()> {
+        Err(houserat::error::Error::ChatNotFound { chat_id: 1 })
+    }
+}
+
+/// Records the devices keepalives are sent to, for the harness to answer.
+struct Probes(Rc<RefCell<Vec<MacAddr>>>);
+
+impl Prober for Probes {
+    fn probe(&self, _us: &NetworkAddresses, them: &NetworkAddresses) -> houserat::Result<()> {
+        self.0.borrow_mut().push(them.mac);
+        Ok(())
+    }
+
+    fn ping(&self, us: &NetworkAddresses, them: &NetworkAddresses) -> houserat::Result<()> {
+        self.probe(us, them)
+    }
+}
+
+fn config(cooldown: Option<Duration>) -> config::Config {
+    let mut rules = HashMap::new();
+    rules.insert(
+        PHONE,
+        Metadata::new(
+            "Alice".to_string(),
+            None,
+            None,
+            "Bob".to_string(),
+            Identity {
+                telegram: Some(1),
+                ..Default::default()
+            },
+        ),
+    );
+    config::Config {
+        cooldown,
+        rules,
+        ..config::Config::for_tests()
+    }
+}
+
+fn start() -> DateTime<Local> {
+    Local.with_ymd_and_hms(2026, 3, 2, 12, 0, 0).unwrap()
+}
+
+fn tick() -> Duration {
+    Duration::seconds(TICK_SECS.into())
+}
+
+/// A clock that only moves when the scenario moves it.
+struct FakeClock(Cell<DateTime<Local>>);
+
+impl FakeClock {
+    fn set(&self, now: DateTime<Local>) {
+        self.0.set(now);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Local> {
+        self.0.get()
+    }
+}
+
+/// A household network on a fake clock, ticked the way the daemon ticks it.
+struct Harness {
+    tracker: PresenceTracker,
+    notifications: Notifications,
+    probes: Rc<RefCell<Vec<MacAddr>>>,
+    clock: FakeClock,
+    /// Whether the phone answers keepalives
+    home: bool,
+}
+
+impl Harness {
+    fn new(cooldown: Option<Duration>) -> Self {
+        let notifications = Notifications::default();
+        let probes = Rc::new(RefCell::new(Vec::new()));
+        let tracker =
+            PresenceTracker::new(config(cooldown), Box::new(Recorder(notifications.clone())))
+                .with_prober(Box::new(Probes(probes.clone())));
+        Harness {
+            tracker,
+            notifications,
+            probes,
+            clock: FakeClock(Cell::new(start())),
+            home: false,
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        let now = self.clock.now();
+        let id = self.tracker.correlation_id(now);
+        self.tracker.handle_event(event, now, &id);
+    }
+
+    /// The phone joins the network, asking for a lease and then announcing itself.
+    fn connect(&mut self) {
+        self.home = true;
+        self.event(Event::Connected {
+            mac: PHONE,
+            ip: Some(IP),
+            hostname: None,
+        });
+        self.event(Event::Alive { mac: PHONE, ip: IP });
+    }
+
+    /// The phone silently drops off the network, as phones do.
+    fn disconnect(&mut self) {
+        self.home = false;
+    }
+
+    /// Advances the clock tick by tick, the phone answering keepalives while it's home.
+    fn advance(&mut self, duration: Duration) {
+        let until = self.clock.now() + duration;
+        while self.clock.now() + tick() <= until {
+            self.clock.set(self.clock.now() + tick());
+            let now = self.clock.now();
+            let id = self.tracker.correlation_id(now);
+            self.tracker.handle_clock(now, &id);
+            let probed: Vec<MacAddr> = self.probes.borrow_mut().drain(..).collect();
+            if self.home {
+                for mac in probed {
+                    self.event(Event::Alive { mac, ip: IP });
+                }
+            }
+        }
+        self.clock.set(until);
+    }
+
+    fn notified(&self) -> Vec<Status> {
+        self.notifications
+            .borrow()
+            .iter()
+            .map(|(status, _)| *status)
+            .collect()
+    }
+}
+
+#[test]
+fn test_keepalives_until_departure() {
+    let mut harness = Harness::new(None);
+    harness.connect();
+    assert_eq!(harness.notified(), vec![Status::Arrived]);
+
+    harness.advance(Duration::hours(2));
+    assert!(harness.tracker.is_online(PHONE));
+    assert_eq!(harness.notified(), vec![Status::Arrived]);
+
+    // every unanswered keepalive is allowed but the last
+    harness.disconnect();
+    harness.advance(tick() * ALLOWED_PACKETS_LOST as i32);
+    assert!(harness.tracker.is_online(PHONE));
+    harness.advance(tick());
+    assert!(!harness.tracker.is_online(PHONE));
+    assert_eq!(harness.notified(), vec![Status::Arrived, Status::Left]);
+}
+
+#[test]
+fn test_flapping() {
+    let mut harness = Harness::new(None);
+    for _ in 0..3 {
+        harness.connect();
+        harness.advance(Duration::minutes(1));
+        harness.disconnect();
+        harness.advance(Duration::minutes(2));
+    }
+    assert_eq!(
+        harness.notified(),
+        [Status::Arrived, Status::Left].repeat(3)
+    );
+}
+
+#[test]
+fn test_flapping_during_cooldown() {
+    let mut harness = Harness::new(Some(Duration::minutes(10)));
+    harness.connect();
+    harness.advance(Duration::minutes(1));
+    for _ in 0..2 {
+        harness.disconnect();
+        harness.advance(Duration::minutes(2));
+        harness.connect();
+        harness.advance(Duration::minutes(1));
+    }
+    assert_eq!(harness.notified(), vec![Status::Arrived]);
+
+    // once the cooldown is over the next departure is announced again
+    harness.advance(Duration::minutes(10));
+    harness.disconnect();
+    harness.advance(Duration::minutes(2));
+    assert_eq!(harness.notified(), vec![Status::Arrived, Status::Left]);
+    let notifications = harness.notifications.borrow();
+    assert!(notifications[1].1 - notifications[0].1 >= Duration::minutes(10));
+}
+
+/// Events at given times, as a capture file would have them.
+struct Script(VecDeque<(DateTime<Local>, Event)>);
+
+impl Source for Script {
+    fn next_event(&mut self) -> houserat::Result<Option<Sourced>> {
+        Ok(self.0.pop_front().map(|(timestamp, event)| Sourced {
+            timestamp,
+            event,
+            vlan: None,
+        }))
+    }
+}
+
+#[test]
+fn test_replay() {
+    let notifications = Notifications::default();
+    let mut tracker = PresenceTracker::new(config(None), Box::new(Recorder(notifications.clone())));
+    let script = Script(
+        vec![
+            (
+                start(),
+                Event::Connected {
+                    mac: PHONE,
+                    ip: Some(IP),
+                    hostname: None,
+                },
+            ),
+            (start(), Event::Alive { mac: PHONE, ip: IP }),
+            (
+                start() + Duration::minutes(1),
+                Event::Alive { mac: PHONE, ip: IP },
+            ),
+            // nothing more is heard of the phone
+            (start() + Duration::minutes(10), Event::Ignored),
+        ]
+        .into(),
+    );
+    replay::run(&mut tracker, script, "max".parse().unwrap()).unwrap();
+
+    let notifications = notifications.borrow();
+    assert_eq!(notifications.len(), 2);
+    assert_eq!(notifications[0], (Status::Arrived, start()));
+    // left on the tick after the last keepalive allowed to go unanswered, ALLOWED_PACKETS_LOST + 1
+    // ticks after the phone was last heard from
+    assert_eq!(notifications[1].0, Status::Left);
+    assert_eq!(
+        notifications[1].1,
+        start() + Duration::minutes(1) + tick() * (ALLOWED_PACKETS_LOST as i32 + 1)
+    );
+}
+
+#[test]
+fn test_reconnect_replayed() {
+    let notifications = Notifications::default();
+    let notifier = Fanout(vec![
+        Box::new(Blocked),
+        Box::new(Recorder(notifications.clone())),
+    ]);
+    let mut tracker = PresenceTracker::new(config(None), Box::new(notifier));
+    let renewal = start() + Duration::seconds(40);
+    let script = Script(
+        vec![
+            (
+                start(),
+                Event::Connected {
+                    mac: PHONE,
+                    ip: Some(IP),
+                    hostname: None,
+                },
+            ),
+            (start(), Event::Alive { mac: PHONE, ip: IP }),
+            // e.g. a lease renewal or roaming between access points, before the phone is given up on
+            (
+                renewal,
+                Event::Connected {
+                    mac: PHONE,
+                    ip: Some(IP),
+                    hostname: None,
+                },
+            ),
+            (renewal, Event::Alive { mac: PHONE, ip: IP }),
+            (start() + Duration::minutes(10), Event::Ignored),
+        ]
+        .into(),
+    );
+    replay::run(&mut tracker, script, "max".parse().unwrap()).unwrap();
+
+    // delivered to the notifiers after the one that failed, the reconnect announced to none
+    assert_eq!(
+        *notifications.borrow(),
+        vec![
+            (Status::Arrived, start()),
+            (
+                Status::Left,
+                renewal + tick() * (ALLOWED_PACKETS_LOST as i32 + 1)
+            ),
+        ]
+    );
+    assert!(tracker.last_failure().is_some());
+}